@@ -1,159 +1,2813 @@
-use chrono::prelude::*;
-use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use sha2::{Digest, Sha256};
 
-pub const DIFFICULTY_LEVEL: usize = 2;
+use crate::{
+    clock::{Clock, SystemClock},
+    consensus::{Consensus, ProofOfWork},
+    error::Error,
+    hasher::{Hasher, Sha256Hasher},
+    p2p::{ENVELOPE_BYTES, MAX_TRANSMIT_SIZE},
+};
+
+// number of leading zero bits a block hash must have
+pub const DIFFICULTY_LEVEL: usize = 16;
+// every supported hash is at least 256 bits long
+pub const MAX_DIFFICULTY: usize = 256;
+pub const TARGET_BLOCK_INTERVAL: i64 = 10;
+// difficulty is retargeted once per window of blocks, genesis excluded
+const DIFFICULTY_WINDOW: usize = 10;
+// room in a block message for the header fields and the coinbase, next to the envelope
+const BLOCK_HEADER_BYTES: usize = 3 * 1024;
+// JSON escapes a data byte to at most 6, so data up to this is always gossiped in one message
+pub const MAX_BLOCK_DATA_BYTES: usize =
+    (MAX_TRANSMIT_SIZE - ENVELOPE_BYTES - BLOCK_HEADER_BYTES) / 6;
+// nonces between mining progress lines, 0 turns them off
+pub const MINE_LOG_EVERY: u64 = 1000;
+// how far ahead of the local clock a block timestamp may be, in seconds
+const MAX_DRIFT: i64 = 120;
+// genesis has no parent, so it points at an all-zero hash
+const GENESIS_PREVIOUS_HASH: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+const GENESIS_TIMESTAMP: i64 = 1_635_000_000;
+// 9999-12-31T23:59:59Z, later timestamps don't format as dates
+const MAX_TIMESTAMP: i64 = 253_402_300_799;
+pub const GENESIS_ACCOUNT: &str = "genesis";
+const GENESIS_SUPPLY: u64 = 1_000_000;
+// sender of the reward transaction a miner puts first in its block
+pub const COINBASE_ACCOUNT: &str = "coinbase";
+pub const BLOCK_REWARD: u64 = 50;
+// balances are signed, so larger transfers can't be applied
+pub const MAX_AMOUNT: u64 = i64::MAX as u64;
+// bumped when the block format changes; blocks without a version are 1,
+// from before transactions and merkle roots; 3 hashes the difficulty and miner,
+// so older blocks no longer verify
+pub const BLOCK_VERSION: u32 = 3;
+pub const SNAPSHOT_FILE: &str = "snapshot.json";
+// layout of chain files, given by their first line; bumped when it changes, with an arm
+// in `App::load_from_file` reading the older versions
+pub const CHAIN_FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChainHeader {
+    format_version: u32,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
+    #[serde(default = "legacy_version")]
+    pub version: u32,
     pub id: u64,
     pub hash: String,
     pub previous_hash: String,
+    #[serde(deserialize_with = "sane_timestamp")]
     pub timestamp: i64,
     pub data: String,
+    #[serde(default)]
+    pub transactions: Vec<Transaction>,
+    #[serde(default)]
+    pub merkle_root: String,
+    // leading zero bits the block was mined at, must match the chain's retarget
+    #[serde(default)]
+    pub difficulty: usize,
+    // peer id of the node that mined it
+    #[serde(default)]
+    pub miner: String,
+    pub nonce: u64,
+    // the miner's signature of the hash under proof of authority, so not part of it
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub signature: String,
+}
+
+fn legacy_version() -> u32 {
+    1
+}
+
+fn write_header<W: Write>(writer: &mut W) -> Result<(), Error> {
+    serde_json::to_writer(
+        &mut *writer,
+        &ChainHeader {
+            format_version: CHAIN_FORMAT_VERSION,
+        },
+    )?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+// a block timestamped before 1970 or past MAX_TIMESTAMP fails to parse at all,
+// so it is dropped like any malformed message
+fn sane_timestamp<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    let timestamp = i64::deserialize(deserializer)?;
+    if (0..=MAX_TIMESTAMP).contains(&timestamp) {
+        Ok(timestamp)
+    } else {
+        Err(de::Error::custom(format!(
+            "timestamp {} is out of range",
+            timestamp
+        )))
+    }
+}
+
+// what a light node keeps of a block: enough to follow linkage and work
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub id: u64,
+    pub hash: String,
+    pub previous_hash: String,
+    pub timestamp: i64,
+    pub nonce: u64,
+    pub merkle_root: String,
+}
+
+impl From<&Block> for BlockHeader {
+    fn from(block: &Block) -> Self {
+        Self {
+            id: block.id,
+            hash: block.hash.clone(),
+            previous_hash: block.previous_hash.clone(),
+            timestamp: block.timestamp,
+            nonce: block.nonce,
+            merkle_root: block.merkle_root.clone(),
+        }
+    }
+}
+
+// lets difficulty retargeting run over full blocks and headers alike
+pub trait ChainEntry {
+    fn id(&self) -> u64;
+    fn timestamp(&self) -> i64;
+}
+
+impl ChainEntry for Block {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+}
+
+impl ChainEntry for BlockHeader {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+}
+
+// what `App::prune` keeps of the dropped prefix, enough to validate the blocks after it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub genesis_hash: String,
+    // the last pruned block
+    pub id: u64,
+    pub hash: String,
+    pub timestamp: i64,
+    // difficulty of the first kept block, which starts a retarget window
+    pub difficulty: usize,
+    pub work: u128,
+    pub balances: HashMap<String, i64>,
+    // transactions sent per account, for nonces
+    pub sent: HashMap<String, u64>,
+}
+
+impl Snapshot {
+    pub fn load_from_file(path: &Path) -> Result<Snapshot, Error> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    pub from: String,
+    pub to: String,
+    pub amount: u64,
     pub nonce: u64,
 }
 
+// aggregates over the blocks the node holds, see `App::stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainStats {
+    pub blocks: usize,
+    pub transactions: usize,
+    // seconds between consecutive mined blocks, None below two of them
+    pub average_interval: Option<f64>,
+    pub total_work: u128,
+    // id and data size in bytes of the block with the most data
+    pub largest_block: Option<(u64, usize)>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GenesisConfig {
+    #[serde(default = "default_genesis_timestamp")]
+    pub timestamp: i64,
+    #[serde(default = "default_genesis_data")]
+    pub data: String,
+    #[serde(default)]
+    pub allocations: Vec<Allocation>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Allocation {
+    pub account: String,
+    pub amount: u64,
+}
+
+fn default_genesis_timestamp() -> i64 {
+    GENESIS_TIMESTAMP
+}
+
+fn default_genesis_data() -> String {
+    String::from("genesis!")
+}
+
+impl Transaction {
+    // the nonce is the block height so every reward is distinct
+    pub fn coinbase(to: String, height: u64) -> Self {
+        Self {
+            from: COINBASE_ACCOUNT.to_string(),
+            to,
+            amount: BLOCK_REWARD,
+            nonce: height,
+        }
+    }
+
+    fn is_coinbase(&self) -> bool {
+        self.from == COINBASE_ACCOUNT
+    }
+}
+
 pub struct App {
     pub blocks: Vec<Block>,
+    pub pending: Vec<Transaction>,
+    pub target_block_interval: i64,
+    // base difficulty before retargeting
+    pub difficulty: usize,
+    pub max_block_data: usize,
+    pub mine_log_every: u64,
+    // credited with the coinbase of blocks mined here, the peer id on a node
+    pub miner: String,
+    // genesis built from `--genesis`, used instead of the hardcoded one
+    pub custom_genesis: Option<Block>,
+    pub hasher: Arc<dyn Hasher>,
+    // proof of work unless --consensus says otherwise
+    pub consensus: Arc<dyn Consensus>,
+    pub clock: Arc<dyn Clock>,
+    // light nodes keep `headers` instead of `blocks`
+    pub light: bool,
+    pub headers: Vec<BlockHeader>,
+    // blocks dropped by the most recent reorg
+    pub last_reorg_depth: Option<usize>,
+    // set once `prune` dropped the start of the chain, `blocks` then continue it
+    pub snapshot: Option<Snapshot>,
+    // expected hashes by block id, a chain disagreeing with any of them is invalid
+    pub checkpoints: BTreeMap<u64, String>,
+    // peer each received block came from, by hash; kept out of the block so it is
+    // never hashed, sent or saved
+    pub sources: HashMap<String, String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockError {
+    WrongPreviousHash,
+    WrongDifficulty,
+    WrongId,
+    WrongHash,
+    WrongMerkleRoot,
+    MalformedHash,
+    DataTooLarge,
+    TimestampBeforeParent,
+    TimestampInFuture,
+    InvalidCoinbase,
+    Overdraft,
+    AmountOutOfRange,
+    CheckpointMismatch,
+    DifficultyMismatch,
+    InvalidSeal,
+}
+
+pub fn merkle_root(txs: &[Transaction]) -> String {
+    if txs.is_empty() {
+        return hex::encode([0u8; 32]);
+    }
+    let mut level: Vec<Vec<u8>> = txs
+        .iter()
+        .map(|tx| {
+            let json = serde_json::to_string(tx).expect("can jsonify transaction");
+            Sha256::digest(json.as_bytes()).to_vec()
+        })
+        .collect();
+    while level.len() > 1 {
+        if level.len() % 2 == 1 {
+            level.push(level.last().expect("level is not empty").clone());
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| {
+                let mut hasher = Sha256::new();
+                hasher.update(&pair[0]);
+                hasher.update(&pair[1]);
+                hasher.finalize().to_vec()
+            })
+            .collect();
+    }
+    hex::encode(&level[0])
+}
+
+// index in `local` of the first block that differs between the two chains;
+// `local` may start later than `remote` once pruned
+pub fn fork_point(local: &[Block], remote: &[Block]) -> usize {
+    let start = local.first().map_or(0, |b| b.id);
+    local
+        .iter()
+        .zip(remote.iter().skip_while(|r| r.id < start))
+        .take_while(|(l, r)| l.hash == r.hash)
+        .count()
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::WrongPreviousHash => write!(f, "wrong previous hash"),
+            BlockError::WrongDifficulty => write!(f, "wrong difficulty prefix"),
+            BlockError::WrongId => write!(f, "wrong id"),
+            BlockError::WrongHash => write!(f, "wrong hash"),
+            BlockError::WrongMerkleRoot => write!(f, "wrong merkle root"),
+            BlockError::MalformedHash => write!(f, "hash is not valid hex"),
+            BlockError::DataTooLarge => write!(f, "data exceeds the block size limit"),
+            BlockError::TimestampBeforeParent => write!(f, "timestamp is before the parent block"),
+            BlockError::TimestampInFuture => write!(f, "timestamp is too far in the future"),
+            BlockError::InvalidCoinbase => write!(f, "misplaced or wrong coinbase transaction"),
+            BlockError::Overdraft => write!(f, "transaction overdraws its sender"),
+            BlockError::AmountOutOfRange => {
+                write!(f, "transaction amount is above {}", MAX_AMOUNT)
+            }
+            BlockError::CheckpointMismatch => write!(f, "hash differs from the checkpoint"),
+            BlockError::DifficultyMismatch => {
+                write!(f, "stored difficulty is not the expected one")
+            }
+            BlockError::InvalidSeal => write!(f, "block is not sealed as the consensus requires"),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MineError {
+    Cancelled,
+    Exhausted,
+    NotAuthority,
+    Signing,
+    // the data's length and the limit
+    DataTooLarge(usize, usize),
+    LightNode,
+    Uninitialised,
+}
+
+impl fmt::Display for MineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MineError::Cancelled => write!(f, "mining was cancelled"),
+            MineError::Exhausted => write!(f, "no nonce satisfies the difficulty"),
+            MineError::NotAuthority => {
+                write!(f, "this node is not an authority and cannot seal blocks")
+            }
+            MineError::Signing => write!(f, "could not sign the block"),
+            MineError::DataTooLarge(len, limit) => {
+                write!(f, "block data is {} bytes, the limit is {}", len, limit)
+            }
+            MineError::LightNode => write!(f, "light nodes keep no block bodies and cannot mine"),
+            MineError::Uninitialised => write!(f, "the chain is not initialised yet"),
+        }
+    }
+}
+
+impl std::error::Error for MineError {}
+
+// why a chain from a peer was turned away before full validation
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChainError {
+    Empty,
+    WrongGenesis,
+    NonSequentialIds,
+    UnsupportedVersion,
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::Empty => write!(f, "chain is empty"),
+            ChainError::WrongGenesis => write!(f, "chain starts from a different genesis"),
+            ChainError::NonSequentialIds => write!(f, "block ids are not sequential"),
+            ChainError::UnsupportedVersion => write!(f, "chain has blocks newer than this node"),
+        }
+    }
 }
 
+impl std::error::Error for ChainError {}
+
 impl Block {
-    pub fn new(id: u64, previous_hash: String, data: String) -> Self {
-        let timestamp = Utc::now().timestamp();
-        let (nonce, hash) = Block::mine_block(id, timestamp, &previous_hash, &data);
-        Self {
+    // gives up once `cancel` is set
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        hasher: &dyn Hasher,
+        consensus: &dyn Consensus,
+        clock: &dyn Clock,
+        id: u64,
+        previous_hash: String,
+        data: String,
+        transactions: Vec<Transaction>,
+        difficulty: usize,
+        miner: String,
+        threads: usize,
+        log_every: u64,
+        cancel: &AtomicBool,
+    ) -> Result<Self, MineError> {
+        let mut block = Self {
+            version: BLOCK_VERSION,
             id,
-            hash,
-            timestamp,
+            hash: String::new(),
+            timestamp: clock.now(),
             previous_hash,
             data,
-            nonce,
+            merkle_root: merkle_root(&transactions),
+            transactions,
+            difficulty,
+            miner,
+            nonce: 0,
+            signature: String::new(),
+        };
+        consensus.seal(&mut block, hasher, threads, log_every, cancel)?;
+        Ok(block)
+    }
+
+    // finds the nonce and hash meeting the block's difficulty
+    pub fn mine(
+        &mut self,
+        hasher: &dyn Hasher,
+        threads: usize,
+        log_every: u64,
+        cancel: &AtomicBool,
+    ) -> Result<(), MineError> {
+        let fields = HashedFields::new(hasher, self);
+        let (nonce, hash) = if threads > 1 {
+            Block::mine_block_parallel(
+                &fields,
+                self.difficulty,
+                u64::MAX,
+                threads,
+                log_every,
+                cancel,
+            )?
+        } else {
+            Block::mine_block(&fields, self.difficulty, u64::MAX, log_every, cancel)?
+        };
+        self.nonce = nonce;
+        self.hash = hash;
+        Ok(())
+    }
+
+    // the stored hash meets the stored difficulty, whether it matches the fields aside
+    pub fn meets_difficulty(&self) -> bool {
+        hex::decode(&self.hash).is_ok_and(|hash| Block::validate_hash(&hash, self.difficulty))
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.version <= BLOCK_VERSION
+    }
+
+    // upgrades a block from an older node to BLOCK_VERSION, filling in what it lacked
+    pub fn migrate(&mut self) {
+        if self.version >= BLOCK_VERSION {
+            return;
+        }
+        if self.merkle_root.is_empty() {
+            self.merkle_root = merkle_root(&self.transactions);
+        }
+        self.version = BLOCK_VERSION;
+    }
+
+    pub fn append_to_file(&self, path: &Path) -> Result<(), Error> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            write_header(&mut file)?;
         }
+        Block::write_line(&mut file, self)
+    }
+
+    fn write_line<W: Write>(writer: &mut W, block: &Block) -> Result<(), Error> {
+        serde_json::to_writer(&mut *writer, block)?;
+        writer.write_all(b"\n")?;
+        Ok(())
     }
 
-    fn mine_block(id: u64, timestamp: i64, previous_hash: &str, data: &str) -> (u64, String) {
+    // tries nonces up to and including `max_nonce`
+    fn mine_block(
+        fields: &HashedFields,
+        difficulty: usize,
+        max_nonce: u64,
+        log_every: u64,
+        cancel: &AtomicBool,
+    ) -> Result<(u64, String), MineError> {
+        let started = Instant::now();
         let mut nonce = 0;
         loop {
-            if nonce % 1000 == 0 {
-                println!("{}", nonce);
+            if nonce % 1000 == 0 && cancel.load(Ordering::Relaxed) {
+                debug!("mining cancelled at nonce {}", nonce);
+                return Err(MineError::Cancelled);
             }
-            let hash = Block::calculate_hash(id, timestamp, previous_hash, data, nonce);
-            if Block::validate_hash(&hash, DIFFICULTY_LEVEL) {
-                return (nonce, hex::encode(hash));
+            if log_every > 0 && nonce > 0 && nonce % log_every == 0 {
+                Block::log_progress(nonce, started);
+            }
+            let hash = fields.hash(nonce);
+            if Block::validate_hash(&hash, difficulty) {
+                return Ok((nonce, hex::encode(hash)));
+            }
+            if nonce >= max_nonce {
+                return Err(MineError::Exhausted);
             }
             nonce += 1;
         }
     }
 
-    fn validate_hash(hash: &[u8], difficulty: usize) -> bool {
-        for i in 0..(difficulty / 2) {
-            if hash[i] != 0 {
-                return false;
+    // each worker scans a stride of the nonce space; the lowest solution wins so the
+    // result is the same nonce the single-threaded search would find
+    fn mine_block_parallel(
+        fields: &HashedFields,
+        difficulty: usize,
+        max_nonce: u64,
+        threads: usize,
+        log_every: u64,
+        cancel: &AtomicBool,
+    ) -> Result<(u64, String), MineError> {
+        let started = Instant::now();
+        let stride = threads as u64;
+        let best = AtomicU64::new(u64::MAX);
+        thread::scope(|scope| {
+            for start in 0..stride {
+                let best = &best;
+                scope.spawn(move || {
+                    let mut nonce = start;
+                    while nonce <= max_nonce && nonce < best.load(Ordering::Relaxed) {
+                        let round = nonce / stride;
+                        if round.is_multiple_of(1000) && cancel.load(Ordering::Relaxed) {
+                            return;
+                        }
+                        // the first worker reports for all of them, roughly `nonce` hashes are done;
+                        // it steps by `stride`, so it lands once in each `log_every` window
+                        if start == 0
+                            && log_every > 0
+                            && nonce >= log_every
+                            && nonce % log_every < stride
+                        {
+                            Block::log_progress(nonce, started);
+                        }
+                        let hash = fields.hash(nonce);
+                        if Block::validate_hash(&hash, difficulty) {
+                            best.fetch_min(nonce, Ordering::Relaxed);
+                            return;
+                        }
+                        nonce = match nonce.checked_add(stride) {
+                            Some(next) => next,
+                            None => return,
+                        };
+                    }
+                });
             }
+        });
+        let nonce = best.into_inner();
+        if cancel.load(Ordering::Relaxed) {
+            return Err(MineError::Cancelled);
+        } else if nonce == u64::MAX {
+            return Err(MineError::Exhausted);
         }
-        true
+        Ok((nonce, hex::encode(fields.hash(nonce))))
     }
 
-    fn calculate_hash(
-        id: u64,
-        timestamp: i64,
-        previous_hash: &str,
-        data: &str,
-        nonce: u64,
-    ) -> Vec<u8> {
-        let data_json = serde_json::json!({
-        "id":id,
-        "previous_hash":previous_hash,
-        "data":data,
-        "timestamp":timestamp,
-        "nonce":nonce
-        });
-        let mut hasher = Sha256::new();
-        hasher.update(data_json.to_string().as_bytes());
-        hasher.finalize().as_slice().to_owned() // consider to_vec
+    fn log_progress(hashes: u64, started: Instant) {
+        let elapsed = started.elapsed().as_secs_f64();
+        debug!(
+            "mining, nonce {} after {:.1}s ({:.0} hashes/s)",
+            hashes,
+            elapsed,
+            hashes as f64 / elapsed.max(f64::EPSILON)
+        );
+    }
+
+    // the stored hash matches the block's fields and meets `difficulty`
+    fn has_valid_hash(&self, hasher: &dyn Hasher, difficulty: usize) -> bool {
+        let hash = Block::calculate_hash(hasher, self);
+        hex::encode(&hash) == self.hash && Block::validate_hash(&hash, difficulty)
+    }
+
+    fn validate_hash(hash: &[u8], difficulty: usize) -> bool {
+        let full_bytes = difficulty / 8;
+        let remaining_bits = difficulty % 8;
+        if hash.len() * 8 < difficulty {
+            return false;
+        }
+        if hash[..full_bytes].iter().any(|b| *b != 0) {
+            return false;
+        }
+        remaining_bits == 0 || hash[full_bytes] >> (8 - remaining_bits) == 0
+    }
+
+    pub fn calculate_hash(hasher: &dyn Hasher, block: &Block) -> Vec<u8> {
+        hasher.hash(&hashable_bytes(block))
+    }
+}
+
+// fixed layout of the hashed block fields: integers are big-endian and strings are
+// prefixed with their byte length as a big-endian u64, in the order
+// id, timestamp, previous_hash, data, merkle_root, difficulty, miner, nonce
+fn hashable_bytes(block: &Block) -> Vec<u8> {
+    let mut bytes = hashable_prefix(block);
+    bytes.extend_from_slice(&block.nonce.to_be_bytes());
+    bytes
+}
+
+// everything in `hashable_bytes` but the trailing nonce
+fn hashable_prefix(block: &Block) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&block.id.to_be_bytes());
+    bytes.extend_from_slice(&block.timestamp.to_be_bytes());
+    for field in [&block.previous_hash, &block.data, &block.merkle_root] {
+        bytes.extend_from_slice(&(field.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(field.as_bytes());
+    }
+    bytes.extend_from_slice(&(block.difficulty as u64).to_be_bytes());
+    bytes.extend_from_slice(&(block.miner.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(block.miner.as_bytes());
+    bytes
+}
+
+// `hashable_prefix` computed once, so mining only appends the nonce per attempt
+struct HashedFields<'a> {
+    hasher: &'a dyn Hasher,
+    prefix: Vec<u8>,
+}
+
+impl<'a> HashedFields<'a> {
+    // the nonce and hash of `block` are ignored
+    fn new(hasher: &'a dyn Hasher, block: &Block) -> Self {
+        let prefix = hashable_prefix(block);
+        Self { hasher, prefix }
+    }
+
+    fn hash(&self, nonce: u64) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.prefix.len() + 8);
+        bytes.extend_from_slice(&self.prefix);
+        bytes.extend_from_slice(&nonce.to_be_bytes());
+        self.hasher.hash(&bytes)
+    }
+}
+
+// a block to mine on a chain's tip, with everything taken from the `App` so it can be
+// mined on another thread while the chain moves on
+pub struct MineTask {
+    hasher: Arc<dyn Hasher>,
+    consensus: Arc<dyn Consensus>,
+    clock: Arc<dyn Clock>,
+    pub id: u64,
+    previous_hash: String,
+    data: String,
+    transactions: Vec<Transaction>,
+    pub difficulty: usize,
+    miner: String,
+    log_every: u64,
+}
+
+impl MineTask {
+    // gives up once `cancel` is set
+    pub fn mine(self, threads: usize, cancel: &AtomicBool) -> Result<Block, MineError> {
+        Block::new(
+            &*self.hasher,
+            &*self.consensus,
+            &*self.clock,
+            self.id,
+            self.previous_hash,
+            self.data,
+            self.transactions,
+            self.difficulty,
+            self.miner,
+            threads,
+            self.log_every,
+            cancel,
+        )
     }
 }
 
 impl App {
     pub fn new() -> Self {
-        Self { blocks: Vec::new() }
+        Self {
+            blocks: Vec::new(),
+            pending: Vec::new(),
+            target_block_interval: TARGET_BLOCK_INTERVAL,
+            difficulty: DIFFICULTY_LEVEL,
+            max_block_data: MAX_BLOCK_DATA_BYTES,
+            mine_log_every: MINE_LOG_EVERY,
+            miner: String::new(),
+            custom_genesis: None,
+            hasher: Arc::new(Sha256Hasher),
+            consensus: Arc::new(ProofOfWork),
+            clock: Arc::new(SystemClock),
+            light: false,
+            headers: Vec::new(),
+            last_reorg_depth: None,
+            snapshot: None,
+            checkpoints: BTreeMap::new(),
+            sources: HashMap::new(),
+        }
+    }
+
+    // an app whose blocks are stamped by `clock`, see `FixedClock`
+    #[cfg(test)]
+    pub fn new_with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            ..App::new()
+        }
     }
 
-    pub fn choose_chain(&mut self, local: Vec<Block>, remote: Vec<Block>) -> Vec<Block> {
-        let is_local_valid = self.is_chain_valid(&local);
-        let is_remote_valid = self.is_chain_valid(&remote);
+    pub fn iter(&self) -> impl Iterator<Item = &Block> {
+        self.blocks.iter()
+    }
 
-        if is_local_valid && is_remote_valid {
-            if local.len() >= remote.len() {
-                local
-            } else {
-                remote
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn tip(&self) -> Option<&Block> {
+        self.blocks.last()
+    }
+
+    // ids are positions in a valid chain, offset by what was pruned;
+    // the id check guards against anything else
+    pub fn get(&self, id: u64) -> Option<&Block> {
+        let offset = self.blocks.first().map_or(0, |b| b.id);
+        let index = usize::try_from(id.checked_sub(offset)?).ok()?;
+        self.blocks.get(index).filter(|b| b.id == id)
+    }
+
+    pub fn load_from_file(path: &Path) -> Result<App, Error> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut lines = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                lines.push(line);
             }
-        } else if is_remote_valid && !is_local_valid {
-            remote
-        } else if is_local_valid && !is_remote_valid {
-            local
+        }
+        let (version, lines) = match lines
+            .first()
+            .map(|l| serde_json::from_str::<ChainHeader>(l))
+        {
+            Some(Ok(header)) => (header.format_version, &lines[1..]),
+            _ => (0, &lines[..]),
+        };
+        match version {
+            // files from before the header are JSON lines of blocks, like version 1
+            0 | CHAIN_FORMAT_VERSION => {}
+            version => return Err(Error::ChainFormat(version)),
+        }
+        let mut blocks = Vec::new();
+        for line in lines {
+            let mut block: Block = serde_json::from_str(line)?;
+            block.migrate();
+            blocks.push(block);
+        }
+        Ok(Self {
+            blocks,
+            ..Self::new()
+        })
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_header(&mut writer)?;
+        for block in &self.blocks {
+            Block::write_line(&mut writer, block)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    // writes the chain as one pretty JSON array, unlike the JSON lines of `save_to_file`
+    pub fn export_to_file(&self, path: &Path, overwrite: bool) -> Result<(), Error> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .create_new(!overwrite)
+            .truncate(true)
+            .open(path)?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, &self.blocks)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn import_from_file(path: &Path) -> Result<Vec<Block>, Error> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut blocks: Vec<Block> = serde_json::from_reader(reader)?;
+        blocks.iter_mut().for_each(Block::migrate);
+        Ok(blocks)
+    }
+
+    // cheap structural checks on a chain from a peer, run before `choose_chain`
+    pub fn check_remote_chain(&self, chain: &[Block]) -> Result<(), ChainError> {
+        let first = chain.first().ok_or(ChainError::Empty)?;
+        let continues_snapshot = self
+            .snapshot
+            .as_ref()
+            .is_some_and(|s| first.id == s.id + 1 && first.previous_hash == s.hash);
+        if first.hash != self.genesis_hash() && !continues_snapshot {
+            Err(ChainError::WrongGenesis)
+        } else if chain.windows(2).any(|w| w[1].id != w[0].id + 1) {
+            Err(ChainError::NonSequentialIds)
+        } else if !chain.iter().all(Block::is_supported) {
+            Err(ChainError::UnsupportedVersion)
         } else {
-            panic!("local and remote are invalid");
+            Ok(())
         }
     }
 
-    fn is_chain_valid(&self, chain: &[Block]) -> bool {
-        for i in 1..chain.len() {
-            let previous_block = chain.get(i - 1).expect("has to exist");
-            let block = chain.get(i).expect("has to exist");
-            if !self.is_block_valid(block, previous_block) {
-                return false;
+    // prefixes a chain that starts past genesis with the local blocks it builds on,
+    // None if it doesn't link to the local chain or snapshot
+    pub fn complete_chain(&self, suffix: &[Block]) -> Option<Vec<Block>> {
+        let first = suffix.first()?;
+        let parent_hash = match self.get(first.id.checked_sub(1)?) {
+            Some(parent) => &parent.hash,
+            None => {
+                &self
+                    .snapshot
+                    .as_ref()
+                    .filter(|s| s.id + 1 == first.id)?
+                    .hash
             }
+        };
+        if *parent_hash != first.previous_hash {
+            return None;
+        }
+        let prefix = self.blocks.iter().take_while(|b| b.id < first.id);
+        Some(prefix.chain(suffix).cloned().collect())
+    }
+
+    // the genesis this node is on, or would start from
+    fn genesis_hash(&self) -> String {
+        let local = self.snapshot.as_ref().map(|s| &s.genesis_hash);
+        let local = local.or(self.blocks.first().map(|b| &b.hash));
+        let local = local.or(self.headers.first().map(|h| &h.hash));
+        match (local, &self.custom_genesis) {
+            (Some(hash), _) => hash.clone(),
+            (None, Some(genesis)) => genesis.hash.clone(),
+            (None, None) => App::genesis_block().hash,
+        }
+    }
+
+    pub fn rules(&self) -> ChainRules<'_> {
+        ChainRules {
+            difficulty: self.difficulty,
+            target_block_interval: self.target_block_interval,
+            max_block_data: self.max_block_data,
+            hasher: &*self.hasher,
+            consensus: &*self.consensus,
+            now: self.clock.now(),
+            snapshot: self.snapshot.as_ref(),
+            checkpoints: &self.checkpoints,
+        }
+    }
+
+    pub fn choose_chain(&self, local: Vec<Block>, remote: Vec<Block>) -> Option<Vec<Block>> {
+        choose_chain(&self.rules(), local, remote)
+    }
+
+    pub fn total_work<T: ChainEntry>(&self, chain: &[T]) -> u128 {
+        self.rules().total_work(chain)
+    }
+
+    pub fn is_chain_valid(&self, chain: &[Block]) -> Result<(), usize> {
+        is_chain_valid(&self.rules(), chain)
+    }
+
+    // a line per block: leading zero bits of its recomputed hash and the difficulty it
+    // needed, whether it validates on the blocks before it, and the work up to it
+    pub fn describe_chain(&self) -> String {
+        let rules = self.rules();
+        let snapshot = self.blocks.first().and_then(|b| rules.snapshot_for(b.id));
+        let mut work = snapshot.map_or(0, |s| s.work);
+        let mut description = String::new();
+        for (i, block) in self.blocks.iter().enumerate() {
+            let chain = &self.blocks[..i];
+            let required = rules.difficulty_at(snapshot, chain);
+            work = work.saturating_add(1u128.checked_shl(required as u32).unwrap_or(u128::MAX));
+            // genesis is always SHA-256
+            let hasher: &dyn Hasher = if block.id == 0 {
+                &Sha256Hasher
+            } else {
+                rules.hasher
+            };
+            let bits = leading_zero_bits(&Block::calculate_hash(hasher, block));
+            let validity = match rules.validate_block(block, chain) {
+                Ok(()) => String::from("valid"),
+                Err(e) => format!("invalid: {}", e),
+            };
+            description.push_str(&format!(
+                "block {} {}: {} zero bits (needs {}), {}, work {}\n",
+                block.id, block.hash, bits, required, validity, work
+            ));
+        }
+        description
+    }
+
+    pub fn next_difficulty(&self) -> usize {
+        if self.light {
+            self.rules().difficulty_for(&self.headers)
+        } else {
+            self.rules().difficulty_for(&self.blocks)
+        }
+    }
+
+    // drops block bodies, keeping only their headers
+    pub fn make_light(&mut self) {
+        self.headers = self.blocks.iter().map(BlockHeader::from).collect();
+        self.blocks.clear();
+        self.light = true;
+    }
+
+    pub fn header_tip(&self) -> Option<&BlockHeader> {
+        self.headers.last()
+    }
+
+    // id of the tip in either mode
+    pub fn height(&self) -> Option<u64> {
+        if self.light {
+            self.header_tip().map(|h| h.id)
+        } else {
+            self.tip().map(|b| b.id)
+        }
+    }
+
+    // adopts `remote` if it is a valid header chain with more work
+    pub fn choose_headers(&mut self, remote: Vec<BlockHeader>) -> bool {
+        if self.is_header_chain_valid(&remote).is_err()
+            || self.total_work(&remote) <= self.total_work(&self.headers)
+        {
+            return false;
         }
+        self.headers = remote;
         true
     }
 
+    pub fn try_add_header(&mut self, header: BlockHeader) -> Result<(), BlockError> {
+        self.validate_header(&header, &self.headers)?;
+        self.headers.push(header);
+        Ok(())
+    }
+
+    // Err holds the index of the first header that does not validate
+    pub fn is_header_chain_valid(&self, headers: &[BlockHeader]) -> Result<(), usize> {
+        for (i, header) in headers.iter().enumerate() {
+            if let Err(e) = self.validate_header(header, &headers[..i]) {
+                warn!("invalid header {}: {}", header.id, e);
+                return Err(i);
+            }
+        }
+        Ok(())
+    }
+
+    // without the body the hash can't be recomputed, only linkage and work are checked
+    fn validate_header(
+        &self,
+        header: &BlockHeader,
+        chain: &[BlockHeader],
+    ) -> Result<(), BlockError> {
+        if self
+            .checkpoints
+            .get(&header.id)
+            .is_some_and(|hash| *hash != header.hash)
+        {
+            return Err(BlockError::CheckpointMismatch);
+        }
+        let hash = hex::decode(&header.hash).map_err(|_| BlockError::MalformedHash)?;
+        let Some(previous) = chain.last() else {
+            return if header.id != 0 {
+                Err(BlockError::WrongId)
+            } else if header.previous_hash != GENESIS_PREVIOUS_HASH {
+                Err(BlockError::WrongPreviousHash)
+            } else {
+                Ok(())
+            };
+        };
+        if header.previous_hash != previous.hash {
+            Err(BlockError::WrongPreviousHash)
+        } else if !Block::validate_hash(&hash, self.rules().difficulty_for(chain)) {
+            Err(BlockError::WrongDifficulty)
+        } else if header.id != previous.id + 1 {
+            Err(BlockError::WrongId)
+        } else if header.timestamp < previous.timestamp {
+            Err(BlockError::TimestampBeforeParent)
+        } else if header.timestamp > self.clock.now() + MAX_DRIFT {
+            Err(BlockError::TimestampInFuture)
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn genesis(&mut self) {
-        let genesis_block = Block {
+        let genesis_block = self
+            .custom_genesis
+            .clone()
+            .unwrap_or_else(App::genesis_block);
+        if self.light {
+            self.headers.push(BlockHeader::from(&genesis_block));
+        } else {
+            self.blocks.push(genesis_block);
+        }
+    }
+
+    // the hardcoded genesis, re-mined deterministically if it doesn't verify
+    pub fn genesis_block() -> Block {
+        let allocations = vec![Transaction {
+            from: GENESIS_ACCOUNT.to_string(),
+            to: GENESIS_ACCOUNT.to_string(),
+            amount: GENESIS_SUPPLY,
+            nonce: 0,
+        }];
+        let mut genesis_block = Block {
+            version: BLOCK_VERSION,
             id: 0,
-            timestamp: Utc::now().timestamp(),
-            previous_hash: String::from("genesis"),
+            timestamp: GENESIS_TIMESTAMP,
+            previous_hash: GENESIS_PREVIOUS_HASH.to_string(),
             data: String::from("genesis!"),
-            nonce: 2836,
-            hash: "0000f816a87f806bb0073dcf026a64fb40c946b5abee2573702828694d5b4c43".to_string(),
+            merkle_root: merkle_root(&allocations),
+            transactions: allocations,
+            difficulty: DIFFICULTY_LEVEL,
+            miner: GENESIS_ACCOUNT.to_string(),
+            nonce: 109616,
+            hash: "00008e89dbb8aad2a3d1098a67a5c2fdd13e4fa5461aad971c59185559069991".to_string(),
+            signature: String::new(),
         };
-        self.blocks.push(genesis_block);
+        if !genesis_block.has_valid_hash(&Sha256Hasher, DIFFICULTY_LEVEL) {
+            warn!("hardcoded genesis does not verify, mining a new one");
+            App::mine_genesis(&mut genesis_block).expect("can mine the default genesis");
+        }
+        genesis_block
     }
 
-    pub fn try_add_bock(&mut self, block: Block) {
-        let latest_block = self.blocks.last().expect("no blocks added");
-        if self.is_block_valid(&block, latest_block) {
-            self.blocks.push(block);
-        } else {
-            panic!("could not add block - invalid")
-        }
+    // a JSON object of block id to expected hash
+    pub fn checkpoints_from_file(path: &Path) -> Result<BTreeMap<u64, String>, Error> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
     }
 
-    fn is_block_valid(&self, block: &Block, previous_block: &Block) -> bool {
-        if block.previous_hash != previous_block.hash {
-            return false;
-        } else if !Block::validate_hash(
-            &hex::decode(&block.hash).expect("can decode from hex"),
+    // mines a genesis from a config file, the same config always gives the same block
+    pub fn genesis_from_config(path: &Path) -> Result<Block, Error> {
+        let config: GenesisConfig = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        let allocations: Vec<Transaction> = config
+            .allocations
+            .into_iter()
+            .enumerate()
+            .map(|(i, allocation)| Transaction {
+                from: GENESIS_ACCOUNT.to_string(),
+                to: allocation.account,
+                amount: allocation.amount,
+                nonce: i as u64,
+            })
+            .collect();
+        let mut genesis_block = Block {
+            version: BLOCK_VERSION,
+            id: 0,
+            timestamp: config.timestamp,
+            previous_hash: GENESIS_PREVIOUS_HASH.to_string(),
+            data: config.data,
+            merkle_root: merkle_root(&allocations),
+            transactions: allocations,
+            difficulty: DIFFICULTY_LEVEL,
+            miner: GENESIS_ACCOUNT.to_string(),
+            nonce: 0,
+            hash: String::new(),
+            signature: String::new(),
+        };
+        App::mine_genesis(&mut genesis_block)?;
+        Ok(genesis_block)
+    }
+
+    // genesis is always SHA-256 so every build agrees on it
+    fn mine_genesis(genesis_block: &mut Block) -> Result<(), MineError> {
+        let fields = HashedFields::new(&Sha256Hasher, genesis_block);
+        let (nonce, hash) = Block::mine_block(
+            &fields,
             DIFFICULTY_LEVEL,
-        ) {
-            println!("Wrong difficulty prefix");
-            return false;
-        } else if block.id != previous_block.id + 1 {
-            println!("Wrong id");
-            return false;
-        } else if hex::encode(Block::calculate_hash(
-            block.id,
-            block.timestamp,
-            &block.previous_hash,
-            &block.data,
-            block.nonce,
-        )) != block.hash
-        {
-            println!("Wrong hash");
-            return false;
+            u64::MAX,
+            MINE_LOG_EVERY,
+            &AtomicBool::new(false),
+        )?;
+        genesis_block.nonce = nonce;
+        genesis_block.hash = hash;
+        Ok(())
+    }
+
+    pub fn balances(&self) -> HashMap<String, i64> {
+        let rules = self.rules();
+        let snapshot = self.blocks.first().and_then(|b| rules.snapshot_for(b.id));
+        App::balances_from(snapshot, &self.blocks)
+    }
+
+    pub fn stats(&self) -> ChainStats {
+        // genesis carries a fixed timestamp, so intervals are measured after it
+        let mined: Vec<&Block> = self.blocks.iter().filter(|b| b.id > 0).collect();
+        let average_interval = match (mined.first(), mined.last()) {
+            (Some(first), Some(last)) if mined.len() > 1 => {
+                Some((last.timestamp - first.timestamp) as f64 / (mined.len() - 1) as f64)
+            }
+            _ => None,
+        };
+        ChainStats {
+            blocks: self.blocks.len(),
+            transactions: self.blocks.iter().map(|b| b.transactions.len()).sum(),
+            average_interval,
+            total_work: self.total_work(&self.blocks),
+            largest_block: self
+                .blocks
+                .iter()
+                .map(|b| (b.id, b.data.len()))
+                .max_by_key(|&(id, size)| (size, Reverse(id))),
         }
-        true
+    }
+
+    // genesis and coinbase transactions credit the receiver without a sender; validated
+    // chains stay in range, anything else saturates rather than wraps
+    fn balances_from(snapshot: Option<&Snapshot>, chain: &[Block]) -> HashMap<String, i64> {
+        let mut balances = snapshot.map(|s| s.balances.clone()).unwrap_or_default();
+        for block in chain {
+            App::replay_block(&mut balances, block);
+        }
+        balances
+    }
+
+    fn replay_block(balances: &mut HashMap<String, i64>, block: &Block) {
+        for tx in &block.transactions {
+            let amount = i64::try_from(tx.amount).unwrap_or(i64::MAX);
+            if block.id != 0 && !tx.is_coinbase() {
+                let sender = balances.entry(tx.from.clone()).or_insert(0);
+                *sender = sender.saturating_sub(amount);
+            }
+            let receiver = balances.entry(tx.to.clone()).or_insert(0);
+            *receiver = receiver.saturating_add(amount);
+        }
+    }
+
+    // number of transactions already sent by `from`, used as the next tx nonce
+    pub fn next_nonce(&self, from: &str) -> u64 {
+        let pruned = self
+            .snapshot
+            .as_ref()
+            .and_then(|s| s.sent.get(from).copied());
+        pruned.unwrap_or(0)
+            + self
+                .blocks
+                .iter()
+                .flat_map(|b| b.transactions.iter())
+                .chain(self.pending.iter())
+                .filter(|tx| tx.from == from)
+                .count() as u64
+    }
+
+    // drops pending transactions that made it into the chain
+    pub fn prune_pending(&mut self) {
+        let blocks = &self.blocks;
+        self.pending
+            .retain(|tx| !blocks.iter().any(|b| b.transactions.contains(tx)));
+    }
+
+    // whether `tx` can be queued on top of the chain and the current mempool
+    pub fn can_afford(&self, tx: &Transaction) -> bool {
+        let mut balances = self.balances();
+        let txs = self.pending.iter().chain(std::iter::once(tx));
+        App::apply_transactions(&mut balances, txs).is_ok()
+    }
+
+    fn apply_transactions<'a>(
+        balances: &mut HashMap<String, i64>,
+        txs: impl IntoIterator<Item = &'a Transaction>,
+    ) -> Result<(), BlockError> {
+        for tx in txs {
+            let amount = i64::try_from(tx.amount).map_err(|_| BlockError::AmountOutOfRange)?;
+            let sender = balances.entry(tx.from.clone()).or_insert(0);
+            *sender = sender
+                .checked_sub(amount)
+                .filter(|balance| *balance >= 0)
+                .ok_or(BlockError::Overdraft)?;
+            let receiver = balances.entry(tx.to.clone()).or_insert(0);
+            *receiver = receiver
+                .checked_add(amount)
+                .ok_or(BlockError::AmountOutOfRange)?;
+        }
+        Ok(())
+    }
+
+    // the coinbase for block `id` followed by the pending transactions
+    pub fn next_transactions(&self, id: u64) -> Vec<Transaction> {
+        let mut transactions = vec![Transaction::coinbase(self.miner.clone(), id)];
+        transactions.extend(self.pending.iter().cloned());
+        transactions
+    }
+
+    // what mining `data` on the tip takes, or why this chain can't mine it
+    pub fn mine_task(&self, data: String) -> Result<MineTask, MineError> {
+        if data.len() > self.max_block_data {
+            return Err(MineError::DataTooLarge(data.len(), self.max_block_data));
+        } else if self.light {
+            return Err(MineError::LightNode);
+        } else if !self.consensus.can_seal() {
+            return Err(MineError::NotAuthority);
+        }
+        let tip = self.tip().ok_or(MineError::Uninitialised)?;
+        let id = tip.id + 1;
+        Ok(MineTask {
+            hasher: self.hasher.clone(),
+            consensus: self.consensus.clone(),
+            clock: self.clock.clone(),
+            id,
+            previous_hash: tip.hash.clone(),
+            data,
+            transactions: self.next_transactions(id),
+            difficulty: self.next_difficulty(),
+            miner: self.miner.clone(),
+            log_every: self.mine_log_every,
+        })
+    }
+
+    // mines `data` on the tip on the calling thread and appends it, starting from genesis
+    // on an empty chain; for use without a network, nodes take the same `mine_task` to a
+    // blocking thread and add what comes back with `try_add_bock`, so only tests call this
+    #[allow(dead_code)]
+    pub fn add_mined_block(&mut self, data: String) -> Result<Block, Error> {
+        if self.blocks.is_empty() && !self.light {
+            self.genesis();
+        }
+        let block = self.mine_task(data)?.mine(1, &AtomicBool::new(false))?;
+        self.try_add_bock(block.clone(), None)?;
+        Ok(block)
+    }
+
+    // `source` is the peer the block arrived from, None for blocks mined here
+    pub fn try_add_bock(&mut self, block: Block, source: Option<&str>) -> Result<(), BlockError> {
+        self.rules().validate_block(&block, &self.blocks)?;
+        self.pending.retain(|tx| !block.transactions.contains(tx));
+        if let Some(source) = source {
+            self.sources.insert(block.hash.clone(), source.to_string());
+        }
+        self.blocks.push(block);
+        Ok(())
+    }
+
+    // drops all but the last `keep` blocks (a few more so the cut lands on a retarget
+    // window boundary) and folds them into the snapshot; returns how many were dropped
+    pub fn prune(&mut self, keep: usize) -> usize {
+        let offset = self.blocks.first().map_or(0, |b| b.id as usize);
+        let height = offset + self.blocks.len();
+        let Some(cut) = height.checked_sub(keep).filter(|&cut| cut > offset) else {
+            return 0;
+        };
+        let cut = (cut - 1) / DIFFICULTY_WINDOW * DIFFICULTY_WINDOW + 1;
+        if cut <= offset {
+            return 0;
+        }
+        let dropped = cut - offset;
+        let prefix = &self.blocks[..dropped];
+        let last = prefix.last().expect("prefix is not empty");
+        let rules = self.rules();
+        let mut sent = self
+            .snapshot
+            .as_ref()
+            .map(|s| s.sent.clone())
+            .unwrap_or_default();
+        for tx in prefix.iter().flat_map(|b| b.transactions.iter()) {
+            *sent.entry(tx.from.clone()).or_insert(0) += 1;
+        }
+        let snapshot = Snapshot {
+            genesis_hash: self.genesis_hash(),
+            id: last.id,
+            hash: last.hash.clone(),
+            timestamp: last.timestamp,
+            difficulty: rules.difficulty_for(prefix),
+            work: rules.total_work(prefix),
+            balances: App::balances_from(rules.snapshot_for(prefix[0].id), prefix),
+            sent,
+        };
+        self.snapshot = Some(snapshot);
+        self.blocks.drain(..dropped);
+        self.retain_sources();
+        dropped
+    }
+
+    // forgets where blocks no longer in the chain came from
+    pub fn retain_sources(&mut self) {
+        let hashes: HashSet<&String> = self.blocks.iter().map(|b| &b.hash).collect();
+        self.sources.retain(|hash, _| hashes.contains(hash));
+    }
+
+    // genesis is checked on its own, it has no previous block to compare against
+    fn validate_genesis(block: &Block) -> Result<(), BlockError> {
+        if block.id != 0 {
+            Err(BlockError::WrongId)
+        } else if block.previous_hash != GENESIS_PREVIOUS_HASH {
+            Err(BlockError::WrongPreviousHash)
+        } else if merkle_root(&block.transactions) != block.merkle_root {
+            Err(BlockError::WrongMerkleRoot)
+        } else if block.difficulty != DIFFICULTY_LEVEL {
+            Err(BlockError::DifficultyMismatch)
+        } else if !block.has_valid_hash(&Sha256Hasher, DIFFICULTY_LEVEL) {
+            Err(BlockError::WrongHash)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// the consensus parameters validation and fork choice depend on, so both can run
+// on plain slices without an App
+#[derive(Clone, Copy)]
+pub struct ChainRules<'a> {
+    pub difficulty: usize,
+    pub target_block_interval: i64,
+    pub max_block_data: usize,
+    pub hasher: &'a dyn Hasher,
+    pub consensus: &'a dyn Consensus,
+    // read once, the time blocks may not be too far ahead of
+    pub now: i64,
+    // where pruned chains resume, chains starting right after it are validated from it
+    pub snapshot: Option<&'a Snapshot>,
+    pub checkpoints: &'a BTreeMap<u64, String>,
+}
+
+pub fn choose_chain(
+    rules: &ChainRules,
+    local: Vec<Block>,
+    remote: Vec<Block>,
+) -> Option<Vec<Block>> {
+    let is_local_valid = is_chain_valid(rules, &local).is_ok();
+    let is_remote_valid = is_chain_valid(rules, &remote).is_ok();
+
+    if is_local_valid && is_remote_valid {
+        if rules.chain_weight(&remote) > rules.chain_weight(&local) {
+            Some(remote)
+        } else {
+            Some(local)
+        }
+    } else if is_remote_valid && !is_local_valid {
+        Some(remote)
+    } else if is_local_valid && !is_remote_valid {
+        Some(local)
+    } else {
+        None
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> usize {
+    match hash.iter().position(|b| *b != 0) {
+        Some(i) => i * 8 + hash[i].leading_zeros() as usize,
+        None => hash.len() * 8,
+    }
+}
+
+// hashes per second of one thread over `duration`, on a copy of genesis with a changing
+// nonce so no chain is touched
+pub fn measure_hashrate(hasher: &dyn Hasher, duration: Duration) -> f64 {
+    let mut block = App::genesis_block();
+    let start = Instant::now();
+    let mut hashes = 0u64;
+    while start.elapsed() < duration {
+        block.nonce = hashes;
+        std::hint::black_box(Block::calculate_hash(hasher, &block));
+        hashes += 1;
+    }
+    hashes as f64 / start.elapsed().as_secs_f64()
+}
+
+// Err holds the index of the first block that does not validate; the difficulty and
+// balances are carried from block to block instead of replayed from the start for each
+pub fn is_chain_valid(rules: &ChainRules, chain: &[Block]) -> Result<(), usize> {
+    let snapshot = chain.first().and_then(|b| rules.snapshot_for(b.id));
+    let (mut difficulty, offset) = match snapshot {
+        Some(s) => (s.difficulty, s.id as usize + 1),
+        None => (rules.difficulty, 0),
+    };
+    // the chain length at which the next window closes, as in `difficulty_at`
+    let mut retarget_at = offset.max(1) + DIFFICULTY_WINDOW;
+    let mut balances = App::balances_from(snapshot, &[]);
+    for (i, block) in chain.iter().enumerate() {
+        if offset + i == retarget_at {
+            difficulty = rules.retarget(difficulty, &chain[i - DIFFICULTY_WINDOW..i]);
+            retarget_at += DIFFICULTY_WINDOW;
+        }
+        let previous = i.checked_sub(1).map(|p| &chain[p]);
+        if let Err(e) = rules.validate_next(block, previous, snapshot, difficulty, &mut balances) {
+            warn!("invalid block {}: {}", block.id, e);
+            return Err(i);
+        }
+    }
+    Ok(())
+}
+
+impl<'a> ChainRules<'a> {
+    // the snapshot a chain whose first block is `first_id` continues, if any
+    fn snapshot_for(&self, first_id: u64) -> Option<&'a Snapshot> {
+        self.snapshot.filter(|s| first_id == s.id + 1)
+    }
+
+    // more work wins, then the longer chain, then the one whose tip is older
+    fn chain_weight(&self, chain: &[Block]) -> (u128, usize, Reverse<i64>) {
+        let tip_timestamp = chain.last().map_or(i64::MAX, |b| b.timestamp);
+        (self.total_work(chain), chain.len(), Reverse(tip_timestamp))
+    }
+
+    pub fn total_work<T: ChainEntry>(&self, chain: &[T]) -> u128 {
+        let snapshot = chain.first().and_then(|b| self.snapshot_for(b.id()));
+        (0..chain.len())
+            .map(|i| self.difficulty_at(snapshot, &chain[..i]))
+            .map(|difficulty| 1u128.checked_shl(difficulty as u32).unwrap_or(u128::MAX))
+            .fold(snapshot.map_or(0, |s| s.work), u128::saturating_add)
+    }
+
+    // difficulty required for the block that would follow `chain`
+    fn difficulty_for<T: ChainEntry>(&self, chain: &[T]) -> usize {
+        let snapshot = chain.first().and_then(|b| self.snapshot_for(b.id()));
+        self.difficulty_at(snapshot, chain)
+    }
+
+    // as `difficulty_for`, with `chain` starting at genesis or right after `snapshot`;
+    // snapshots sit on window boundaries so no window straddles one
+    fn difficulty_at<T: ChainEntry>(&self, snapshot: Option<&Snapshot>, chain: &[T]) -> usize {
+        let (mut difficulty, offset) = match snapshot {
+            Some(s) => (s.difficulty, s.id as usize + 1),
+            None => (self.difficulty, 0),
+        };
+        let mut height = offset.max(1) + DIFFICULTY_WINDOW;
+        while height <= offset + chain.len() {
+            let window = &chain[height - DIFFICULTY_WINDOW - offset..height - offset];
+            difficulty = self.retarget(difficulty, window);
+            height += DIFFICULTY_WINDOW;
+        }
+        difficulty
+    }
+
+    fn retarget<T: ChainEntry>(&self, difficulty: usize, window: &[T]) -> usize {
+        let first = window.first().expect("window is not empty");
+        let last = window.last().expect("window is not empty");
+        let actual = last.timestamp() - first.timestamp();
+        let expected = self.target_block_interval * (window.len() as i64 - 1);
+        if actual < expected / 2 {
+            (difficulty + 1).min(MAX_DIFFICULTY)
+        } else if actual > expected * 2 {
+            difficulty.saturating_sub(1).max(1)
+        } else {
+            difficulty
+        }
+    }
+
+    // only the first transaction may be a coinbase, paying the fixed reward for this height
+    // `balances` are before the block, and take on its transactions as they are applied
+    fn validate_transactions(
+        &self,
+        block: &Block,
+        balances: &mut HashMap<String, i64>,
+    ) -> Result<(), BlockError> {
+        let mut txs = block.transactions.as_slice();
+        if let Some((first, rest)) = txs.split_first() {
+            if first.is_coinbase() {
+                if *first != Transaction::coinbase(first.to.clone(), block.id) {
+                    return Err(BlockError::InvalidCoinbase);
+                }
+                let miner = balances.entry(first.to.clone()).or_insert(0);
+                *miner = miner.saturating_add(BLOCK_REWARD as i64);
+                txs = rest;
+            }
+        }
+        if txs.iter().any(Transaction::is_coinbase) {
+            return Err(BlockError::InvalidCoinbase);
+        }
+        App::apply_transactions(balances, txs)
+    }
+
+    // validates `block` as the successor of `chain`, which starts at genesis or at the snapshot
+    fn validate_block(&self, block: &Block, chain: &[Block]) -> Result<(), BlockError> {
+        let snapshot = self.snapshot_for(chain.first().map_or(block.id, |b| b.id));
+        let difficulty = self.difficulty_at(snapshot, chain);
+        let mut balances = App::balances_from(snapshot, chain);
+        self.validate_next(block, chain.last(), snapshot, difficulty, &mut balances)
+    }
+
+    // as `validate_block`, with the parent, the difficulty and the balances after the parent
+    // worked out by the caller; a valid block's transactions are applied to `balances`
+    fn validate_next(
+        &self,
+        block: &Block,
+        previous: Option<&Block>,
+        snapshot: Option<&Snapshot>,
+        difficulty: usize,
+        balances: &mut HashMap<String, i64>,
+    ) -> Result<(), BlockError> {
+        // before any hashing, so oversized blocks cost nothing to turn away
+        if block.data.len() > self.max_block_data {
+            return Err(BlockError::DataTooLarge);
+        } else if self
+            .checkpoints
+            .get(&block.id)
+            .is_some_and(|hash| *hash != block.hash)
+        {
+            return Err(BlockError::CheckpointMismatch);
+        }
+        let (previous_id, previous_hash, previous_timestamp) = match (previous, snapshot) {
+            (Some(b), _) => (b.id, &b.hash, b.timestamp),
+            (None, Some(s)) => (s.id, &s.hash, s.timestamp),
+            (None, None) => {
+                App::validate_genesis(block)?;
+                App::replay_block(balances, block);
+                return Ok(());
+            }
+        };
+        // the stored hash is only a claim, work is checked on the one recomputed from the fields
+        let hash = Block::calculate_hash(self.hasher, block);
+        if block.previous_hash != *previous_hash {
+            return Err(BlockError::WrongPreviousHash);
+        } else if block.difficulty != difficulty {
+            return Err(BlockError::DifficultyMismatch);
+        } else if hex::encode(&hash) != block.hash {
+            return Err(BlockError::WrongHash);
+        } else if !self.consensus.verify(block) {
+            return Err(BlockError::InvalidSeal);
+        } else if block.id != previous_id + 1 {
+            return Err(BlockError::WrongId);
+        } else if merkle_root(&block.transactions) != block.merkle_root {
+            return Err(BlockError::WrongMerkleRoot);
+        } else if block.timestamp < previous_timestamp {
+            return Err(BlockError::TimestampBeforeParent);
+        } else if block.timestamp > self.now + MAX_DRIFT {
+            return Err(BlockError::TimestampInFuture);
+        }
+        self.validate_transactions(block, balances)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // cheap to mine, so tests can grow chains quickly
+    fn test_app() -> App {
+        let mut app = App::new();
+        app.difficulty = 1;
+        app.miner = "miner".to_string();
+        app.genesis();
+        app
+    }
+
+    fn transfer(from: &str, to: &str, amount: u64, nonce: u64) -> Transaction {
+        Transaction {
+            from: from.to_string(),
+            to: to.to_string(),
+            amount,
+            nonce,
+        }
+    }
+
+    // a block on the tip of `app` carrying `transactions` after the coinbase
+    fn block_with(app: &App, transactions: Vec<Transaction>) -> Block {
+        let tip = app.tip().expect("test apps start from genesis");
+        let id = tip.id + 1;
+        let mut txs = vec![Transaction::coinbase(app.miner.clone(), id)];
+        txs.extend(transactions);
+        Block::new(
+            &*app.hasher,
+            &*app.consensus,
+            &*app.clock,
+            id,
+            tip.hash.clone(),
+            "data".to_string(),
+            txs,
+            app.next_difficulty(),
+            app.miner.clone(),
+            1,
+            0,
+            &AtomicBool::new(false),
+        )
+        .expect("mines")
+    }
+
+    #[test]
+    fn same_clock_and_inputs_mine_the_same_chain() {
+        let mine = || {
+            let clock = Arc::new(crate::clock::FixedClock(GENESIS_TIMESTAMP + 1000));
+            let mut app = App::new_with_clock(clock);
+            app.difficulty = 8;
+            app.miner = "miner".to_string();
+            app.pending.push(transfer(GENESIS_ACCOUNT, "alice", 10, 1));
+            for data in ["one", "two", "three"] {
+                app.add_mined_block(data.to_string()).expect("mines");
+            }
+            let mut bytes = Vec::new();
+            for block in app.iter() {
+                Block::write_line(&mut bytes, block).expect("can serialize");
+            }
+            bytes
+        };
+        assert_eq!(mine(), mine());
+    }
+
+    #[test]
+    fn mined_block_extends_the_tip() {
+        let mut app = test_app();
+        let tip = app.tip().expect("starts from genesis").clone();
+        let block = app.add_mined_block("next".to_string()).expect("mines");
+        assert_eq!(block.id, tip.id + 1);
+        assert_eq!(block.previous_hash, tip.hash);
+        assert_eq!(app.tip().map(|b| &b.hash), Some(&block.hash));
+    }
+
+    #[test]
+    fn lowest_difficulty_mines_and_validates() {
+        let mut app = test_app();
+        for data in ["one", "two", "three"] {
+            let block = app.add_mined_block(data.to_string()).expect("mines");
+            assert_eq!(block.difficulty, 1);
+        }
+        assert_eq!(app.is_chain_valid(&app.blocks), Ok(()));
+    }
+
+    #[test]
+    fn mining_gives_up_past_max_nonce() {
+        let block = App::genesis_block();
+        let fields = HashedFields::new(&Sha256Hasher, &block);
+        let cancel = AtomicBool::new(false);
+        assert!(matches!(
+            Block::mine_block(&fields, MAX_DIFFICULTY, 1000, 0, &cancel),
+            Err(MineError::Exhausted)
+        ));
+        assert!(matches!(
+            Block::mine_block_parallel(&fields, MAX_DIFFICULTY, 1000, 4, 0, &cancel),
+            Err(MineError::Exhausted)
+        ));
+        // genesis' own nonce is still found when the cap is exactly it
+        let found = Block::mine_block(&fields, DIFFICULTY_LEVEL, block.nonce, 0, &cancel);
+        assert_eq!(
+            found.expect("is in range"),
+            (block.nonce, block.hash.clone())
+        );
+        let found =
+            Block::mine_block_parallel(&fields, DIFFICULTY_LEVEL, block.nonce, 4, 0, &cancel);
+        assert_eq!(found.expect("is in range"), (block.nonce, block.hash));
+    }
+
+    #[test]
+    fn amounts_past_i64_are_rejected() {
+        let mut app = test_app();
+        let tx = transfer(GENESIS_ACCOUNT, "alice", MAX_AMOUNT + 1, 1);
+        assert!(!app.can_afford(&tx));
+        let mut balances = app.balances();
+        assert_eq!(
+            App::apply_transactions(&mut balances, [&tx]),
+            Err(BlockError::AmountOutOfRange)
+        );
+        let block = block_with(&app, vec![tx]);
+        assert_eq!(
+            app.try_add_bock(block, None),
+            Err(BlockError::AmountOutOfRange)
+        );
+        assert_eq!(app.len(), 1);
+    }
+
+    #[test]
+    fn credits_that_overflow_are_rejected() {
+        let mut balances = HashMap::from([
+            ("alice".to_string(), i64::MAX),
+            ("bob".to_string(), i64::MAX),
+        ]);
+        let tx = transfer("alice", "bob", 1, 0);
+        assert_eq!(
+            App::apply_transactions(&mut balances, [&tx]),
+            Err(BlockError::AmountOutOfRange)
+        );
+    }
+
+    // a 256-bit hash whose first set bit is bit `zeros`, counted from the most significant
+    fn hash_with_zero_bits(zeros: usize) -> Vec<u8> {
+        let mut hash = vec![0u8; 32];
+        hash[zeros / 8] = 0x80 >> (zeros % 8);
+        hash
+    }
+
+    #[test]
+    fn difficulty_counts_leading_zero_bits() {
+        for zeros in 0..=20 {
+            let hash = hash_with_zero_bits(zeros);
+            assert_eq!(leading_zero_bits(&hash), zeros);
+            for difficulty in 0..=24 {
+                assert_eq!(
+                    Block::validate_hash(&hash, difficulty),
+                    difficulty <= zeros,
+                    "{} zero bits at difficulty {}",
+                    zeros,
+                    difficulty
+                );
+            }
+        }
+        assert!(Block::validate_hash(&[0u8; 32], MAX_DIFFICULTY));
+        assert!(!Block::validate_hash(&[0u8; 32], MAX_DIFFICULTY + 1));
+    }
+
+    #[test]
+    fn mined_blocks_meet_their_difficulty() {
+        let mut app = test_app();
+        app.difficulty = 12;
+        let block = app.add_mined_block("twelve".to_string()).expect("mines");
+        let hash = hex::decode(&block.hash).expect("is hex");
+        assert!(leading_zero_bits(&hash) >= 12);
+        assert!(block.has_valid_hash(&Sha256Hasher, 12));
+    }
+
+    fn remine(app: &App, block: &mut Block) {
+        block
+            .mine(&*app.hasher, 1, 0, &AtomicBool::new(false))
+            .expect("mines");
+    }
+
+    #[test]
+    fn invalid_blocks_are_rejected_and_not_added() {
+        let mut app = test_app();
+        let good = block_with(&app, Vec::new());
+
+        let mut wrong_previous = good.clone();
+        wrong_previous.previous_hash = hex::encode([1u8; 32]);
+        remine(&app, &mut wrong_previous);
+        let mut wrong_difficulty = good.clone();
+        wrong_difficulty.difficulty += 1;
+        remine(&app, &mut wrong_difficulty);
+        let mut wrong_hash = good.clone();
+        wrong_hash.data = "tampered".to_string();
+        let mut wrong_id = good.clone();
+        wrong_id.id += 1;
+        remine(&app, &mut wrong_id);
+
+        for (block, error) in [
+            (wrong_previous, BlockError::WrongPreviousHash),
+            (wrong_difficulty, BlockError::DifficultyMismatch),
+            (wrong_hash, BlockError::WrongHash),
+            (wrong_id, BlockError::WrongId),
+        ] {
+            assert_eq!(app.try_add_bock(block, None), Err(error));
+            assert_eq!(app.len(), 1);
+        }
+        assert_eq!(app.try_add_bock(good, None), Ok(()));
+        assert_eq!(app.len(), 2);
+    }
+
+    fn chain_json(app: &App) -> Vec<serde_json::Value> {
+        app.iter()
+            .map(|b| serde_json::to_value(b).expect("can jsonify block"))
+            .collect()
+    }
+
+    #[test]
+    fn saved_chain_loads_back_the_same() {
+        let dir = tempfile::tempdir().expect("can create a temp dir");
+        let mut app = test_app();
+        app.pending.push(transfer(GENESIS_ACCOUNT, "alice", 10, 1));
+        for data in ["one", "two", "three"] {
+            app.add_mined_block(data.to_string()).expect("mines");
+        }
+
+        let saved = dir.path().join("saved.jsonl");
+        app.save_to_file(&saved).expect("can save");
+        let loaded = App::load_from_file(&saved).expect("can load");
+        assert_eq!(loaded.len(), 4);
+        assert_eq!(chain_json(&loaded), chain_json(&app));
+
+        // appending block by block, as the node does, loads the same chain
+        let appended = dir.path().join("appended.jsonl");
+        for block in app.iter() {
+            block.append_to_file(&appended).expect("can append");
+        }
+        let loaded = App::load_from_file(&appended).expect("can load");
+        assert_eq!(chain_json(&loaded), chain_json(&app));
+    }
+
+    // genesis followed by one retarget window of blocks `interval` seconds apart
+    fn app_with_interval(interval: i64) -> App {
+        let mut app = test_app();
+        app.difficulty = 4;
+        let genesis = app.tip().expect("has genesis").clone();
+        for id in 1..=DIFFICULTY_WINDOW as u64 {
+            let mut block = genesis.clone();
+            block.id = id;
+            block.timestamp = genesis.timestamp + id as i64 * interval;
+            app.blocks.push(block);
+        }
+        app
+    }
+
+    #[test]
+    fn difficulty_follows_block_timing() {
+        assert_eq!(app_with_interval(1).next_difficulty(), 5);
+        assert_eq!(
+            app_with_interval(TARGET_BLOCK_INTERVAL).next_difficulty(),
+            4
+        );
+        assert_eq!(
+            app_with_interval(TARGET_BLOCK_INTERVAL * 10).next_difficulty(),
+            3
+        );
+
+        // a window short of full keeps the base difficulty
+        let mut app = app_with_interval(1);
+        app.blocks.pop();
+        assert_eq!(app.next_difficulty(), 4);
+    }
+
+    #[test]
+    fn retargeted_blocks_validate() {
+        let mut app = test_app();
+        for i in 0..=DIFFICULTY_WINDOW {
+            app.add_mined_block(i.to_string()).expect("mines");
+        }
+        // mined back to back, far faster than the target
+        let tip = app.tip().expect("has blocks");
+        assert_eq!(tip.difficulty, 2);
+        assert_eq!(app.next_difficulty(), 2);
+    }
+
+    #[test]
+    fn transactions_are_part_of_the_hash() {
+        let app = test_app();
+        let block = block_with(&app, vec![transfer(GENESIS_ACCOUNT, "alice", 10, 1)]);
+        let mut other = block.clone();
+        other.transactions[1] = transfer(GENESIS_ACCOUNT, "bob", 10, 1);
+        other.merkle_root = merkle_root(&other.transactions);
+        assert_ne!(
+            Block::calculate_hash(&*app.hasher, &block),
+            Block::calculate_hash(&*app.hasher, &other)
+        );
+    }
+
+    #[test]
+    fn tampered_transactions_invalidate_the_block() {
+        let mut app = test_app();
+        let block = block_with(&app, vec![transfer(GENESIS_ACCOUNT, "alice", 10, 1)]);
+
+        let mut tampered = block.clone();
+        tampered.transactions[1].amount = 1000;
+        assert_eq!(
+            app.try_add_bock(tampered.clone(), None),
+            Err(BlockError::WrongMerkleRoot)
+        );
+        // fixing up the root changes the hash, so the work no longer matches
+        tampered.merkle_root = merkle_root(&tampered.transactions);
+        assert_eq!(app.try_add_bock(tampered, None), Err(BlockError::WrongHash));
+
+        assert_eq!(app.try_add_bock(block, None), Ok(()));
+        assert_eq!(app.balances()["alice"], 10);
+    }
+
+    fn leaf(tx: &Transaction) -> Vec<u8> {
+        Sha256::digest(serde_json::to_string(tx).expect("can jsonify").as_bytes()).to_vec()
+    }
+
+    fn node(left: &[u8], right: &[u8]) -> Vec<u8> {
+        Sha256::digest(&[left, right].concat()).to_vec()
+    }
+
+    #[test]
+    fn merkle_root_pairs_leaves_and_repeats_the_odd_one() {
+        let txs: Vec<_> = (1..=3).map(|n| transfer("a", "b", n, n)).collect();
+        let (a, b, c) = (leaf(&txs[0]), leaf(&txs[1]), leaf(&txs[2]));
+
+        assert_eq!(merkle_root(&[]), hex::encode([0u8; 32]));
+        assert_eq!(merkle_root(&txs[..1]), hex::encode(&a));
+        assert_eq!(merkle_root(&txs[..2]), hex::encode(node(&a, &b)));
+        assert_eq!(
+            merkle_root(&txs),
+            hex::encode(node(&node(&a, &b), &node(&c, &c)))
+        );
+    }
+
+    #[test]
+    fn changing_a_transaction_changes_the_merkle_root() {
+        let mut txs: Vec<_> = (1..=4).map(|n| transfer("a", "b", n, n)).collect();
+        let root = merkle_root(&txs);
+        txs[2].to = "c".to_string();
+        assert_ne!(merkle_root(&txs), root);
+    }
+
+    // genesis and a block mined at each of `timestamps`
+    fn chain_mined_at(timestamps: impl Iterator<Item = i64>) -> Vec<Block> {
+        let mut app = test_app();
+        for (i, timestamp) in timestamps.enumerate() {
+            app.clock = Arc::new(crate::clock::FixedClock(timestamp));
+            app.add_mined_block(i.to_string()).expect("mines");
+        }
+        app.blocks
+    }
+
+    #[test]
+    fn heavier_chain_wins_at_equal_length() {
+        let blocks = DIFFICULTY_WINDOW as i64 + 2;
+        // all at once, so the blocks past the first window are mined at difficulty 2,
+        // and with a later tip so the tie break would pick the other chain
+        let heavy = chain_mined_at((0..blocks).map(|_| GENESIS_TIMESTAMP + 5000));
+        let light = chain_mined_at((0..blocks).map(|i| GENESIS_TIMESTAMP + 1000 + i * 100));
+        assert_eq!(heavy.len(), light.len());
+        assert_eq!(heavy.last().map(|b| b.difficulty), Some(2));
+        assert_eq!(light.last().map(|b| b.difficulty), Some(1));
+
+        let clock = crate::clock::FixedClock(GENESIS_TIMESTAMP + 5000);
+        let mut app = App::new_with_clock(Arc::new(clock));
+        app.difficulty = 1;
+        assert!(app.total_work(&heavy) > app.total_work(&light));
+        let tip = |chain: Option<Vec<Block>>| chain.and_then(|c| c.last().cloned()).map(|b| b.hash);
+        let heavy_tip = heavy.last().map(|b| b.hash.clone());
+        assert_eq!(
+            tip(app.choose_chain(light.clone(), heavy.clone())),
+            heavy_tip
+        );
+        assert_eq!(tip(app.choose_chain(heavy, light)), heavy_tip);
+    }
+
+    #[test]
+    fn two_invalid_chains_choose_none() {
+        let mut app = test_app();
+        app.add_mined_block("one".to_string()).expect("mines");
+        let mut local = app.blocks.clone();
+        local[1].data = "tampered".to_string();
+        let mut remote = app.blocks.clone();
+        remote[1].previous_hash = hex::encode([1u8; 32]);
+        assert!(app.choose_chain(local, remote).is_none());
+        assert_eq!(app.len(), 2);
+    }
+
+    #[test]
+    fn mined_transactions_leave_every_mempool() {
+        let mut miner = test_app();
+        let mut peer = test_app();
+        let mined = transfer(GENESIS_ACCOUNT, "alice", 10, 1);
+        let unseen = transfer(GENESIS_ACCOUNT, "bob", 5, 2);
+        miner.pending.push(mined.clone());
+        peer.pending = vec![mined.clone(), unseen.clone()];
+
+        let block = miner.add_mined_block("txs".to_string()).expect("mines");
+        assert!(block.transactions.contains(&mined));
+        assert!(miner.pending.is_empty());
+        peer.try_add_bock(block, None).expect("is valid");
+        assert_eq!(peer.pending, vec![unseen]);
+    }
+
+    #[test]
+    fn balances_replay_every_transfer() {
+        let mut app = test_app();
+        app.pending = vec![
+            transfer(GENESIS_ACCOUNT, "alice", 100, 1),
+            transfer(GENESIS_ACCOUNT, "bob", 50, 2),
+        ];
+        app.add_mined_block("one".to_string()).expect("mines");
+        app.pending = vec![
+            transfer("alice", "bob", 30, 1),
+            transfer("bob", "carol", 70, 1),
+        ];
+        app.add_mined_block("two".to_string()).expect("mines");
+
+        let balances = app.balances();
+        assert_eq!(balances["alice"], 70);
+        assert_eq!(balances["bob"], 10);
+        assert_eq!(balances["carol"], 70);
+        assert_eq!(balances[GENESIS_ACCOUNT], GENESIS_SUPPLY as i64 - 150);
+        assert_eq!(balances["miner"], 2 * BLOCK_REWARD as i64);
+        let total: i64 = balances.values().sum();
+        assert_eq!(total, (GENESIS_SUPPLY + 2 * BLOCK_REWARD) as i64);
+    }
+
+    #[test]
+    fn overdrafts_are_rejected() {
+        let mut app = test_app();
+        let funded = block_with(&app, vec![transfer(GENESIS_ACCOUNT, "alice", 100, 1)]);
+        assert_eq!(app.try_add_bock(funded, None), Ok(()));
+
+        let overdraft = block_with(&app, vec![transfer("alice", "bob", 101, 1)]);
+        assert_eq!(
+            app.try_add_bock(overdraft, None),
+            Err(BlockError::Overdraft)
+        );
+        let unfunded = block_with(&app, vec![transfer("bob", "alice", 1, 1)]);
+        assert_eq!(app.try_add_bock(unfunded, None), Err(BlockError::Overdraft));
+        assert_eq!(app.len(), 2);
+
+        let spend_all = block_with(&app, vec![transfer("alice", "bob", 100, 1)]);
+        assert_eq!(app.try_add_bock(spend_all, None), Ok(()));
+        assert_eq!(app.len(), 3);
+    }
+
+    #[test]
+    fn parallel_mining_finds_the_single_threaded_nonce() {
+        let app = test_app();
+        let block = block_with(&app, Vec::new());
+        let fields = HashedFields::new(&Sha256Hasher, &block);
+        let cancel = AtomicBool::new(false);
+        let single = Block::mine_block(&fields, 12, u64::MAX, 0, &cancel).expect("mines");
+        for threads in [2, 3, 8] {
+            let parallel = Block::mine_block_parallel(&fields, 12, u64::MAX, threads, 0, &cancel)
+                .expect("mines");
+            assert_eq!(parallel, single);
+        }
+        let (nonce, hash) = single;
+        assert_eq!(hash, hex::encode(fields.hash(nonce)));
+        assert!(Block::validate_hash(&fields.hash(nonce), 12));
+    }
+
+    #[test]
+    fn genesis_is_self_consistent() {
+        let genesis = App::genesis_block();
+        // the hardcoded values verify, so nothing was re-mined
+        assert_eq!(genesis.nonce, 109616);
+        assert_eq!(
+            hex::encode(Block::calculate_hash(&Sha256Hasher, &genesis)),
+            genesis.hash
+        );
+        assert!(genesis.has_valid_hash(&Sha256Hasher, DIFFICULTY_LEVEL));
+        assert_eq!(App::validate_genesis(&genesis), Ok(()));
+        assert_eq!(
+            test_app().is_chain_valid(std::slice::from_ref(&genesis)),
+            Ok(())
+        );
+
+        let mut wrong_nonce = genesis;
+        wrong_nonce.nonce += 1;
+        assert_eq!(
+            App::validate_genesis(&wrong_nonce),
+            Err(BlockError::WrongHash)
+        );
+    }
+
+    #[test]
+    fn chain_check_points_at_the_first_bad_block() {
+        let mut app = test_app();
+        for data in ["one", "two", "three"] {
+            app.add_mined_block(data.to_string()).expect("mines");
+        }
+        assert_eq!(app.is_chain_valid(&app.blocks), Ok(()));
+
+        let mut corrupted = app.blocks.clone();
+        corrupted[2].data = "tampered".to_string();
+        assert_eq!(app.is_chain_valid(&corrupted), Err(2));
+        corrupted[1].nonce += 1;
+        assert_eq!(app.is_chain_valid(&corrupted), Err(1));
+    }
+
+    #[test]
+    fn lower_difficulty_needs_fewer_hashes() {
+        let block = block_with(&test_app(), Vec::new());
+        let fields = HashedFields::new(&Sha256Hasher, &block);
+        let cancel = AtomicBool::new(false);
+        let nonce = |difficulty| {
+            Block::mine_block(&fields, difficulty, u64::MAX, 0, &cancel)
+                .expect("mines")
+                .0
+        };
+        // any hash meeting the higher difficulty meets the lower one too
+        assert!(nonce(1) <= nonce(8));
+        assert!(nonce(8) <= nonce(14));
+
+        // blocks mined at difficulty 1 only verify where 1 is the configured difficulty
+        let mut strict = test_app();
+        strict.difficulty = DIFFICULTY_LEVEL;
+        assert_eq!(
+            strict.try_add_bock(block.clone(), None),
+            Err(BlockError::DifficultyMismatch)
+        );
+        assert_eq!(test_app().try_add_bock(block, None), Ok(()));
+    }
+
+    #[test]
+    fn timestamps_must_follow_the_parent_and_the_clock() {
+        let mut app = test_app();
+        let at = |timestamp| {
+            let mut block = block_with(&app, Vec::new());
+            block.timestamp = timestamp;
+            remine(&app, &mut block);
+            block
+        };
+        let now = app.clock.now();
+        let backdated = at(GENESIS_TIMESTAMP - 1);
+        let future = at(now + MAX_DRIFT + 3600);
+        let normal = at(now);
+        assert_eq!(
+            app.try_add_bock(backdated, None),
+            Err(BlockError::TimestampBeforeParent)
+        );
+        assert_eq!(
+            app.try_add_bock(future, None),
+            Err(BlockError::TimestampInFuture)
+        );
+        assert_eq!(app.try_add_bock(normal, None), Ok(()));
+    }
+
+    #[test]
+    fn export_writes_the_chain_and_keeps_existing_files() {
+        let dir = tempfile::tempdir().expect("can create a temp dir");
+        let path = dir.path().join("export.json");
+        let mut app = test_app();
+        app.add_mined_block("one".to_string()).expect("mines");
+
+        app.export_to_file(&path, false).expect("can export");
+        let exported = App {
+            blocks: App::import_from_file(&path).expect("can read back"),
+            ..App::new()
+        };
+        assert_eq!(chain_json(&exported), chain_json(&app));
+
+        app.add_mined_block("two".to_string()).expect("mines");
+        assert!(app.export_to_file(&path, false).is_err());
+        app.export_to_file(&path, true).expect("can overwrite");
+        assert_eq!(
+            App::import_from_file(&path).expect("can read back").len(),
+            3
+        );
+    }
+
+    #[test]
+    fn malformed_hashes_are_typed_errors() {
+        let mut app = test_app();
+        let mut block = block_with(&app, Vec::new());
+        block.hash = "not hex".to_string();
+        assert!(!block.meets_difficulty());
+
+        let headers = [BlockHeader::from(&App::genesis_block())];
+        let header = BlockHeader::from(&block);
+        assert_eq!(
+            app.validate_header(&header, &headers),
+            Err(BlockError::MalformedHash)
+        );
+        let error = Error::from(BlockError::MalformedHash);
+        assert_eq!(error.to_string(), "invalid block: hash is not valid hex");
+
+        assert_eq!(app.try_add_bock(block, None), Err(BlockError::WrongHash));
+        assert_eq!(app.len(), 1);
+    }
+
+    #[test]
+    fn cached_fields_hash_like_the_whole_block() {
+        let app = test_app();
+        let mut block = block_with(&app, vec![transfer(GENESIS_ACCOUNT, "alice", 10, 1)]);
+        let fields = HashedFields::new(&Sha256Hasher, &block);
+        for nonce in [0, 1, 12345, u64::MAX] {
+            block.nonce = nonce;
+            assert_eq!(
+                fields.hash(nonce),
+                Block::calculate_hash(&Sha256Hasher, &block)
+            );
+        }
+        // the cached prefix is the block's own layout, with the nonce left off
+        assert_eq!(
+            [hashable_prefix(&block), block.nonce.to_be_bytes().to_vec()].concat(),
+            hashable_bytes(&block)
+        );
+    }
+
+    #[test]
+    fn block_data_is_limited() {
+        let mut app = test_app();
+        app.max_block_data = 9;
+        let mine = |app: &App, data: &str| {
+            let task = app.mine_task(data.to_string()).expect("has genesis");
+            task.mine(1, &AtomicBool::new(false)).expect("mines")
+        };
+        // mined by a peer with a higher limit
+        let over = mine(&app, "123456789");
+        app.max_block_data = 8;
+        assert_eq!(
+            app.mine_task("123456789".to_string()).err(),
+            Some(MineError::DataTooLarge(9, 8))
+        );
+        let at_limit = mine(&app, "12345678");
+        // turned away before its hash is recomputed
+        let hasher = std::mem::replace(&mut app.hasher, Arc::new(UnusedHasher));
+        assert_eq!(app.try_add_bock(over, None), Err(BlockError::DataTooLarge));
+        app.hasher = hasher;
+        assert_eq!(app.try_add_bock(at_limit, None), Ok(()));
+    }
+
+    struct UnusedHasher;
+
+    impl Hasher for UnusedHasher {
+        fn hash(&self, _: &[u8]) -> Vec<u8> {
+            panic!("nothing should be hashed")
+        }
+    }
+
+    #[test]
+    fn accessors_read_the_chain() {
+        let empty = App::new();
+        assert_eq!(empty.len(), 0);
+        assert!(empty.is_empty());
+        assert!(empty.tip().is_none());
+        assert!(empty.get(0).is_none());
+        assert_eq!(empty.iter().count(), 0);
+
+        let mut app = test_app();
+        for data in ["one", "two"] {
+            app.add_mined_block(data.to_string()).expect("mines");
+        }
+        assert_eq!(app.len(), 3);
+        assert!(!app.is_empty());
+        assert_eq!(app.tip().map(|b| b.data.as_str()), Some("two"));
+        assert_eq!(app.get(1).map(|b| b.data.as_str()), Some("one"));
+        assert!(app.get(3).is_none());
+        let ids: Vec<u64> = app.iter().map(|b| b.id).collect();
+        assert_eq!(ids, [0, 1, 2]);
+    }
+
+    #[test]
+    fn miner_earns_the_reward_for_each_block() {
+        let mut app = test_app();
+        let blocks = 4;
+        for i in 0..blocks {
+            let block = app.add_mined_block(i.to_string()).expect("mines");
+            assert_eq!(
+                block.transactions[0],
+                Transaction::coinbase("miner".to_string(), block.id)
+            );
+        }
+        assert_eq!(app.balances()["miner"], blocks * BLOCK_REWARD as i64);
+
+        // a miner paying itself more than the reward is rejected
+        let mut greedy = block_with(&app, Vec::new());
+        greedy.transactions[0].amount = BLOCK_REWARD + 1;
+        greedy.merkle_root = merkle_root(&greedy.transactions);
+        remine(&app, &mut greedy);
+        assert_eq!(
+            app.try_add_bock(greedy, None),
+            Err(BlockError::InvalidCoinbase)
+        );
+    }
+
+    #[test]
+    fn hashable_bytes_have_a_fixed_layout() {
+        let mut block = App::genesis_block();
+        block.id = 1;
+        block.timestamp = 2;
+        block.previous_hash = "ab".to_string();
+        block.data = "c".to_string();
+        block.merkle_root = "d".to_string();
+        block.difficulty = 3;
+        block.miner = "m".to_string();
+        block.nonce = 4;
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&1u64.to_be_bytes());
+        expected.extend_from_slice(&2i64.to_be_bytes());
+        for field in ["ab", "c", "d"] {
+            expected.extend_from_slice(&(field.len() as u64).to_be_bytes());
+            expected.extend_from_slice(field.as_bytes());
+        }
+        expected.extend_from_slice(&3u64.to_be_bytes());
+        expected.extend_from_slice(&1u64.to_be_bytes());
+        expected.extend_from_slice(b"m");
+        expected.extend_from_slice(&4u64.to_be_bytes());
+        assert_eq!(hashable_bytes(&block), expected);
+    }
+
+    #[test]
+    fn hash_does_not_depend_on_json_key_order() {
+        let genesis = App::genesis_block();
+        let json = serde_json::to_value(&genesis).expect("can jsonify");
+        let object = json.as_object().expect("is an object");
+        // the same fields, written in reverse order
+        let reversed: Vec<String> = object
+            .iter()
+            .rev()
+            .map(|(key, value)| format!("{:?}:{}", key, value))
+            .collect();
+        let reversed: Block =
+            serde_json::from_str(&format!("{{{}}}", reversed.join(","))).expect("is a block");
+        assert_eq!(hashable_bytes(&reversed), hashable_bytes(&genesis));
+        assert_eq!(
+            hex::encode(Block::calculate_hash(&Sha256Hasher, &reversed)),
+            genesis.hash
+        );
+    }
+
+    // a different hash function in every build, whatever features are on
+    struct ReversedSha256;
+
+    impl Hasher for ReversedSha256 {
+        fn hash(&self, bytes: &[u8]) -> Vec<u8> {
+            let mut hash = Sha256Hasher.hash(bytes);
+            hash.reverse();
+            hash
+        }
+    }
+
+    #[test]
+    fn each_hasher_mines_blocks_only_it_verifies() {
+        let mut hashers: Vec<Arc<dyn Hasher>> = ["sha256", "sha512", "blake3"]
+            .into_iter()
+            .filter_map(crate::hasher::by_name)
+            .collect();
+        hashers.push(Arc::new(ReversedSha256));
+        let chains: Vec<App> = hashers
+            .iter()
+            .map(|hasher| {
+                let mut app = test_app();
+                app.hasher = hasher.clone();
+                for data in ["one", "two"] {
+                    app.add_mined_block(data.to_string()).expect("mines");
+                }
+                app
+            })
+            .collect();
+        for (i, miner) in chains.iter().enumerate() {
+            for (j, validator) in chains.iter().enumerate() {
+                let valid = validator.is_chain_valid(&miner.blocks);
+                // genesis is always SHA-256, the first mined block is where they part
+                assert_eq!(valid, if i == j { Ok(()) } else { Err(1) });
+            }
+        }
+    }
+
+    #[test]
+    fn genesis_previous_hash_is_all_zero_hex() {
+        let genesis = App::genesis_block();
+        let previous = hex::decode(&genesis.previous_hash).expect("is hex");
+        assert_eq!(previous, vec![0u8; 32]);
+        // the genesis is never compared against a previous block
+        assert_eq!(test_app().rules().validate_block(&genesis, &[]), Ok(()));
+    }
+
+    #[test]
+    fn header_chain_checks_linkage_without_bodies() {
+        let mut app = test_app();
+        for data in ["one", "two", "three"] {
+            app.add_mined_block(data.to_string()).expect("mines");
+        }
+        let headers: Vec<BlockHeader> = app.blocks.iter().map(BlockHeader::from).collect();
+        assert_eq!(app.is_header_chain_valid(&headers), Ok(()));
+
+        let mut light = App::new();
+        light.difficulty = 1;
+        for header in headers.iter().cloned() {
+            assert_eq!(light.try_add_header(header), Ok(()));
+        }
+
+        let mut broken = headers.clone();
+        broken[2].previous_hash = broken[0].hash.clone();
+        assert_eq!(app.is_header_chain_valid(&broken), Err(2));
+        let mut light = App::new();
+        light.difficulty = 1;
+        light.headers = headers[..2].to_vec();
+        assert_eq!(
+            light.try_add_header(broken[2].clone()),
+            Err(BlockError::WrongPreviousHash)
+        );
+    }
+
+    thread_local! {
+        static PROGRESS_LINES: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+    }
+
+    // counts the progress lines logged on the current thread, the miner logs on the caller's
+    struct ProgressCounter;
+
+    impl log::Log for ProgressCounter {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            if record.args().to_string().starts_with("mining, nonce") {
+                PROGRESS_LINES.with(|lines| lines.set(lines.get() + 1));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn mined_progress_lines(block: &mut Block, log_every: u64) -> u64 {
+        static LOGGER: ProgressCounter = ProgressCounter;
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Debug);
+        }
+        PROGRESS_LINES.with(|lines| lines.set(0));
+        block
+            .mine(&Sha256Hasher, 1, log_every, &AtomicBool::new(false))
+            .expect("mines");
+        PROGRESS_LINES.with(|lines| lines.get())
+    }
+
+    #[test]
+    fn disabled_progress_reporting_still_mines() {
+        let mut app = test_app();
+        app.clock = Arc::new(crate::clock::FixedClock(GENESIS_TIMESTAMP + 1000));
+        let mut block = block_with(&app, Vec::new());
+        block.difficulty = 10;
+        // every nonce tried after the first is reported
+        let lines = mined_progress_lines(&mut block, 1);
+        assert_eq!(lines, block.nonce);
+        assert!(lines > 0);
+
+        block.nonce = 0;
+        block.hash.clear();
+        assert_eq!(mined_progress_lines(&mut block, 0), 0);
+        assert!(block.has_valid_hash(&Sha256Hasher, 10));
+    }
+
+    #[test]
+    fn v1_block_deserializes_and_migrates() {
+        let v1 = r#"{
+            "id": 1,
+            "hash": "00ab",
+            "previous_hash": "00cd",
+            "timestamp": 1635000100,
+            "data": "old",
+            "nonce": 42
+        }"#;
+        let mut block: Block = serde_json::from_str(v1).expect("old blocks still parse");
+        assert_eq!(block.version, 1);
+        assert_eq!(block.data, "old");
+        assert!(block.transactions.is_empty());
+        assert!(block.merkle_root.is_empty());
+        assert!(block.signature.is_empty());
+        assert!(block.is_supported());
+
+        block.migrate();
+        assert_eq!(block.version, BLOCK_VERSION);
+        assert_eq!(block.merkle_root, merkle_root(&[]));
+
+        block.version = BLOCK_VERSION + 1;
+        assert!(!block.is_supported());
+    }
+
+    #[test]
+    fn fork_choice_runs_on_plain_slices() {
+        let mut app = test_app();
+        app.add_mined_block("shared".to_string()).expect("mines");
+        let short = app.blocks.clone();
+        app.add_mined_block("longer".to_string()).expect("mines");
+        let long = app.blocks.clone();
+        let mut broken = long.clone();
+        broken[2].data = "tampered".to_string();
+
+        let checkpoints = BTreeMap::new();
+        let rules = ChainRules {
+            difficulty: 1,
+            target_block_interval: TARGET_BLOCK_INTERVAL,
+            max_block_data: MAX_BLOCK_DATA_BYTES,
+            hasher: &Sha256Hasher,
+            consensus: &ProofOfWork,
+            now: i64::MAX / 2,
+            snapshot: None,
+            checkpoints: &checkpoints,
+        };
+        assert_eq!(is_chain_valid(&rules, &short), Ok(()));
+        assert_eq!(is_chain_valid(&rules, &long), Ok(()));
+        assert_eq!(is_chain_valid(&rules, &broken), Err(2));
+
+        let hashes = |chain: Option<Vec<Block>>| {
+            chain.map(|c| c.into_iter().map(|b| b.hash).collect::<Vec<_>>())
+        };
+        let long_hashes = hashes(Some(long.clone()));
+        assert_eq!(
+            hashes(choose_chain(&rules, short.clone(), long.clone())),
+            long_hashes
+        );
+        assert_eq!(
+            hashes(choose_chain(&rules, long.clone(), short.clone())),
+            long_hashes
+        );
+        assert_eq!(
+            hashes(choose_chain(&rules, short.clone(), broken.clone())),
+            hashes(Some(short))
+        );
+        assert_eq!(hashes(choose_chain(&rules, broken.clone(), broken)), None);
+    }
+
+    #[test]
+    fn pruned_chain_keeps_its_tip_and_still_validates() {
+        let mut app = test_app();
+        for i in 0..25 {
+            app.add_mined_block(format!("block {}", i)).expect("mines");
+        }
+        let tip = app.tip().expect("has blocks").hash.clone();
+        let balances = app.balances();
+
+        // the cut is moved back to a window boundary, so more than 7 are kept
+        assert_eq!(app.prune(7), 11);
+        assert_eq!(app.blocks.len(), 15);
+        assert_eq!(app.blocks[0].id, 11);
+        assert_eq!(app.snapshot.as_ref().map(|s| s.id), Some(10));
+        assert_eq!(app.tip().map(|b| &b.hash), Some(&tip));
+        assert_eq!(app.balances(), balances);
+        assert_eq!(app.is_chain_valid(&app.blocks), Ok(()));
+
+        app.add_mined_block("after prune".to_string())
+            .expect("mines");
+        assert_eq!(app.tip().map(|b| b.id), Some(26));
+        assert_eq!(app.is_chain_valid(&app.blocks), Ok(()));
+        assert_eq!(app.prune(100), 0);
+    }
+
+    #[test]
+    fn longer_chain_violating_a_checkpoint_is_rejected() {
+        let mut app = test_app();
+        app.add_mined_block("honest".to_string()).expect("mines");
+        let mut fork = test_app();
+        for data in ["fork one", "fork two", "fork three"] {
+            fork.add_mined_block(data.to_string()).expect("mines");
+        }
+        assert_eq!(app.is_chain_valid(&fork.blocks), Ok(()));
+
+        let dir = tempfile::tempdir().expect("can create a temp dir");
+        let path = dir.path().join("checkpoints.json");
+        let config = format!(r#"{{"1": "{}"}}"#, app.blocks[1].hash);
+        std::fs::write(&path, config).expect("can write");
+        app.checkpoints = App::checkpoints_from_file(&path).expect("loads");
+
+        assert_eq!(app.is_chain_valid(&fork.blocks), Err(1));
+        let chosen = app
+            .choose_chain(app.blocks.clone(), fork.blocks.clone())
+            .expect("local is valid");
+        assert_eq!(chosen.len(), 2);
+        assert_eq!(chosen[1].data, "honest");
+    }
+
+    #[test]
+    fn miner_and_difficulty_round_trip_and_are_checked() {
+        let mut app = test_app();
+        app.add_mined_block("mined".to_string()).expect("mines");
+        let block = app.tip().expect("has blocks").clone();
+        assert_eq!(block.miner, "miner");
+        assert_eq!(block.difficulty, 1);
+        let json = serde_json::to_string(&block).expect("serializes");
+        let parsed: Block = serde_json::from_str(&json).expect("parses");
+        assert_eq!(parsed.miner, block.miner);
+        assert_eq!(parsed.difficulty, block.difficulty);
+        assert_eq!(parsed.hash, block.hash);
+
+        let app = test_app();
+        let good = block_with(&app, Vec::new());
+        // the miner is hashed, so claiming someone else's block breaks it
+        let mut stolen = good.clone();
+        stolen.miner = "thief".to_string();
+        assert_eq!(
+            app.rules().validate_block(&stolen, &app.blocks),
+            Err(BlockError::WrongHash)
+        );
+        let mut understated = good;
+        understated.difficulty = 0;
+        remine(&app, &mut understated);
+        assert_eq!(
+            app.rules().validate_block(&understated, &app.blocks),
+            Err(BlockError::DifficultyMismatch)
+        );
+    }
+
+    #[test]
+    fn out_of_range_timestamps_fail_to_parse() {
+        let mut block = serde_json::to_value(App::genesis_block()).expect("jsonifies");
+        let parse = |block: &serde_json::Value| serde_json::from_value::<Block>(block.clone());
+        for timestamp in [0, MAX_TIMESTAMP] {
+            block["timestamp"] = timestamp.into();
+            assert_eq!(parse(&block).expect("is in range").timestamp, timestamp);
+        }
+        for timestamp in [-1, MAX_TIMESTAMP + 1, i64::MIN, i64::MAX] {
+            block["timestamp"] = timestamp.into();
+            let e = parse(&block).expect_err("is out of range");
+            assert!(e.to_string().contains("out of range"), "{}", e);
+        }
+        // floats and overflowing numbers are not timestamps either
+        block["timestamp"] = f64::NAN.into();
+        assert!(parse(&block).is_err());
+        block["timestamp"] = serde_json::json!(1e30);
+        assert!(parse(&block).is_err());
+    }
+
+    #[test]
+    fn stats_total_and_average_a_fixed_chain() {
+        let mut app = test_app();
+        app.pending.push(transfer(GENESIS_ACCOUNT, "alice", 10, 1));
+        for (timestamp, data) in [(100, "a"), (130, "a larger block"), (190, "cc")] {
+            app.clock = Arc::new(crate::clock::FixedClock(GENESIS_TIMESTAMP + timestamp));
+            app.add_mined_block(data.to_string()).expect("mines");
+        }
+        let stats = app.stats();
+        assert_eq!(stats.blocks, 4);
+        // the genesis allocation, a coinbase per mined block and the transfer
+        assert_eq!(stats.transactions, 5);
+        assert_eq!(stats.average_interval, Some(45.0));
+        // every block at difficulty 1 counts 2 hashes of work
+        assert_eq!(stats.total_work, 8);
+        assert_eq!(stats.largest_block, Some((2, 14)));
+
+        let stats = test_app().stats();
+        assert_eq!(stats.blocks, 1);
+        assert_eq!(stats.average_interval, None);
+    }
+
+    #[test]
+    fn chain_grows_headless_through_add_mined_block() {
+        // no genesis yet, the first call lays it
+        let mut app = App::new();
+        app.difficulty = 1;
+        app.miner = "miner".to_string();
+        let mut mined = Vec::new();
+        for data in ["one", "two", "three", "four"] {
+            mined.push(app.add_mined_block(data.to_string()).expect("mines"));
+        }
+        assert_eq!(app.len(), 5);
+        assert_eq!(app.is_chain_valid(&app.blocks), Ok(()));
+        for (block, stored) in mined.iter().zip(&app.blocks[1..]) {
+            assert_eq!(block.hash, stored.hash);
+        }
+        for pair in app.blocks.windows(2) {
+            assert_eq!(pair[1].previous_hash, pair[0].hash);
+            assert_eq!(pair[1].id, pair[0].id + 1);
+        }
+        assert_eq!(app.tip().map(|b| b.data.as_str()), Some("four"));
+
+        // light chains hold no bodies to mine on, and say so instead of panicking
+        let mut light = App::new();
+        light.light = true;
+        assert!(matches!(
+            light.add_mined_block("one".to_string()),
+            Err(Error::Mine(MineError::LightNode))
+        ));
+        assert!(light.is_empty());
+    }
+
+    #[test]
+    fn stored_hash_is_a_claim_checked_against_the_fields() {
+        let mut app = test_app();
+        app.difficulty = 8;
+        let good = block_with(&app, Vec::new());
+        assert_eq!(app.rules().validate_block(&good, &app.blocks), Ok(()));
+
+        // a hash far past the difficulty, but not the one the fields give
+        let mut claimed = good.clone();
+        claimed.hash = hex::encode(hash_with_zero_bits(64));
+        assert_eq!(
+            app.rules().validate_block(&claimed, &app.blocks),
+            Err(BlockError::WrongHash)
+        );
+
+        // the stored hash still meets the difficulty, the nonce no longer does
+        let mut forged = good;
+        forged.nonce = (forged.nonce + 1..)
+            .find(|&nonce| {
+                let mut candidate = forged.clone();
+                candidate.nonce = nonce;
+                !Block::validate_hash(&Block::calculate_hash(&Sha256Hasher, &candidate), 8)
+            })
+            .expect("most nonces miss");
+        assert!(Block::validate_hash(
+            &hex::decode(&forged.hash).expect("is hex"),
+            8
+        ));
+        assert_eq!(
+            app.rules().validate_block(&forged, &app.blocks),
+            Err(BlockError::WrongHash)
+        );
+    }
+
+    #[test]
+    fn genesis_from_config_validates() {
+        let dir = tempfile::tempdir().expect("can create a temp dir");
+        let path = dir.path().join("genesis.json");
+        let config = r#"{"data": "fresh", "allocations": [{"account": "alice", "amount": 5}]}"#;
+        std::fs::write(&path, config).expect("can write");
+        let genesis = App::genesis_from_config(&path).expect("mines");
+        assert_eq!(genesis.data, "fresh");
+        assert_eq!(App::validate_genesis(&genesis), Ok(()));
+        assert_ne!(genesis.hash, App::genesis_block().hash);
+    }
+
+    #[test]
+    fn described_chain_shows_work_per_block() {
+        let mut app = test_app();
+        for data in ["one", "two"] {
+            app.add_mined_block(data.to_string()).expect("mines");
+        }
+        app.blocks[2].data = "tampered".to_string();
+        let description = app.describe_chain();
+        let lines: Vec<&str> = description.lines().collect();
+        assert_eq!(lines.len(), 3);
+        // difficulty 1 adds 2 to the work at every height
+        for (line, work) in lines.iter().zip([2, 4, 6]) {
+            assert!(line.ends_with(&format!(", work {}", work)), "{}", line);
+            assert!(line.contains("(needs 1)"), "{}", line);
+        }
+        assert!(lines[0].starts_with(&format!("block 0 {}: ", app.blocks[0].hash)));
+        assert!(lines[1].contains(", valid,"));
+        assert!(lines[2].contains(&format!("invalid: {}", BlockError::WrongHash)));
+    }
+
+    #[test]
+    fn chain_files_are_checked_for_their_format_version() {
+        let dir = tempfile::tempdir().expect("can create a temp dir");
+        let app = test_app();
+        let path = dir.path().join("chain.jsonl");
+        app.save_to_file(&path).expect("can save");
+        let saved = std::fs::read_to_string(&path).expect("can read");
+        let (header, blocks) = saved.split_once('\n').expect("has a header line");
+        assert_eq!(header, r#"{"format_version":1}"#);
+        assert_eq!(App::load_from_file(&path).expect("can load").len(), 1);
+
+        // files from before the header still load
+        std::fs::write(&path, blocks).expect("can write");
+        assert_eq!(App::load_from_file(&path).expect("can load").len(), 1);
+
+        std::fs::write(&path, format!("{{\"format_version\":2}}\n{}", blocks)).expect("can write");
+        let err = App::load_from_file(&path).err().expect("is refused");
+        assert!(matches!(err, Error::ChainFormat(2)));
+        assert_eq!(
+            err.to_string(),
+            "chain file format 2 is not supported, this node reads up to 1"
+        );
+    }
+
+    #[test]
+    fn hashrate_is_positive_and_leaves_the_chain_alone() {
+        let mut app = test_app();
+        app.add_mined_block("one".to_string()).expect("mines");
+        let before = chain_json(&app);
+
+        let rate = measure_hashrate(&*app.hasher, Duration::from_millis(50));
+        assert!(rate.is_finite() && rate > 0.0, "rate was {}", rate);
+        assert_eq!(chain_json(&app), before);
+        assert!(app.pending.is_empty());
+    }
+
+    #[test]
+    fn whole_chain_checks_agree_with_block_by_block_ones() {
+        let mut app = test_app();
+        // all at once, so every window retargets up a bit
+        app.clock = Arc::new(crate::clock::FixedClock(GENESIS_TIMESTAMP + 5000));
+        for i in 0..DIFFICULTY_WINDOW * 3 {
+            let nonce = app.next_nonce(GENESIS_ACCOUNT);
+            app.pending
+                .push(transfer(GENESIS_ACCOUNT, "alice", 1, nonce));
+            app.add_mined_block(i.to_string()).expect("mines");
+        }
+        assert_eq!(app.tip().map(|b| b.difficulty), Some(3));
+        assert_eq!(app.is_chain_valid(&app.blocks), Ok(()));
+        let rules = app.rules();
+        for (i, block) in app.blocks.iter().enumerate() {
+            assert_eq!(rules.validate_block(block, &app.blocks[..i]), Ok(()));
+        }
+
+        // alice was paid once per block, the running balances hold her to it
+        let spend = |amount| {
+            let nonce = app.next_nonce("alice");
+            let mut chain = app.blocks.clone();
+            chain.push(block_with(
+                &app,
+                vec![transfer("alice", "bob", amount, nonce)],
+            ));
+            chain
+        };
+        let exact = spend(DIFFICULTY_WINDOW as u64 * 3);
+        assert_eq!(app.is_chain_valid(&exact), Ok(()));
+        let over = spend(DIFFICULTY_WINDOW as u64 * 3 + 1);
+        assert_eq!(app.is_chain_valid(&over), Err(over.len() - 1));
+        let (last, before) = over.split_last().expect("has blocks");
+        assert_eq!(
+            rules.validate_block(last, before),
+            Err(BlockError::Overdraft)
+        );
     }
 }
@@ -0,0 +1,121 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::{Multiaddr, PeerId};
+use log::info;
+
+const REDIAL_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_REDIAL_BACKOFF: Duration = Duration::from_secs(60);
+
+// the --bootstrap addresses, redialed with exponential backoff whenever they drop
+pub struct Bootstrap {
+    peers: HashMap<Multiaddr, Redial>,
+}
+
+#[derive(Default)]
+struct Redial {
+    // failed or dropped connections since the last one that was established
+    attempts: u32,
+    due: Option<Instant>,
+    // who answered at the address, its other connections may outlive the one dialed here
+    peer: Option<PeerId>,
+}
+
+impl Bootstrap {
+    pub fn new(addrs: impl IntoIterator<Item = Multiaddr>) -> Self {
+        let peers = addrs
+            .into_iter()
+            .map(|addr| (addr, Redial::default()))
+            .collect();
+        Self { peers }
+    }
+
+    pub fn connected(&mut self, addr: &Multiaddr, peer: PeerId) {
+        if let Some(redial) = self.peers.get_mut(addr) {
+            *redial = Redial {
+                peer: Some(peer),
+                ..Redial::default()
+            };
+        }
+    }
+
+    // the last connection to `peer` closed
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        let addrs: Vec<Multiaddr> = self
+            .peers
+            .iter()
+            .filter(|(_, redial)| redial.peer.as_ref() == Some(peer))
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        addrs.iter().for_each(|addr| self.dropped(addr));
+    }
+
+    // schedules a redial unless one is already pending
+    pub fn dropped(&mut self, addr: &Multiaddr) {
+        let Some(redial) = self.peers.get_mut(addr) else {
+            return;
+        };
+        if redial.due.is_some() {
+            return;
+        }
+        let delay = REDIAL_BACKOFF
+            .saturating_mul(2u32.saturating_pow(redial.attempts))
+            .min(MAX_REDIAL_BACKOFF);
+        redial.attempts += 1;
+        redial.peer = None;
+        redial.due = Some(Instant::now() + delay);
+        info!(
+            "lost bootstrap peer {}, redialing in {:?} (attempt {})",
+            addr, delay, redial.attempts
+        );
+    }
+
+    // addresses whose redial is due, each is returned once per `dropped`
+    pub fn due(&mut self) -> Vec<Multiaddr> {
+        let now = Instant::now();
+        self.peers
+            .iter_mut()
+            .filter(|(_, redial)| redial.due.is_some_and(|due| due <= now))
+            .map(|(addr, redial)| {
+                redial.due = None;
+                addr.clone()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delay(bootstrap: &Bootstrap, addr: &Multiaddr) -> Duration {
+        let due = bootstrap.peers[addr].due.expect("a redial is pending");
+        due.saturating_duration_since(Instant::now())
+    }
+
+    #[test]
+    fn redials_back_off_up_to_the_cap() {
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().expect("is valid");
+        let mut bootstrap = Bootstrap::new([addr.clone()]);
+        let mut expected = REDIAL_BACKOFF;
+        for _ in 0..10 {
+            bootstrap.dropped(&addr);
+            // a second drop while one is pending changes nothing
+            bootstrap.dropped(&addr);
+            let delay = delay(&bootstrap, &addr);
+            assert!(delay <= expected && delay + Duration::from_secs(1) > expected);
+            bootstrap.peers.get_mut(&addr).expect("is tracked").due = Some(Instant::now());
+            assert_eq!(bootstrap.due(), std::slice::from_ref(&addr));
+            assert!(bootstrap.due().is_empty());
+            expected = (expected * 2).min(MAX_REDIAL_BACKOFF);
+        }
+        assert_eq!(expected, MAX_REDIAL_BACKOFF);
+
+        // a connection that comes up starts the backoff over
+        bootstrap.connected(&addr, PeerId::random());
+        bootstrap.dropped(&addr);
+        assert!(delay(&bootstrap, &addr) <= REDIAL_BACKOFF);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    num::{NonZeroU32, NonZeroUsize},
+    path::PathBuf,
+};
+
+use clap::{builder::RangedU64ValueParser, Parser, ValueEnum};
+use libp2p::{Multiaddr, PeerId};
+
+use crate::{
+    blockchain::{DIFFICULTY_LEVEL, MAX_BLOCK_DATA_BYTES, MAX_DIFFICULTY, MINE_LOG_EVERY},
+    p2p::{
+        CHANNEL_CAPACITY, MAX_PEERS, MAX_PING_FAILURES, MAX_RESPONSE_BLOCKS, MAX_SYNC_ATTEMPTS,
+        MIN_BLOCK_INTERVAL_SECS, STALE_AFTER_SECS, SYNC_TIMEOUT_SECS,
+    },
+};
+
+#[derive(Parser, Debug)]
+#[command(about = "A peer-to-peer demo blockchain node")]
+pub struct Args {
+    /// Multiaddr the node listens on
+    #[arg(long, default_value = "/ip4/0.0.0.0/tcp/0")]
+    pub listen_addr: Multiaddr,
+
+    /// Multiaddr of a peer to dial on startup, may be repeated
+    #[arg(long)]
+    pub bootstrap: Vec<Multiaddr>,
+
+    /// Leading zero bits required of block hashes before retargeting, 1 to 256
+    #[arg(
+        long,
+        default_value_t = DIFFICULTY_LEVEL,
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..=MAX_DIFFICULTY as u64)
+    )]
+    pub difficulty: usize,
+
+    /// Largest block data payload accepted, in bytes; larger blocks would not fit in one
+    /// gossip message
+    #[arg(
+        long,
+        default_value_t = MAX_BLOCK_DATA_BYTES,
+        value_parser = RangedU64ValueParser::<usize>::new().range(0..=MAX_BLOCK_DATA_BYTES as u64)
+    )]
+    pub max_block_data: usize,
+
+    /// Nonces between mining progress lines at debug level, 0 disables them
+    #[arg(long, default_value_t = MINE_LOG_EVERY)]
+    pub mine_log_every: u64,
+
+    /// Seconds to wait between blocks created from the command line
+    #[arg(long, default_value_t = MIN_BLOCK_INTERVAL_SECS)]
+    pub min_block_interval: u64,
+
+    /// Stream multiplexer used on connections
+    #[arg(long, value_enum, default_value_t = Muxer::Mplex)]
+    pub muxer: Muxer,
+
+    /// Consecutive failed pings after which a peer is dropped
+    #[arg(long, default_value_t = NonZeroU32::new(MAX_PING_FAILURES).expect("is not zero"))]
+    pub max_ping_failures: NonZeroU32,
+
+    /// Most peers to gossip with directly; past it the one heard from least recently
+    /// is dropped
+    #[arg(long, default_value_t = NonZeroUsize::new(MAX_PEERS).expect("is not zero"))]
+    pub max_peers: NonZeroUsize,
+
+    /// Do not discover peers with mDNS, only --bootstrap and `add peer` connect
+    #[arg(long)]
+    pub no_mdns: bool,
+
+    /// Seconds after which `since` warns that the tip is stale
+    #[arg(long, default_value_t = STALE_AFTER_SECS)]
+    pub stale_after: u64,
+
+    /// Seconds to wait for a chain response before asking another peer
+    #[arg(long, default_value_t = SYNC_TIMEOUT_SECS)]
+    pub sync_timeout: u64,
+
+    /// Chain requests sent before a sync is given up
+    #[arg(long, default_value_t = NonZeroU32::new(MAX_SYNC_ATTEMPTS).expect("is not zero"))]
+    pub max_sync_attempts: NonZeroU32,
+
+    /// Most blocks sent in one chain or range response; a chain response carries only the
+    /// newest blocks, full history is fetched with range requests
+    #[arg(long, default_value_t = NonZeroUsize::new(MAX_RESPONSE_BLOCKS).expect("is not zero"))]
+    pub max_response_blocks: NonZeroUsize,
+
+    /// Events each channel into the main loop buffers; chain responses past it are dropped,
+    /// mined blocks and http mine requests wait for room
+    #[arg(long, default_value_t = NonZeroUsize::new(CHANNEL_CAPACITY).expect("is not zero"))]
+    pub channel_capacity: NonZeroUsize,
+
+    /// Blocks to mine with placeholder data after startup, for demos and load tests
+    #[arg(long, default_value_t = 0)]
+    pub mine_on_start: u64,
+
+    /// Hash function for blocks: sha256, or sha512/blake3 when built with those features
+    #[arg(long, default_value = "sha256")]
+    pub hasher: String,
+
+    /// Name prefixed to the gossip topics, only nodes sharing it exchange data
+    #[arg(long)]
+    pub network: Option<String>,
+
+    /// Also keep a chain for this network, in a subdirectory of the data dir named
+    /// after it; commands reach it as `@<network> <command>`, may be repeated
+    #[arg(long)]
+    pub host_network: Vec<String>,
+
+    /// Directory holding the node's state files, created if missing
+    #[arg(long, default_value = "data")]
+    pub data_dir: PathBuf,
+
+    /// JSON file describing the genesis block, relative to the data dir;
+    /// the built-in genesis is used otherwise
+    #[arg(long)]
+    pub genesis: Option<PathBuf>,
+
+    /// Mine the genesis block from --genesis, or the built-in one, print it as JSON
+    /// and exit without starting the node
+    #[arg(long)]
+    pub gen_genesis: bool,
+
+    /// JSON file mapping block ids to the hashes they must have, relative to
+    /// the data dir; chains that disagree are rejected whatever their work
+    #[arg(long)]
+    pub checkpoints: Option<PathBuf>,
+
+    /// Cut a chain file that fails to replay at startup back to its valid prefix
+    /// instead of refusing to start
+    #[arg(long)]
+    pub truncate_invalid: bool,
+
+    /// How blocks are sealed: pow mines them, poa has an authority sign them
+    #[arg(long, value_enum, default_value_t = ConsensusKind::Pow)]
+    pub consensus: ConsensusKind,
+
+    /// Peer id allowed to seal blocks under --consensus poa, may be repeated
+    #[arg(long)]
+    pub authority: Vec<PeerId>,
+
+    /// Keep only block headers and never mine, for observers
+    #[arg(long)]
+    pub light: bool,
+
+    /// For tests and demos only: derive the node key from this seed instead of
+    /// generating one, so the peer id is the same on every run. Anyone who can
+    /// guess the seed has the key
+    #[arg(long)]
+    pub key_seed: Option<u64>,
+
+    /// File of commands to run once the node is initialised, one per line as
+    /// typed on stdin; lines starting with # are skipped
+    #[arg(long)]
+    pub script: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsensusKind {
+    Pow,
+    Poa,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Muxer {
+    Mplex,
+    Yamux,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::harness::TestNode;
+
+    #[test]
+    fn difficulty_is_between_1_and_256() {
+        let parse = |difficulty: &str| Args::try_parse_from(["node", "--difficulty", difficulty]);
+        assert!(parse("0").is_err());
+        assert!(parse("257").is_err());
+        assert_eq!(parse("1").expect("in range").difficulty, 1);
+        assert_eq!(parse("256").expect("in range").difficulty, 256);
+    }
+
+    #[tokio::test]
+    async fn explicit_listen_addr_is_listened_on() {
+        let args = Args::try_parse_from(["node", "--listen-addr", "/ip4/127.0.0.1/tcp/40000"])
+            .expect("is a multiaddr");
+        let listen = args.listen_addr.to_string();
+        let node = TestNode::with_options(None, false, &listen).await;
+        assert_eq!(node.listen_addrs, [args.listen_addr]);
+    }
+
+    #[test]
+    fn malformed_listen_addr_is_a_usage_error() {
+        let e = Args::try_parse_from(["node", "--listen-addr", "127.0.0.1:40000"])
+            .expect_err("is not a multiaddr");
+        assert_eq!(e.kind(), clap::error::ErrorKind::ValueValidation);
+        assert_eq!(e.exit_code(), 2);
+        assert!(e
+            .to_string()
+            .contains("invalid value '127.0.0.1:40000' for '--listen-addr <LISTEN_ADDR>'"));
+    }
+}
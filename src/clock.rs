@@ -0,0 +1,27 @@
+use chrono::Utc;
+
+// where block timestamps and the future-drift check get the current time from
+pub trait Clock: Send + Sync {
+    // seconds since the unix epoch
+    fn now(&self) -> i64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        Utc::now().timestamp()
+    }
+}
+
+// always the same time, so the same inputs mine the same blocks; it also freezes the
+// future-drift check, so it is only for tests
+#[cfg(test)]
+pub struct FixedClock(pub i64);
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> i64 {
+        self.0
+    }
+}
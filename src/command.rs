@@ -0,0 +1,380 @@
+use std::fmt;
+
+use crate::blockchain::MAX_AMOUNT;
+
+const LS_USAGE: &str = "ls p | ls c [verbose]";
+const CREATE_USAGE: &str = "create b <data>";
+const MINE_USAGE: &str = "mine";
+const TX_USAGE: &str = "tx <from> <to> <amount>";
+const BALANCE_USAGE: &str = "balance <account>";
+const STATUS_USAGE: &str = "status";
+const STATS_USAGE: &str = "stats";
+const SINCE_USAGE: &str = "since";
+const HASHRATE_USAGE: &str = "hashrate";
+const VALIDATE_USAGE: &str = "validate";
+const WATCH_USAGE: &str = "watch peers on|off";
+const PEERS_USAGE: &str = "peers detail";
+const BLOCK_USAGE: &str = "block <id>";
+const EXPORT_USAGE: &str = "export <path> [--force]";
+const IMPORT_USAGE: &str = "import <path>";
+const CHECK_USAGE: &str = "check <path>";
+const RESYNC_USAGE: &str = "resync";
+const BROADCAST_USAGE: &str = "broadcast";
+const PRUNE_USAGE: &str = "prune <n>";
+const DISCONNECT_USAGE: &str = "disconnect <peer_id>";
+const ADD_PEER_USAGE: &str = "add peer <multiaddr>";
+const ADDRS_USAGE: &str = "addrs";
+const HELP_USAGE: &str = "help";
+const HOSTED_USAGE: &str = "@<network> <command>";
+
+// (syntax, description) for every command, printed by `help`
+const COMMANDS: &[(&str, &str)] = &[
+    ("ls p", "list discovered peers"),
+    ("ls c", "print the local chain as JSON"),
+    (
+        "ls c verbose",
+        "list each block with its hash's zero bits, validity and cumulative work",
+    ),
+    (BLOCK_USAGE, "print one block as JSON"),
+    (PEERS_USAGE, "list discovered peers with their addresses"),
+    (ADDRS_USAGE, "list the addresses this node listens on"),
+    (
+        CREATE_USAGE,
+        "mine a block with data and the pending transactions",
+    ),
+    (MINE_USAGE, "mine a block with the pending transactions"),
+    (TX_USAGE, "queue a transaction for the next block"),
+    (BALANCE_USAGE, "print the balance of an account"),
+    (STATUS_USAGE, "print height, tip, difficulty and peer count"),
+    (
+        STATS_USAGE,
+        "print block and transaction totals, block interval and work",
+    ),
+    (SINCE_USAGE, "print how long ago the tip was mined"),
+    (
+        HASHRATE_USAGE,
+        "measure how fast this node hashes, without mining",
+    ),
+    (VALIDATE_USAGE, "check the whole local chain"),
+    (EXPORT_USAGE, "write the chain to a JSON file"),
+    (
+        IMPORT_USAGE,
+        "adopt a chain from a JSON file if it is heavier",
+    ),
+    (
+        CHECK_USAGE,
+        "validate a chain JSON file without importing it",
+    ),
+    (
+        RESYNC_USAGE,
+        "reset to genesis and fetch the chain from a peer",
+    ),
+    (BROADCAST_USAGE, "push the local chain to all peers"),
+    (PRUNE_USAGE, "drop all but about the last n blocks"),
+    (
+        DISCONNECT_USAGE,
+        "drop a peer from gossip and close its connection",
+    ),
+    (
+        ADD_PEER_USAGE,
+        "dial a peer and gossip with it, mdns expiry does not drop it",
+    ),
+    (WATCH_USAGE, "toggle peer join/leave notifications"),
+    (
+        HOSTED_USAGE,
+        "run ls c, block, create b, mine, status or validate on a hosted network",
+    ),
+    (HELP_USAGE, "show this list"),
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    ListPeers,
+    ListChain,
+    DescribeChain,
+    PeerDetails,
+    ListenAddrs,
+    ShowBlock(u64),
+    CreateBlock(String),
+    Mine,
+    Transaction {
+        from: String,
+        to: String,
+        amount: u64,
+    },
+    Balance(String),
+    Status,
+    Stats,
+    Since,
+    Hashrate,
+    Validate,
+    WatchPeers(bool),
+    Export {
+        path: String,
+        force: bool,
+    },
+    Import(String),
+    Check(String),
+    Resync,
+    Broadcast,
+    Prune(usize),
+    Disconnect(String),
+    AddPeer(String),
+    Help,
+    // a command for the chain of a `--host-network`
+    OnNetwork {
+        network: String,
+        command: Box<Command>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    Unknown(String),
+    Usage(&'static str),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "empty command"),
+            ParseError::Unknown(cmd) => {
+                write!(f, "unknown command: {}, run `help` to list commands", cmd)
+            }
+            ParseError::Usage(usage) => write!(f, "usage: {}", usage),
+        }
+    }
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, ParseError> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.as_slice() {
+            [] => Err(ParseError::Empty),
+            [network, rest @ ..] if network.starts_with('@') => {
+                match (&network[1..], Command::parse(&rest.join(" "))) {
+                    ("", _) | (_, Ok(Command::OnNetwork { .. })) | (_, Err(ParseError::Empty)) => {
+                        Err(ParseError::Usage(HOSTED_USAGE))
+                    }
+                    (network, command) => Ok(Command::OnNetwork {
+                        network: network.to_string(),
+                        command: Box::new(command?),
+                    }),
+                }
+            }
+            ["ls", "p"] => Ok(Command::ListPeers),
+            ["ls", "c"] => Ok(Command::ListChain),
+            ["ls", "c", "verbose"] => Ok(Command::DescribeChain),
+            ["ls", ..] => Err(ParseError::Usage(LS_USAGE)),
+            ["peers", "detail"] => Ok(Command::PeerDetails),
+            ["peers", ..] => Err(ParseError::Usage(PEERS_USAGE)),
+            ["addrs"] => Ok(Command::ListenAddrs),
+            ["addrs", ..] => Err(ParseError::Usage(ADDRS_USAGE)),
+            ["block", id] => id
+                .parse()
+                .map(Command::ShowBlock)
+                .map_err(|_| ParseError::Usage(BLOCK_USAGE)),
+            ["block", ..] => Err(ParseError::Usage(BLOCK_USAGE)),
+            ["create", "b", data @ ..] => Ok(Command::CreateBlock(data.join(" "))),
+            ["create", ..] => Err(ParseError::Usage(CREATE_USAGE)),
+            ["mine"] => Ok(Command::Mine),
+            ["mine", ..] => Err(ParseError::Usage(MINE_USAGE)),
+            ["tx", from, to, amount] => match amount.parse() {
+                Ok(amount) if amount <= MAX_AMOUNT => Ok(Command::Transaction {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                    amount,
+                }),
+                _ => Err(ParseError::Usage(TX_USAGE)),
+            },
+            ["tx", ..] => Err(ParseError::Usage(TX_USAGE)),
+            ["balance", account] => Ok(Command::Balance(account.to_string())),
+            ["balance", ..] => Err(ParseError::Usage(BALANCE_USAGE)),
+            ["status"] => Ok(Command::Status),
+            ["status", ..] => Err(ParseError::Usage(STATUS_USAGE)),
+            ["stats"] => Ok(Command::Stats),
+            ["stats", ..] => Err(ParseError::Usage(STATS_USAGE)),
+            ["since"] => Ok(Command::Since),
+            ["since", ..] => Err(ParseError::Usage(SINCE_USAGE)),
+            ["hashrate"] => Ok(Command::Hashrate),
+            ["hashrate", ..] => Err(ParseError::Usage(HASHRATE_USAGE)),
+            ["validate"] => Ok(Command::Validate),
+            ["validate", ..] => Err(ParseError::Usage(VALIDATE_USAGE)),
+            ["watch", "peers", "on"] => Ok(Command::WatchPeers(true)),
+            ["watch", "peers", "off"] => Ok(Command::WatchPeers(false)),
+            ["watch", ..] => Err(ParseError::Usage(WATCH_USAGE)),
+            ["export", path] => Ok(Command::Export {
+                path: path.to_string(),
+                force: false,
+            }),
+            ["export", path, "--force"] => Ok(Command::Export {
+                path: path.to_string(),
+                force: true,
+            }),
+            ["export", ..] => Err(ParseError::Usage(EXPORT_USAGE)),
+            ["import", path] => Ok(Command::Import(path.to_string())),
+            ["import", ..] => Err(ParseError::Usage(IMPORT_USAGE)),
+            ["check", path] => Ok(Command::Check(path.to_string())),
+            ["check", ..] => Err(ParseError::Usage(CHECK_USAGE)),
+            ["resync"] => Ok(Command::Resync),
+            ["resync", ..] => Err(ParseError::Usage(RESYNC_USAGE)),
+            ["broadcast"] => Ok(Command::Broadcast),
+            ["broadcast", ..] => Err(ParseError::Usage(BROADCAST_USAGE)),
+            ["prune", n] => match n.parse() {
+                Ok(n) if n > 0 => Ok(Command::Prune(n)),
+                _ => Err(ParseError::Usage(PRUNE_USAGE)),
+            },
+            ["prune", ..] => Err(ParseError::Usage(PRUNE_USAGE)),
+            ["disconnect", peer] => Ok(Command::Disconnect(peer.to_string())),
+            ["disconnect", ..] => Err(ParseError::Usage(DISCONNECT_USAGE)),
+            ["add", "peer", addr] => Ok(Command::AddPeer(addr.to_string())),
+            ["add", ..] => Err(ParseError::Usage(ADD_PEER_USAGE)),
+            ["help"] => Ok(Command::Help),
+            ["help", ..] => Err(ParseError::Usage(HELP_USAGE)),
+            [cmd, ..] => Err(ParseError::Unknown(cmd.to_string())),
+        }
+    }
+
+    // whether running it starts a mine
+    pub fn mines(&self) -> bool {
+        match self {
+            Command::CreateBlock(_) | Command::Mine => true,
+            Command::OnNetwork { command, .. } => command.mines(),
+            _ => false,
+        }
+    }
+}
+
+pub fn help() -> String {
+    let width = COMMANDS
+        .iter()
+        .map(|(usage, _)| usage.len())
+        .max()
+        .unwrap_or(0);
+    COMMANDS
+        .iter()
+        .map(|(usage, description)| format!("{:width$}  {}", usage, description, width = width))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_amounts_past_i64() {
+        let line = format!("tx alice bob {}", MAX_AMOUNT + 1);
+        assert_eq!(Command::parse(&line), Err(ParseError::Usage(TX_USAGE)));
+        let line = format!("tx alice bob {}", MAX_AMOUNT);
+        assert!(matches!(
+            Command::parse(&line),
+            Ok(Command::Transaction {
+                amount: MAX_AMOUNT,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn parses_each_command_form() {
+        assert_eq!(Command::parse("ls p"), Ok(Command::ListPeers));
+        assert_eq!(Command::parse("  ls   c "), Ok(Command::ListChain));
+        assert_eq!(
+            Command::parse("create b hello  world"),
+            Ok(Command::CreateBlock("hello world".to_string()))
+        );
+        assert_eq!(
+            Command::parse("create b"),
+            Ok(Command::CreateBlock(String::new()))
+        );
+        assert_eq!(Command::parse("mine"), Ok(Command::Mine));
+        assert_eq!(
+            Command::parse("tx alice bob 10"),
+            Ok(Command::Transaction {
+                from: "alice".to_string(),
+                to: "bob".to_string(),
+                amount: 10,
+            })
+        );
+        assert_eq!(
+            Command::parse("balance alice"),
+            Ok(Command::Balance("alice".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        assert_eq!(Command::parse(""), Err(ParseError::Empty));
+        assert_eq!(Command::parse("   "), Err(ParseError::Empty));
+        assert_eq!(
+            Command::parse("create black"),
+            Err(ParseError::Usage(CREATE_USAGE))
+        );
+        assert_eq!(Command::parse("ls x"), Err(ParseError::Usage(LS_USAGE)));
+        assert_eq!(Command::parse("ls"), Err(ParseError::Usage(LS_USAGE)));
+        assert_eq!(
+            Command::parse("mine now"),
+            Err(ParseError::Usage(MINE_USAGE))
+        );
+        assert_eq!(
+            Command::parse("tx alice bob"),
+            Err(ParseError::Usage(TX_USAGE))
+        );
+        assert_eq!(
+            Command::parse("tx alice bob -5"),
+            Err(ParseError::Usage(TX_USAGE))
+        );
+        assert_eq!(
+            Command::parse("balance"),
+            Err(ParseError::Usage(BALANCE_USAGE))
+        );
+        assert_eq!(
+            Command::parse("frobnicate"),
+            Err(ParseError::Unknown("frobnicate".to_string()))
+        );
+    }
+
+    #[test]
+    fn help_lists_every_command() {
+        let help = help();
+        for keyword in [
+            "ls",
+            "block",
+            "peers",
+            "addrs",
+            "create",
+            "mine",
+            "tx",
+            "balance",
+            "status",
+            "stats",
+            "since",
+            "hashrate",
+            "validate",
+            "export",
+            "import",
+            "check",
+            "resync",
+            "broadcast",
+            "prune",
+            "disconnect",
+            "add",
+            "watch",
+            "@",
+            "help",
+        ] {
+            assert!(
+                help.lines().any(|line| line.starts_with(keyword)),
+                "help is missing {}",
+                keyword
+            );
+            // and each listed keyword is one `parse` knows
+            let known = !matches!(Command::parse(keyword), Err(ParseError::Unknown(_)));
+            assert!(known, "{} is not a command", keyword);
+        }
+        let unknown = ParseError::Unknown("frobnicate".to_string()).to_string();
+        assert!(unknown.contains("help"));
+    }
+}
@@ -0,0 +1,57 @@
+use std::{
+    borrow::Cow,
+    io::{self, Read, Write},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+// gzip streams open with these bytes, JSON never does
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+// far beyond any real chain response, only a crafted message inflates this much
+const MAX_INFLATED_BYTES: u64 = 64 * 1024 * 1024;
+
+pub fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+// plain messages, as sent by nodes built without compression, pass through as they are
+pub fn decompress(data: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    if !data.starts_with(&GZIP_MAGIC) {
+        return Ok(Cow::Borrowed(data));
+    }
+    let mut inflated = Vec::new();
+    GzDecoder::new(data)
+        .take(MAX_INFLATED_BYTES + 1)
+        .read_to_end(&mut inflated)?;
+    if inflated.len() as u64 > MAX_INFLATED_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message inflates past the size limit",
+        ));
+    }
+    Ok(Cow::Owned(inflated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_data_inflates_back_and_plain_data_passes() {
+        let json = br#"{"blocks":[]}"#.repeat(1000);
+        let compressed = compress(&json).expect("compresses");
+        assert!(compressed.starts_with(&GZIP_MAGIC));
+        assert!(compressed.len() < json.len() / 10);
+        assert_eq!(decompress(&compressed).expect("inflates"), &json[..]);
+        assert!(matches!(decompress(&json), Ok(Cow::Borrowed(_))));
+    }
+
+    #[test]
+    fn bombs_are_refused() {
+        let bomb = compress(&vec![0; MAX_INFLATED_BYTES as usize + 1]).expect("compresses");
+        let e = decompress(&bomb).expect_err("is too big");
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,178 @@
+use std::{collections::HashSet, sync::atomic::AtomicBool};
+
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
+
+use crate::{
+    blockchain::{Block, MineError},
+    hasher::Hasher,
+};
+
+// how a block whose other fields are set gets its nonce, hash and signature, and what
+// makes those acceptable; the chain rules check everything else, genesis is always mined
+pub trait Consensus: Send + Sync {
+    fn seal(
+        &self,
+        block: &mut Block,
+        hasher: &dyn Hasher,
+        threads: usize,
+        log_every: u64,
+        cancel: &AtomicBool,
+    ) -> Result<(), MineError>;
+
+    // the hash of `block` is already known to match its fields
+    fn verify(&self, block: &Block) -> bool;
+
+    // whether `seal` can succeed here at all, checked before any work starts
+    fn can_seal(&self) -> bool {
+        true
+    }
+}
+
+// the hash meets the block's difficulty, found by trying nonces
+pub struct ProofOfWork;
+
+impl Consensus for ProofOfWork {
+    fn seal(
+        &self,
+        block: &mut Block,
+        hasher: &dyn Hasher,
+        threads: usize,
+        log_every: u64,
+        cancel: &AtomicBool,
+    ) -> Result<(), MineError> {
+        block.mine(hasher, threads, log_every, cancel)
+    }
+
+    fn verify(&self, block: &Block) -> bool {
+        block.meets_difficulty()
+    }
+}
+
+// blocks are signed by their miner instead of mined, and only a fixed set of peers
+// may be it
+pub struct ProofOfAuthority {
+    keys: Keypair,
+    authorities: HashSet<PeerId>,
+}
+
+impl ProofOfAuthority {
+    // `keys` seal this node's blocks, they only work if their peer is an authority
+    pub fn new(keys: Keypair, authorities: impl IntoIterator<Item = PeerId>) -> Self {
+        Self {
+            keys,
+            authorities: authorities.into_iter().collect(),
+        }
+    }
+}
+
+impl Consensus for ProofOfAuthority {
+    fn seal(
+        &self,
+        block: &mut Block,
+        hasher: &dyn Hasher,
+        _threads: usize,
+        _log_every: u64,
+        _cancel: &AtomicBool,
+    ) -> Result<(), MineError> {
+        if !self.can_seal() {
+            return Err(MineError::NotAuthority);
+        }
+        block.nonce = 0;
+        let hash = Block::calculate_hash(hasher, block);
+        let signature = self.keys.sign(&hash).map_err(|_| MineError::Signing)?;
+        block.hash = hex::encode(hash);
+        block.signature = hex::encode(signature);
+        Ok(())
+    }
+
+    fn verify(&self, block: &Block) -> bool {
+        let Ok(signer) = block.miner.parse::<PeerId>() else {
+            return false;
+        };
+        let (Ok(hash), Ok(signature)) = (hex::decode(&block.hash), hex::decode(&block.signature))
+        else {
+            return false;
+        };
+        self.authorities.contains(&signer)
+            && public_key(&signer).is_some_and(|key| key.verify(&hash, &signature))
+    }
+
+    fn can_seal(&self) -> bool {
+        self.authorities
+            .contains(&self.keys.public().into_peer_id())
+    }
+}
+
+// ed25519 peer ids inline their public key behind the identity multihash code
+fn public_key(peer: &PeerId) -> Option<PublicKey> {
+    let multihash = peer.as_ref();
+    if multihash.code() != 0 {
+        return None;
+    }
+    PublicKey::from_protobuf_encoding(multihash.digest()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use libp2p::identity;
+
+    use super::*;
+    use crate::blockchain::{App, BlockError};
+
+    // a chain sealed by `keys` under proof of authority with `authorities`
+    fn poa_app(keys: &Keypair, authorities: &[PeerId]) -> App {
+        let mut app = App::new();
+        app.difficulty = 1;
+        app.miner = keys.public().into_peer_id().to_string();
+        app.consensus = Arc::new(ProofOfAuthority::new(
+            keys.clone(),
+            authorities.iter().copied(),
+        ));
+        app.genesis();
+        app
+    }
+
+    #[test]
+    fn authority_blocks_validate_and_others_are_rejected() {
+        let authority = identity::Keypair::generate_ed25519();
+        let authority_id = authority.public().into_peer_id();
+        let mut app = poa_app(&authority, &[authority_id]);
+        let block = app.add_mined_block("signed".to_string()).expect("seals");
+        assert_eq!(block.nonce, 0);
+        assert!(!block.signature.is_empty());
+        assert_eq!(app.is_chain_valid(&app.blocks), Ok(()));
+
+        // a key sealing for itself, which the authority's chain does not accept
+        let rogue = identity::Keypair::generate_ed25519();
+        let rogue_id = rogue.public().into_peer_id();
+        let mut rogue_app = poa_app(&rogue, &[rogue_id]);
+        rogue_app.blocks = app.blocks.clone();
+        let forged = rogue_app
+            .add_mined_block("forged".to_string())
+            .expect("seals");
+        assert_eq!(
+            app.try_add_bock(forged.clone(), None),
+            Err(BlockError::InvalidSeal)
+        );
+
+        // nor may it claim the authority as miner without its key
+        let mut impostor = forged;
+        impostor.miner = authority_id.to_string();
+        impostor.hash = hex::encode(Block::calculate_hash(&*app.hasher, &impostor));
+        assert_eq!(
+            app.try_add_bock(impostor, None),
+            Err(BlockError::InvalidSeal)
+        );
+
+        assert_eq!(app.len(), 2);
+
+        // and a key outside the authorities can't seal at all
+        let outsider = poa_app(&rogue, &[authority_id]);
+        assert!(!outsider.consensus.can_seal());
+    }
+}
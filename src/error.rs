@@ -0,0 +1,32 @@
+use std::io;
+
+use libp2p::gossipsub::error::PublishError;
+use thiserror::Error;
+
+use crate::blockchain::{BlockError, MineError};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("serialization error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid block: {0}")]
+    Block(#[from] BlockError),
+    #[error("mining failed: {0}")]
+    Mine(#[from] MineError),
+    #[error(
+        "chain file format {0} is not supported, this node reads up to {}",
+        crate::blockchain::CHAIN_FORMAT_VERSION
+    )]
+    ChainFormat(u32),
+    #[error("network error: {0}")]
+    Network(String),
+}
+
+// PublishError only implements Debug
+impl From<PublishError> for Error {
+    fn from(e: PublishError) -> Self {
+        Error::Network(format!("{:?}", e))
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+
+// the hash function blocks are mined and validated with
+pub trait Hasher: Send + Sync {
+    fn hash(&self, bytes: &[u8]) -> Vec<u8>;
+}
+
+pub struct Sha256Hasher;
+
+impl Hasher for Sha256Hasher {
+    fn hash(&self, bytes: &[u8]) -> Vec<u8> {
+        Sha256::digest(bytes).to_vec()
+    }
+}
+
+#[cfg(feature = "sha512")]
+pub struct Sha512Hasher;
+
+#[cfg(feature = "sha512")]
+impl Hasher for Sha512Hasher {
+    fn hash(&self, bytes: &[u8]) -> Vec<u8> {
+        sha2::Sha512::digest(bytes).to_vec()
+    }
+}
+
+#[cfg(feature = "blake3")]
+pub struct Blake3Hasher;
+
+#[cfg(feature = "blake3")]
+impl Hasher for Blake3Hasher {
+    fn hash(&self, bytes: &[u8]) -> Vec<u8> {
+        blake3::hash(bytes).as_bytes().to_vec()
+    }
+}
+
+// hashers compiled into this build, by the name `--hasher` takes
+pub fn by_name(name: &str) -> Option<Arc<dyn Hasher>> {
+    match name {
+        "sha256" => Some(Arc::new(Sha256Hasher)),
+        #[cfg(feature = "sha512")]
+        "sha512" => Some(Arc::new(Sha512Hasher)),
+        #[cfg(feature = "blake3")]
+        "blake3" => Some(Arc::new(Blake3Hasher)),
+        _ => None,
+    }
+}
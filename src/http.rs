@@ -0,0 +1,254 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use log::{error, info};
+use serde::Deserialize;
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot, watch},
+};
+
+use crate::{blockchain::Block, p2p::MineRequest};
+
+#[derive(Debug, Clone, Default)]
+pub struct NodeState {
+    pub blocks: Vec<Block>,
+    pub peers: Vec<String>,
+    // listening, initialised and holding at least genesis
+    pub ready: bool,
+}
+
+#[derive(Clone)]
+struct SharedState {
+    node: watch::Receiver<NodeState>,
+    mine: mpsc::Sender<MineRequest>,
+}
+
+#[derive(Deserialize)]
+struct MineBody {
+    data: String,
+}
+
+pub async fn serve(
+    addr: SocketAddr,
+    node: watch::Receiver<NodeState>,
+    mine: mpsc::Sender<MineRequest>,
+) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("could not start http server on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("http api listening on {}", addr);
+    if let Err(e) = axum::serve(listener, router(node, mine)).await {
+        error!("http server error: {}", e);
+    }
+}
+
+fn router(node: watch::Receiver<NodeState>, mine: mpsc::Sender<MineRequest>) -> Router {
+    Router::new()
+        .route("/chain", get(get_chain))
+        .route("/block/:id", get(get_block))
+        .route("/peers", get(get_peers))
+        .route("/health", get(get_health))
+        .route("/mine", post(post_mine))
+        .with_state(SharedState { node, mine })
+}
+
+async fn get_chain(State(state): State<SharedState>) -> Json<Vec<Block>> {
+    Json(state.node.borrow().blocks.clone())
+}
+
+async fn get_block(
+    State(state): State<SharedState>,
+    Path(id): Path<u64>,
+) -> Result<Json<Block>, StatusCode> {
+    state
+        .node
+        .borrow()
+        .blocks
+        .iter()
+        .find(|b| b.id == id)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_peers(State(state): State<SharedState>) -> Json<Vec<String>> {
+    Json(state.node.borrow().peers.clone())
+}
+
+async fn get_health(State(state): State<SharedState>) -> StatusCode {
+    if state.node.borrow().ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+// waits for the main loop to mine the block, 409 if it refuses
+async fn post_mine(
+    State(state): State<SharedState>,
+    Json(body): Json<MineBody>,
+) -> Result<Json<Block>, StatusCode> {
+    let (reply, response) = oneshot::channel();
+    state
+        .mine
+        .send(MineRequest {
+            data: body.data,
+            reply,
+        })
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    match response.await {
+        Ok(Some(block)) => Ok(Json(block)),
+        _ => Err(StatusCode::CONFLICT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use libp2p::identity;
+
+    use super::*;
+    use crate::{
+        blockchain::App,
+        p2p::{AppBehaviour, CHANNEL_CAPACITY},
+    };
+
+    // serves `state` on a free loopback port, handing back the mining requests it gets
+    async fn start(state: NodeState) -> (SocketAddr, mpsc::Receiver<MineRequest>) {
+        let (_, node) = watch::channel(state);
+        let (mine, requests) = mpsc::channel(1);
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("can bind");
+        let addr = listener.local_addr().expect("is bound");
+        tokio::spawn(async move { axum::serve(listener, router(node, mine)).await });
+        (addr, requests)
+    }
+
+    // a bare HTTP/1.1 request, so the tests need no client; gives the status and body
+    async fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.expect("can connect");
+        let request = format!(
+            "{} {} HTTP/1.1\r\nhost: test\r\nconnection: close\r\n\
+             content-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        );
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .expect("can send");
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .await
+            .expect("can read");
+        let (head, body) = response.split_once("\r\n\r\n").expect("has a body");
+        let status = head.split(' ').nth(1).expect("has a status");
+        (
+            status.parse().expect("status is a number"),
+            body.to_string(),
+        )
+    }
+
+    fn initialised() -> NodeState {
+        let mut app = App::new();
+        app.genesis();
+        NodeState {
+            blocks: app.blocks.clone(),
+            peers: vec!["peer".to_string()],
+            ready: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn chain_starts_with_genesis() {
+        let (addr, _requests) = start(initialised()).await;
+        let (status, body) = request(addr, "GET", "/chain", "").await;
+        assert_eq!(status, 200);
+        let blocks: Vec<Block> = serde_json::from_str(&body).expect("is a chain");
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].id, 0);
+        assert_eq!(blocks[0].hash, initialised().blocks[0].hash);
+    }
+
+    #[tokio::test]
+    async fn blocks_and_peers_are_served() {
+        let (addr, _requests) = start(initialised()).await;
+        let (status, body) = request(addr, "GET", "/block/0", "").await;
+        assert_eq!(status, 200);
+        let block: Block = serde_json::from_str(&body).expect("is a block");
+        assert_eq!(block.id, 0);
+        assert_eq!(request(addr, "GET", "/block/1", "").await.0, 404);
+        let (_, body) = request(addr, "GET", "/peers", "").await;
+        assert_eq!(body, r#"["peer"]"#);
+    }
+
+    #[tokio::test]
+    async fn posted_data_is_mined_into_a_block() {
+        let (addr, mut requests) = start(initialised()).await;
+        let dir = tempfile::tempdir().expect("can create a temp dir");
+        let mut app = App::new();
+        app.difficulty = 1;
+        app.genesis();
+        let (response_sender, _responses) = mpsc::channel(CHANNEL_CAPACITY);
+        let (mined_sender, mut mined) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut node = AppBehaviour::new(
+            identity::Keypair::generate_ed25519(),
+            app,
+            response_sender,
+            dir.path().join("chain.jsonl"),
+            mined_sender,
+            None,
+            false,
+        )
+        .await;
+
+        let first =
+            tokio::spawn(
+                async move { request(addr, "POST", "/mine", r#"{"data":"remote"}"#).await },
+            );
+        node.handle_remote_mine(requests.recv().await.expect("is forwarded"));
+        // the first job is still running, so the second post is refused
+        let second =
+            tokio::spawn(
+                async move { request(addr, "POST", "/mine", r#"{"data":"too soon"}"#).await },
+            );
+        node.handle_remote_mine(requests.recv().await.expect("is forwarded"));
+        assert_eq!(second.await.expect("completes").0, 409);
+
+        node.handle_mined_block(mined.recv().await.expect("the job reports"));
+        let (status, body) = first.await.expect("completes");
+        assert_eq!(status, 200);
+        let block: Block = serde_json::from_str(&body).expect("is a block");
+        assert_eq!(block.data, "remote");
+        assert_eq!(node.app.tip().map(|b| &b.hash), Some(&block.hash));
+    }
+
+    #[tokio::test]
+    async fn health_turns_ready_after_init() {
+        let (state, node) = watch::channel(NodeState::default());
+        let (mine, _requests) = mpsc::channel(1);
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("can bind");
+        let addr = listener.local_addr().expect("is bound");
+        tokio::spawn(async move { axum::serve(listener, router(node, mine)).await });
+        assert_eq!(request(addr, "GET", "/health", "").await.0, 503);
+
+        // what the main loop publishes once listening and initialised
+        state.send(initialised()).expect("the server watches");
+        assert_eq!(request(addr, "GET", "/health", "").await.0, 200);
+    }
+}
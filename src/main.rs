@@ -1,77 +1,311 @@
-use std::time::Duration;
+use std::{fs, io, path::Path, process, sync::Arc, time::Duration};
 
+use clap::Parser;
+use command::{Command, ParseError};
 use libp2p::{
-    core::upgrade,
+    core::{muxing::StreamMuxerBox, transport, upgrade, ConnectedPoint},
     futures::StreamExt,
-    mplex,
+    identity, mplex,
     noise::{Keypair, NoiseConfig, X25519Spec},
-    swarm::{Swarm, SwarmBuilder},
+    swarm::{Swarm, SwarmBuilder, SwarmEvent},
     tcp::TokioTcpConfig,
-    Transport,
+    yamux::YamuxConfig,
+    Multiaddr, PeerId, Transport,
 };
-use p2p::PEER_ID;
+use log::{debug, error, info, warn};
 use tokio::{
     io::{stdin, AsyncBufReadExt, BufReader},
-    select, spawn,
+    select, signal, spawn,
     sync::mpsc,
-    time::sleep,
+    time::{interval, sleep},
 };
 
 mod blockchain;
+mod bootstrap;
+mod cli;
+mod clock;
+mod command;
+#[cfg(feature = "compression")]
+mod compression;
+mod consensus;
+mod error;
+mod hasher;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod orphans;
 mod p2p;
+mod registry;
+
+const CHAIN_FILE: &str = "chain.jsonl";
+// how often the sync deadline, --mine-on-start and bootstrap redials are checked
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+// pause after a --script command that mines, so its block is usually in before the next
+const SCRIPT_MINE_DELAY: Duration = Duration::from_secs(2);
+#[cfg(feature = "http")]
+const HTTP_ADDR: &str = "127.0.0.1:8080";
+#[cfg(feature = "metrics")]
+const METRICS_ADDR: &str = "127.0.0.1:9090";
 
 #[tokio::main]
 async fn main() {
-    println!("PEER ID {}", *PEER_ID);
-    let (response_sender, mut response_rcv) = mpsc::unbounded_channel();
-    let (init_sender, mut init_rcv) = mpsc::unbounded_channel();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = cli::Args::parse();
+    if args.gen_genesis {
+        return print_genesis(&args);
+    }
+    let keys = match args.key_seed {
+        Some(seed) => {
+            warn!("the node key comes from --key-seed, anyone who guesses the seed can use it");
+            p2p::keypair_from_seed(seed)
+        }
+        None => identity::Keypair::generate_ed25519(),
+    };
+    let peer_id = PeerId::from(keys.public());
+    info!("PEER ID {}", peer_id);
+    let capacity = args.channel_capacity.get();
+    let (response_sender, mut response_rcv) = mpsc::channel(capacity);
+    let (init_sender, mut init_rcv) = mpsc::channel(1);
+    let (mined_sender, mut mined_rcv) = mpsc::channel(capacity);
+    // only the http api sends mine requests, otherwise the channel just stays open
+    let (_mine_sender, mut mine_rcv) = mpsc::channel(capacity);
     // this will keep the channel open so recv will sleep
     let _init_sender = init_sender.clone();
+    // the script task gets a clone, this one keeps recv sleeping once it is done
+    let (script_sender, mut script_rcv) = mpsc::channel(1);
+    let mut script = args.script.as_deref().map(read_script);
 
-    let auth_keys = Keypair::<X25519Spec>::new()
-        .into_authentic(&p2p::KEYS)
-        .expect("can create auth keys");
-
-    let transport = TokioTcpConfig::new()
-        .upgrade(upgrade::Version::V1)
-        .authenticate(NoiseConfig::xx(auth_keys).into_authenticated())
-        .multiplex(mplex::MplexConfig::new())
-        .boxed();
-    let behaviour = p2p::AppBehaviour::new(blockchain::App::new(), response_sender).await;
-    let mut swarm = SwarmBuilder::new(transport, behaviour, *p2p::PEER_ID)
-        .executor(Box::new(|fut| {
-            spawn(fut);
-        }))
-        .build();
+    if let Err(e) = fs::create_dir_all(&args.data_dir) {
+        error!("cannot create {}: {}", args.data_dir.display(), e);
+        process::exit(1);
+    }
+    let chain_file = args.data_dir.join(CHAIN_FILE);
+    let mut app = load_chain(&chain_file);
+    let snapshot_file = args.data_dir.join(blockchain::SNAPSHOT_FILE);
+    match blockchain::Snapshot::load_from_file(&snapshot_file) {
+        Ok(snapshot) => {
+            info!("chain continues from snapshot at block {}", snapshot.id);
+            app.snapshot = Some(snapshot);
+        }
+        Err(error::Error::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => warn!("could not load {}: {}", snapshot_file.display(), e),
+    }
+    if let Some(path) = args.genesis.as_ref().map(|p| args.data_dir.join(p)) {
+        match blockchain::App::genesis_from_config(&path) {
+            Ok(genesis) => {
+                info!("using genesis {} from {}", genesis.hash, path.display());
+                if app.get(0).is_some_and(|b| b.hash != genesis.hash) {
+                    warn!("{} starts from a different genesis", chain_file.display());
+                }
+                app.custom_genesis = Some(genesis);
+            }
+            Err(e) => {
+                error!("cannot read genesis config {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = args.checkpoints.as_ref().map(|p| args.data_dir.join(p)) {
+        match blockchain::App::checkpoints_from_file(&path) {
+            Ok(checkpoints) => {
+                info!(
+                    "loaded {} checkpoints from {}",
+                    checkpoints.len(),
+                    path.display()
+                );
+                app.checkpoints = checkpoints;
+            }
+            Err(e) => {
+                error!("cannot read checkpoints {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+    app.difficulty = args.difficulty;
+    app.hasher = match hasher::by_name(&args.hasher) {
+        Some(hasher) => hasher,
+        None => {
+            error!("unknown or disabled hasher {}", args.hasher);
+            process::exit(1);
+        }
+    };
+    app.consensus = match args.consensus {
+        cli::ConsensusKind::Pow => Arc::new(consensus::ProofOfWork),
+        cli::ConsensusKind::Poa if args.authority.is_empty() => {
+            error!("proof of authority needs at least one --authority");
+            process::exit(1);
+        }
+        // headers carry no signature, so light nodes could not check who sealed a block
+        cli::ConsensusKind::Poa if args.light => {
+            error!("light nodes cannot follow a proof of authority chain");
+            process::exit(1);
+        }
+        cli::ConsensusKind::Poa => Arc::new(consensus::ProofOfAuthority::new(
+            keys.clone(),
+            args.authority.iter().copied(),
+        )),
+    };
+    app.max_block_data = args.max_block_data;
+    app.mine_log_every = args.mine_log_every;
+    app.miner = peer_id.to_string();
+    verify_chain(&mut app, &chain_file, args.truncate_invalid);
+    if args.light {
+        app.make_light();
+        info!("light mode, keeping {} headers", app.headers.len());
+    }
+    let mut hosted = Vec::new();
+    for network in &args.host_network {
+        if args.network.as_ref() == Some(network) {
+            error!(
+                "{} is this node's own network, it cannot be hosted too",
+                network
+            );
+            process::exit(1);
+        }
+        let dir = args.data_dir.join(network);
+        if let Err(e) = fs::create_dir_all(&dir) {
+            error!("cannot create {}: {}", dir.display(), e);
+            process::exit(1);
+        }
+        let chain_file = dir.join(CHAIN_FILE);
+        let mut hosted_app = hosted_app(&app, &chain_file);
+        verify_chain(&mut hosted_app, &chain_file, args.truncate_invalid);
+        hosted.push((network, hosted_app, chain_file));
+    }
+    let mut behaviour = p2p::AppBehaviour::new(
+        keys.clone(),
+        app,
+        response_sender,
+        chain_file,
+        mined_sender,
+        args.network.as_deref(),
+        !args.no_mdns,
+    )
+    .await;
+    behaviour.min_block_interval = Duration::from_secs(args.min_block_interval);
+    behaviour.set_max_ping_failures(args.max_ping_failures);
+    behaviour.sync_timeout = Duration::from_secs(args.sync_timeout);
+    behaviour.stale_after = Duration::from_secs(args.stale_after);
+    behaviour.max_sync_attempts = args.max_sync_attempts;
+    behaviour.max_response_blocks = args.max_response_blocks;
+    behaviour.max_peers = args.max_peers;
+    behaviour.mine_on_start = args.mine_on_start;
+    for (network, app, chain_file) in hosted {
+        info!("hosting the {} chain in {}", network, chain_file.display());
+        behaviour.host_chain(network, app, chain_file);
+    }
+    let mut swarm = build_swarm(behaviour, &keys, args.muxer == cli::Muxer::Yamux);
     let mut stdin = BufReader::new(stdin()).lines();
 
-    Swarm::listen_on(
-        &mut swarm,
-        "/ip4/0.0.0.0/tcp/0"
-            .parse()
-            .expect("can get a local socket"),
-    )
-    .expect("swarm can be started");
+    if let Err(e) = Swarm::listen_on(&mut swarm, args.listen_addr.clone()) {
+        error!("cannot listen on {}: {}", args.listen_addr, e);
+        process::exit(1);
+    }
+
+    // filled from NewListenAddr, `/tcp/0` only gets its port once listening starts
+    let mut listen_addrs: Vec<Multiaddr> = Vec::new();
+    let mut bootstrap = bootstrap::Bootstrap::new(args.bootstrap.clone());
+    for addr in args.bootstrap {
+        dial_bootstrap(&mut swarm, &mut bootstrap, addr);
+    }
+
+    #[cfg(feature = "http")]
+    let state_sender = {
+        let (state_sender, state_rcv) = tokio::sync::watch::channel(http::NodeState::default());
+        let addr = HTTP_ADDR.parse().expect("can parse http address");
+        spawn(http::serve(addr, state_rcv, _mine_sender.clone()));
+        state_sender
+    };
+
+    #[cfg(feature = "metrics")]
+    {
+        let registry = prometheus::Registry::new();
+        metrics::register(&registry);
+        let addr = METRICS_ADDR.parse().expect("can parse metrics address");
+        spawn(metrics::serve(addr, registry));
+    }
 
     spawn(async move {
         sleep(Duration::from_secs(1)).await;
-        println!("sending init event");
-        init_sender.send(true).expect("can send init event");
+        debug!("sending init event");
+        init_sender.send(true).await.expect("can send init event");
     });
 
+    let mut ticker = interval(TICK_INTERVAL);
+    // cleared at end of input, the node then keeps running without commands
+    let mut stdin_open = true;
+    #[cfg(feature = "http")]
+    let mut initialised = false;
     loop {
         let evt = {
             select! {
-                line = stdin.next_line() => {
-                    Some(p2p::EventType::Input(line.expect("can get line").expect("can read line from sdin")))
+                line = stdin.next_line(), if stdin_open => match line {
+                    Ok(Some(line)) => Some(p2p::EventType::Input(line)),
+                    Ok(None) => {
+                        info!("stdin closed, no more commands are read");
+                        stdin_open = false;
+                        None
+                    }
+                    Err(e) => {
+                        warn!("cannot read a command: {}", e);
+                        None
+                    }
+                },
+                line = script_rcv.recv() => line.map(p2p::EventType::Input),
+                response = response_rcv.recv() => {
+                    response.map(|(network, resp)| p2p::EventType::LocalChainResponse(network, resp))
+                },
+                mined = mined_rcv.recv() => {
+                    mined.map(p2p::EventType::MinedBlock)
                 },
-                response = response_rcv.recv() =>{
-                    Some(p2p::EventType::LocalChainResponse(response.expect("response exist")))
+                request = mine_rcv.recv() => {
+                    request.map(p2p::EventType::RemoteMine)
                 },
                 _init = init_rcv.recv()=>{
                     Some(p2p::EventType::Init)
                 },
-                _event = swarm.select_next_some() =>{
+                _ = ticker.tick() => {
+                    Some(p2p::EventType::Tick)
+                },
+                _ = signal::ctrl_c() => {
+                    Some(p2p::EventType::Shutdown)
+                },
+                event = swarm.select_next_some() => {
+                    match event {
+                        SwarmEvent::ConnectionEstablished {
+                            peer_id, endpoint, ..
+                        } => {
+                            swarm.behaviour_mut().connection_opened(peer_id);
+                            if let ConnectedPoint::Dialer { address } = endpoint {
+                                bootstrap.connected(&address, peer_id);
+                                swarm.behaviour_mut().handle_dialed_peer(peer_id, &address);
+                            }
+                        }
+                        SwarmEvent::ConnectionClosed {
+                            peer_id,
+                            num_established,
+                            cause,
+                            ..
+                        } => {
+                            swarm.behaviour_mut().connection_closed(peer_id, cause);
+                            if num_established == 0 {
+                                bootstrap.disconnected(&peer_id);
+                            }
+                        }
+                        SwarmEvent::NewListenAddr { address, .. } => {
+                            info!("listening on {}/p2p/{}", address, peer_id);
+                            listen_addrs.push(address);
+                        }
+                        SwarmEvent::ExpiredListenAddr { address, .. } => {
+                            listen_addrs.retain(|a| *a != address)
+                        }
+                        SwarmEvent::UnreachableAddr { address, .. }
+                        | SwarmEvent::UnknownPeerUnreachableAddr { address, .. } => {
+                            bootstrap.dropped(&address)
+                        }
+                        _ => {}
+                    }
                     None
                 }
             }
@@ -81,23 +315,342 @@ async fn main() {
             match event {
                 p2p::EventType::Init => {
                     swarm.behaviour_mut().handle_init();
+                    if let Some(lines) = script.take() {
+                        let delay =
+                            SCRIPT_MINE_DELAY.max(Duration::from_secs(args.min_block_interval));
+                        spawn(run_script(lines, script_sender.clone(), delay));
+                    }
+                    #[cfg(feature = "http")]
+                    {
+                        initialised = true;
+                    }
                 }
-                p2p::EventType::LocalChainResponse(resp) => {
-                    let json = serde_json::to_string(&resp).expect("can jsonify response");
-                    swarm
-                        .behaviour_mut()
-                        .floodsub
-                        .publish(p2p::CHAIN_TOPIC.clone(), json.as_bytes());
+                p2p::EventType::Tick => {
+                    for addr in bootstrap.due() {
+                        dial_bootstrap(&mut swarm, &mut bootstrap, addr);
+                    }
+                    swarm.behaviour_mut().handle_tick()
                 }
-                p2p::EventType::Input(line) => match line.as_str() {
-                    "ls p" => swarm.behaviour().handle_print_peers(),
-                    cmd if cmd.starts_with("create b") => {
-                        swarm.behaviour_mut().handle_create_block(cmd)
+                p2p::EventType::MinedBlock(block) => {
+                    swarm.behaviour_mut().handle_mined_block(block)
+                }
+                p2p::EventType::RemoteMine(request) => {
+                    swarm.behaviour_mut().handle_remote_mine(request)
+                }
+                p2p::EventType::Shutdown => break,
+                p2p::EventType::LocalChainResponse(network, resp) => swarm
+                    .behaviour_mut()
+                    .publish_chain(network.as_deref(), resp),
+                p2p::EventType::Input(line) => match Command::parse(&line) {
+                    Ok(Command::ListPeers) => swarm.behaviour_mut().handle_print_peers(),
+                    Ok(Command::ListChain) => swarm.behaviour().print_chain(),
+                    Ok(Command::DescribeChain) => {
+                        print!("{}", swarm.behaviour().app.describe_chain())
+                    }
+                    Ok(Command::PeerDetails) => swarm.behaviour_mut().handle_print_peer_details(),
+                    Ok(Command::ListenAddrs) => match listen_addrs.as_slice() {
+                        [] => println!("not listening yet"),
+                        addrs => addrs
+                            .iter()
+                            .for_each(|addr| println!("{}/p2p/{}", addr, peer_id)),
+                    },
+                    Ok(Command::ShowBlock(id)) => swarm.behaviour().print_block(id),
+                    Ok(Command::CreateBlock(data)) => {
+                        swarm.behaviour_mut().handle_create_block(&data);
+                    }
+                    Ok(Command::Mine) => {
+                        swarm.behaviour_mut().handle_create_block("");
                     }
-                    cmd if cmd.starts_with("ls c") => swarm.behaviour().print_chain(),
-                    _ => print!("unspported command"),
+                    Ok(Command::Transaction { from, to, amount }) => {
+                        swarm.behaviour_mut().handle_transaction(from, to, amount)
+                    }
+                    Ok(Command::Balance(account)) => swarm.behaviour().handle_balance(&account),
+                    Ok(Command::Status) => swarm.behaviour().handle_status(),
+                    Ok(Command::Stats) => print_stats(&swarm.behaviour().app),
+                    Ok(Command::Since) => swarm.behaviour().handle_since(),
+                    Ok(Command::Hashrate) => swarm.behaviour().handle_hashrate(),
+                    Ok(Command::Validate) => swarm.behaviour().handle_validate(),
+                    Ok(Command::WatchPeers(on)) => swarm.behaviour_mut().watch_peers = on,
+                    Ok(Command::Export { path, force }) => {
+                        swarm.behaviour().handle_export(Path::new(&path), force)
+                    }
+                    Ok(Command::Import(path)) => {
+                        swarm.behaviour_mut().handle_import(Path::new(&path))
+                    }
+                    Ok(Command::Check(path)) => swarm.behaviour().handle_check(Path::new(&path)),
+                    Ok(Command::Resync) => swarm.behaviour_mut().handle_resync(),
+                    Ok(Command::Broadcast) => swarm.behaviour_mut().handle_broadcast(),
+                    Ok(Command::Prune(keep)) => swarm.behaviour_mut().handle_prune(keep),
+                    Ok(Command::Disconnect(peer)) => disconnect(&mut swarm, &peer),
+                    Ok(Command::AddPeer(addr)) => add_peer(&mut swarm, &addr),
+                    Ok(Command::Help) => println!("{}", command::help()),
+                    Ok(Command::OnNetwork { network, command }) => swarm
+                        .behaviour_mut()
+                        .handle_hosted_command(&network, *command),
+                    Err(ParseError::Empty) => {}
+                    Err(e) => println!("{}", e),
                 },
             }
         }
+
+        #[cfg(feature = "http")]
+        publish_state(
+            &state_sender,
+            swarm.behaviour(),
+            initialised && !listen_addrs.is_empty(),
+        );
+        #[cfg(feature = "metrics")]
+        {
+            let behaviour = swarm.behaviour();
+            metrics::PEERS_CONNECTED.set(behaviour.gossipsub.all_peers().count() as i64);
+            metrics::CHAIN_HEIGHT.set(behaviour.app.height().map_or(0, |id| id as i64));
+        }
     }
+
+    swarm.behaviour_mut().handle_shutdown();
+    response_rcv.close();
+    init_rcv.close();
+    mined_rcv.close();
+    mine_rcv.close();
+    info!("shut down");
+}
+
+// genesis is mined at the fixed genesis difficulty, --difficulty only applies to later blocks
+fn print_genesis(args: &cli::Args) {
+    let genesis = match args.genesis.as_ref().map(|p| args.data_dir.join(p)) {
+        Some(path) => match blockchain::App::genesis_from_config(&path) {
+            Ok(genesis) => genesis,
+            Err(e) => {
+                error!("cannot read genesis config {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        },
+        None => blockchain::App::genesis_block(),
+    };
+    match serde_json::to_string_pretty(&genesis) {
+        Ok(json) => println!("{}", json),
+        Err(e) => {
+            error!("could not print genesis: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// the commands of a --script file, without blank and comment lines
+fn read_script(path: &Path) -> Vec<String> {
+    match fs::read_to_string(path) {
+        Ok(script) => script
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect(),
+        Err(e) => {
+            error!("cannot read script {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+}
+
+// hands the loop one line at a time, as if typed, waiting `mine_delay` after each that mines
+async fn run_script(lines: Vec<String>, sender: mpsc::Sender<String>, mine_delay: Duration) {
+    for line in lines {
+        info!("script: {}", line);
+        let mines = Command::parse(&line).is_ok_and(|command| command.mines());
+        if sender.send(line).await.is_err() {
+            return;
+        }
+        if mines {
+            sleep(mine_delay).await;
+        }
+    }
+    info!("script done");
+}
+
+fn load_chain(chain_file: &Path) -> blockchain::App {
+    match blockchain::App::load_from_file(chain_file) {
+        Ok(app) => {
+            info!("loaded {} blocks from {}", app.len(), chain_file.display());
+            app
+        }
+        Err(error::Error::Io(e)) if e.kind() == io::ErrorKind::NotFound => blockchain::App::new(),
+        // starting empty would overwrite a file a newer node can still read
+        Err(e @ error::Error::ChainFormat(_)) => {
+            error!("could not load {}: {}", chain_file.display(), e);
+            process::exit(1);
+        }
+        Err(e) => {
+            warn!("could not load {}: {}", chain_file.display(), e);
+            blockchain::App::new()
+        }
+    }
+}
+
+// a loaded chain is replayed before it is trusted; an invalid one stops the node unless
+// `truncate` keeps its valid prefix
+fn verify_chain(app: &mut blockchain::App, chain_file: &Path, truncate: bool) {
+    let Err(i) = app.is_chain_valid(&app.blocks) else {
+        return;
+    };
+    let id = app.blocks[i].id;
+    if !truncate {
+        error!(
+            "{} is invalid from block {}, start with --truncate-invalid to drop it",
+            chain_file.display(),
+            id
+        );
+        process::exit(1);
+    }
+    warn!(
+        "{} is invalid from block {}, dropping {} blocks",
+        chain_file.display(),
+        id,
+        app.len() - i
+    );
+    app.blocks.truncate(i);
+    if let Err(e) = app.save_to_file(chain_file) {
+        error!("could not rewrite {}: {}", chain_file.display(), e);
+        process::exit(1);
+    }
+}
+
+// a hosted network's chain, with the consensus settings of the node's own
+fn hosted_app(own: &blockchain::App, chain_file: &Path) -> blockchain::App {
+    let mut app = load_chain(chain_file);
+    app.difficulty = own.difficulty;
+    app.target_block_interval = own.target_block_interval;
+    app.max_block_data = own.max_block_data;
+    app.mine_log_every = own.mine_log_every;
+    app.miner = own.miner.clone();
+    app.custom_genesis = own.custom_genesis.clone();
+    app.hasher = own.hasher.clone();
+    app.consensus = own.consensus.clone();
+    app.clock = own.clock.clone();
+    app
+}
+
+fn print_stats(app: &blockchain::App) {
+    if app.light {
+        println!("light nodes keep no blocks to count");
+        return;
+    }
+    let stats = app.stats();
+    println!("blocks: {}", stats.blocks);
+    println!("transactions: {}", stats.transactions);
+    match stats.average_interval {
+        Some(secs) => println!("average block interval: {:.1}s", secs),
+        None => println!("average block interval: n/a"),
+    }
+    println!("total work: {}", stats.total_work);
+    match stats.largest_block {
+        Some((id, size)) => println!("largest block: {} ({} bytes of data)", id, size),
+        None => println!("largest block: n/a"),
+    }
+}
+
+fn dial_bootstrap(
+    swarm: &mut Swarm<p2p::AppBehaviour>,
+    bootstrap: &mut bootstrap::Bootstrap,
+    addr: Multiaddr,
+) {
+    match swarm.dial_addr(addr.clone()) {
+        Ok(()) => info!("dialing bootstrap peer {}", addr),
+        Err(e) => {
+            warn!("cannot dial {}: {:?}", addr, e);
+            bootstrap.dropped(&addr);
+        }
+    }
+}
+
+fn add_peer(swarm: &mut Swarm<p2p::AppBehaviour>, addr: &str) {
+    let Ok(addr) = addr.parse::<Multiaddr>() else {
+        println!("{} is not a multiaddr", addr);
+        return;
+    };
+    match swarm.dial_addr(addr.clone()) {
+        Ok(()) => {
+            println!("dialing {}", addr);
+            swarm.behaviour_mut().add_manual_addr(addr);
+        }
+        Err(e) => println!("cannot dial {}: {:?}", addr, e),
+    }
+}
+
+// closes every connection to `peer` after dropping it from gossip
+fn disconnect(swarm: &mut Swarm<p2p::AppBehaviour>, peer: &str) {
+    let Ok(peer_id) = peer.parse::<PeerId>() else {
+        println!("{} is not a peer id", peer);
+        return;
+    };
+    if !swarm.is_connected(&peer_id) {
+        println!("not connected to {}, `ls p` lists known peers", peer_id);
+        return;
+    }
+    swarm.behaviour_mut().forget_peer(&peer_id);
+    match swarm.disconnect_peer_id(peer_id) {
+        Ok(()) => println!("disconnected from {}", peer_id),
+        Err(()) => println!("not connected to {}", peer_id),
+    }
+}
+
+// a swarm for the behaviour's peer id, running its background tasks on tokio
+fn build_swarm(
+    behaviour: p2p::AppBehaviour,
+    keys: &identity::Keypair,
+    use_yamux: bool,
+) -> Swarm<p2p::AppBehaviour> {
+    let peer_id = behaviour.peer_id;
+    SwarmBuilder::new(build_transport(keys, use_yamux), behaviour, peer_id)
+        .executor(Box::new(|fut| {
+            spawn(fut);
+        }))
+        .build()
+}
+
+fn build_transport(
+    keys: &identity::Keypair,
+    use_yamux: bool,
+) -> transport::Boxed<(PeerId, StreamMuxerBox)> {
+    let auth_keys = Keypair::<X25519Spec>::new()
+        .into_authentic(keys)
+        .expect("can create auth keys");
+
+    // /ws addresses go to the websocket transport, plain tcp ones stay on tcp
+    #[cfg(feature = "websocket")]
+    let tcp =
+        TokioTcpConfig::new().or_transport(libp2p::websocket::WsConfig::new(TokioTcpConfig::new()));
+    #[cfg(not(feature = "websocket"))]
+    let tcp = TokioTcpConfig::new();
+    let authenticated = tcp
+        .upgrade(upgrade::Version::V1)
+        .authenticate(NoiseConfig::xx(auth_keys).into_authenticated());
+    if use_yamux {
+        authenticated.multiplex(YamuxConfig::default()).boxed()
+    } else {
+        authenticated.multiplex(mplex::MplexConfig::new()).boxed()
+    }
+}
+
+#[cfg(feature = "http")]
+fn publish_state(
+    sender: &tokio::sync::watch::Sender<http::NodeState>,
+    behaviour: &p2p::AppBehaviour,
+    started: bool,
+) {
+    let peers = behaviour.peer_ids();
+    let blocks = &behaviour.app.blocks;
+    // light nodes keep genesis as a header
+    let ready = started && !(blocks.is_empty() && behaviour.app.headers.is_empty());
+    sender.send_if_modified(|state| {
+        let changed = state.blocks.len() != blocks.len()
+            || state.blocks.last().map(|b| &b.hash) != blocks.last().map(|b| &b.hash)
+            || state.peers != peers
+            || state.ready != ready;
+        if changed {
+            state.blocks = blocks.clone();
+            state.peers = peers;
+            state.ready = ready;
+        }
+        changed
+    });
 }
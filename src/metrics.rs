@@ -0,0 +1,107 @@
+use std::net::SocketAddr;
+
+use axum::{http::StatusCode, routing::get, Router};
+use log::{error, info};
+use once_cell::sync::Lazy;
+use prometheus::{Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+use tokio::net::TcpListener;
+
+pub static BLOCKS_MINED: Lazy<IntCounter> = Lazy::new(|| {
+    IntCounter::new("blocks_mined_total", "blocks mined by this node").expect("valid metric")
+});
+pub static BLOCKS_RECEIVED: Lazy<IntCounter> = Lazy::new(|| {
+    IntCounter::new("blocks_received_total", "blocks received from peers").expect("valid metric")
+});
+pub static BLOCKS_REJECTED: Lazy<IntCounter> = Lazy::new(|| {
+    IntCounter::new(
+        "blocks_rejected_total",
+        "received blocks that failed validation",
+    )
+    .expect("valid metric")
+});
+pub static PEERS_CONNECTED: Lazy<IntGauge> = Lazy::new(|| {
+    IntGauge::new("peers_connected", "peers in the gossip mesh").expect("valid metric")
+});
+pub static CHAIN_HEIGHT: Lazy<IntGauge> =
+    Lazy::new(|| IntGauge::new("chain_height", "id of the local chain tip").expect("valid metric"));
+
+pub fn register(registry: &Registry) {
+    registry
+        .register(Box::new(BLOCKS_MINED.clone()))
+        .expect("can register blocks_mined_total");
+    registry
+        .register(Box::new(BLOCKS_RECEIVED.clone()))
+        .expect("can register blocks_received_total");
+    registry
+        .register(Box::new(BLOCKS_REJECTED.clone()))
+        .expect("can register blocks_rejected_total");
+    registry
+        .register(Box::new(PEERS_CONNECTED.clone()))
+        .expect("can register peers_connected");
+    registry
+        .register(Box::new(CHAIN_HEIGHT.clone()))
+        .expect("can register chain_height");
+}
+
+pub async fn serve(addr: SocketAddr, registry: Registry) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("could not start metrics server on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("metrics listening on {}", addr);
+    if let Err(e) = axum::serve(listener, router(registry)).await {
+        error!("metrics server error: {}", e);
+    }
+}
+
+fn router(registry: Registry) -> Router {
+    Router::new().route("/metrics", get(move || async move { gather(&registry) }))
+}
+
+fn gather(registry: &Registry) -> Result<String, StatusCode> {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&registry.gather(), &mut buffer)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    String::from_utf8(buffer).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn scrape_reports_received_blocks() {
+        let registry = Registry::new();
+        register(&registry);
+        BLOCKS_RECEIVED.inc();
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("can bind");
+        let addr = listener.local_addr().expect("is bound");
+        tokio::spawn(async move { axum::serve(listener, router(registry)).await });
+
+        let mut stream = TcpStream::connect(addr).await.expect("can connect");
+        let request = "GET /metrics HTTP/1.1\r\nhost: test\r\nconnection: close\r\n\r\n";
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .expect("can send");
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .await
+            .expect("can read");
+        assert!(response.starts_with("HTTP/1.1 200"));
+        // other tests feed blocks too, so only a lower bound holds
+        let received = response
+            .lines()
+            .find_map(|line| line.strip_prefix("blocks_received_total "))
+            .expect("has the counter");
+        assert!(received.parse::<u64>().expect("is a count") >= 1);
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use libp2p::PeerId;
+use log::{debug, info};
+
+use crate::blockchain::{App, Block};
+
+const MAX_ORPHANS: usize = 64;
+
+// blocks that arrived ahead of their parent, by the hash of that parent, so they can
+// be added once it shows up
+#[derive(Default)]
+pub struct OrphanPool {
+    by_parent: HashMap<String, Vec<(PeerId, Block)>>,
+    len: usize,
+}
+
+impl OrphanPool {
+    // when full, the highest block goes, it is the furthest from connecting
+    pub fn insert(&mut self, source: PeerId, block: Block) {
+        if self.iter().any(|orphan| orphan.hash == block.hash) {
+            return;
+        }
+        if self.len >= MAX_ORPHANS {
+            let highest = self.iter().map(|orphan| orphan.id).max();
+            if highest.is_some_and(|id| id <= block.id) {
+                debug!("orphan pool is full, dropping block {}", block.id);
+                return;
+            }
+            self.evict(highest.expect("pool is not empty"));
+        }
+        self.len += 1;
+        self.by_parent
+            .entry(block.previous_hash.clone())
+            .or_default()
+            .push((source, block));
+    }
+
+    // the orphans waiting on `hash`, they leave the pool
+    pub fn take_children(&mut self, hash: &str) -> Vec<(PeerId, Block)> {
+        let children = self.by_parent.remove(hash).unwrap_or_default();
+        self.len -= children.len();
+        children
+    }
+
+    // adds the orphans waiting on `hash` to `app`, each one added may in turn free its own;
+    // returns how many were added, they end the chain
+    pub fn connect(&mut self, app: &mut App, hash: &str) -> usize {
+        let mut added = 0;
+        let mut children = self.take_children(hash);
+        while let Some((source, child)) = children.pop() {
+            let id = child.id;
+            match app.try_add_bock(child, Some(&source.to_string())) {
+                Ok(()) => {
+                    let block = app.tip().expect("block was just added");
+                    info!("connected orphan block {} from {}", id, source);
+                    children.extend(self.take_children(&block.hash));
+                    added += 1;
+                }
+                Err(e) => debug!("dropping orphan block {}: {}", id, e),
+            }
+        }
+        added
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Block> {
+        self.by_parent
+            .values()
+            .flat_map(|children| children.iter().map(|(_, block)| block))
+    }
+
+    fn evict(&mut self, id: u64) {
+        for children in self.by_parent.values_mut() {
+            if let Some(i) = children.iter().position(|(_, block)| block.id == id) {
+                let (_, block) = children.remove(i);
+                debug!("orphan pool is full, evicting block {}", block.id);
+                self.len -= 1;
+                break;
+            }
+        }
+        self.by_parent.retain(|_, children| !children.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a block whose parent is `parent`, only the fields the pool looks at matter
+    fn orphan(id: u64, parent: &str) -> Block {
+        let mut block = App::genesis_block();
+        block.id = id;
+        block.hash = format!("hash {}", id);
+        block.previous_hash = parent.to_string();
+        block
+    }
+
+    #[test]
+    fn children_leave_the_pool_with_their_parent() {
+        let mut pool = OrphanPool::default();
+        let source = PeerId::random();
+        pool.insert(source, orphan(2, "hash 1"));
+        pool.insert(source, orphan(2, "hash 1"));
+        pool.insert(source, orphan(3, "hash 2"));
+        assert_eq!(pool.len, 2);
+        let children = pool.take_children("hash 1");
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].0, source);
+        assert_eq!(children[0].1.id, 2);
+        assert!(pool.take_children("hash 1").is_empty());
+        assert_eq!(pool.len, 1);
+    }
+
+    #[test]
+    fn full_pool_drops_the_highest_block() {
+        let mut pool = OrphanPool::default();
+        let source = PeerId::random();
+        for id in 10..10 + MAX_ORPHANS as u64 {
+            pool.insert(source, orphan(id, &format!("hash {}", id - 1)));
+        }
+        let highest = 9 + MAX_ORPHANS as u64;
+        // higher than everything held, so it is the one turned away
+        pool.insert(source, orphan(highest + 1, "far ahead"));
+        assert!(pool.take_children("far ahead").is_empty());
+        // lower, so it takes the place of the highest
+        pool.insert(source, orphan(5, "hash 4"));
+        assert_eq!(pool.len, MAX_ORPHANS);
+        assert_eq!(pool.take_children("hash 4").len(), 1);
+        assert!(pool
+            .take_children(&format!("hash {}", highest - 1))
+            .is_empty());
+    }
+}
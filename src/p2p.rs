@@ -1,22 +1,66 @@
-use std::collections::HashSet;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt, fs, io,
+    num::{NonZeroU32, NonZeroUsize},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use crate::blockchain::{App, Block};
+use crate::{
+    blockchain::{self, App, Block, BlockHeader, MineError, MineTask, Transaction, MAX_AMOUNT},
+    command::Command,
+    error::Error,
+    orphans::OrphanPool,
+    registry::{ChainRegistry, HostedChain},
+};
 use libp2p::{
-    floodsub::{Floodsub, FloodsubEvent, Topic},
+    gossipsub::{
+        Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage, IdentTopic,
+        MessageAuthenticity, MessageId,
+    },
     identity,
     mdns::{Mdns, MdnsEvent},
-    swarm::NetworkBehaviourEventProcess,
-    NetworkBehaviour, PeerId,
+    ping::{Ping, PingConfig, PingEvent, PingSuccess},
+    swarm::{toggle::Toggle, NetworkBehaviour as _, NetworkBehaviourEventProcess},
+    Multiaddr, NetworkBehaviour, PeerId,
 };
-use once_cell::sync::Lazy;
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
+use sha2::{Digest, Sha256};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    task,
+};
 
-pub static KEYS: Lazy<identity::Keypair> = Lazy::new(identity::Keypair::generate_ed25519);
-pub static PEER_ID: Lazy<PeerId> = Lazy::new(|| PeerId::from(KEYS.public()));
-pub static CHAIN_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("chains"));
-pub static BLOCK_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("blocks"));
+pub const MIN_BLOCK_INTERVAL_SECS: u64 = 1;
+pub const MAX_PING_FAILURES: u32 = 3;
+// peers gossiped with directly, past it the one heard from least recently is dropped
+pub const MAX_PEERS: usize = 50;
+// receiver of a chain response meant for every peer
+pub const BROADCAST_RECEIVER: &str = "*";
+pub const SYNC_TIMEOUT_SECS: u64 = 10;
+// a tip older than this is reported as stale by `since`
+pub const STALE_AFTER_SECS: u64 = 300;
+pub const MAX_SYNC_ATTEMPTS: u32 = 3;
+// most blocks in one chain or range response, full history is paged in with range requests
+pub const MAX_RESPONSE_BLOCKS: usize = 500;
+// largest gossipsub message; responses stop at the blocks that fit in it
+pub const MAX_TRANSMIT_SIZE: usize = 1024 * 1024;
+// room in a message for gossipsub's framing and the JSON around the blocks
+pub const ENVELOPE_BYTES: usize = 1024;
+// pending events per channel into the main loop
+pub const CHANNEL_CAPACITY: usize = 64;
+const HASHRATE_SECS: u64 = 2;
 
+// holds the newest blocks only when the chain is longer than the response cap
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChainResponse {
     pub blocks: Vec<Block>,
@@ -28,133 +72,3201 @@ pub struct LocalChainRequest {
     pub from_peer_id: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RangeRequest {
+    pub from_peer_id: String,
+    pub start_id: u64,
+    pub end_id: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RangeResponse {
+    pub blocks: Vec<Block>,
+    pub receiver: String,
+}
+
+// consecutive blocks mined within one tick, oldest first
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockBatch {
+    pub blocks: Vec<Block>,
+}
+
+// everything sent over gossip, tagged with its kind so a payload can only be read as the
+// type it was sent as
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "payload", rename_all = "snake_case")]
+pub enum Message {
+    ChainResponse(ChainResponse),
+    ChainRequest(LocalChainRequest),
+    Block(Block),
+    BlockBatch(BlockBatch),
+    RangeRequest(RangeRequest),
+    RangeResponse(RangeResponse),
+}
+
+// blocks are identified by the hash of the message bytes so re-broadcasts of the same
+// block are dropped, a block claiming another's hash can't shadow it; anything else by
+// author and sequence number so a repeated request still goes out
+fn message_id(message: &GossipsubMessage) -> MessageId {
+    match serde_json::from_slice::<Message>(&message.data) {
+        Ok(Message::Block(_)) => MessageId::from(hex::encode(Sha256::digest(&message.data))),
+        _ => {
+            let source = message.source.map(|p| p.to_base58()).unwrap_or_default();
+            let seqno = message.sequence_number.unwrap_or_default();
+            MessageId::from(format!("{}{}", source, seqno))
+        }
+    }
+}
+
+// the connection is closed once the same threshold that prunes a peer is reached
+fn new_ping(max_failures: NonZeroU32) -> Ping {
+    Ping::new(
+        PingConfig::new()
+            .with_keep_alive(true)
+            .with_max_failures(max_failures),
+    )
+}
+
+fn new_gossipsub(keys: identity::Keypair) -> Gossipsub {
+    let config = GossipsubConfigBuilder::default()
+        .message_id_fn(message_id)
+        .max_transmit_size(MAX_TRANSMIT_SIZE)
+        .build()
+        .expect("valid gossipsub config");
+    Gossipsub::new(MessageAuthenticity::Signed(keys), config).expect("can create gossipsub")
+}
+
+// a mine request from outside the main loop, answered with the block once mined
+// or None if the node refused to mine it
+pub struct MineRequest {
+    pub data: String,
+    pub reply: oneshot::Sender<Option<Block>>,
+}
+
+// the chain request waiting for a response
+struct SyncRequest {
+    peer: PeerId,
+    deadline: Instant,
+    attempts: u32,
+    tried: HashSet<PeerId>,
+}
+
+// the newest blocks of a peer's chain, waiting for the page before them
+struct PartialChain {
+    peer: PeerId,
+    blocks: Vec<Block>,
+    // the ids asked for, only a response starting there is taken as the page
+    range: (u64, u64),
+    // the tip the peer answered with, paging never holds more than the blocks up to it
+    height: u64,
+}
+
+// what a mining thread reports, tagged with its job so the result of a cancelled job is
+// told apart from the one in flight
+pub struct MineOutcome {
+    job: u64,
+    // the hosted chain the block is for, None for the node's own
+    network: Option<String>,
+    result: Result<Block, MineError>,
+}
+
 pub enum EventType {
-    LocalChainResponse(ChainResponse),
+    // the hosted network the response is for, None for the node's own
+    LocalChainResponse(Option<String>, ChainResponse),
+    MinedBlock(MineOutcome),
+    RemoteMine(MineRequest),
     Input(String),
     Init,
+    Tick,
+    Shutdown,
 }
 
 #[derive(NetworkBehaviour)]
 pub struct AppBehaviour {
-    pub floodsub: Floodsub,
-    pub mdns: Mdns,
+    pub gossipsub: Gossipsub,
+    // whose keys sign this node's gossip, what messages name to address it
+    #[behaviour(ignore)]
+    pub peer_id: PeerId,
+    // disabled with --no-mdns, peers are then only dialed explicitly
+    pub mdns: Toggle<Mdns>,
+    pub ping: Ping,
     #[behaviour(ignore)]
-    pub reponse_sender: mpsc::UnboundedSender<ChainResponse>,
+    pub reponse_sender: mpsc::Sender<(Option<String>, ChainResponse)>,
     #[behaviour(ignore)]
     pub app: App,
+    #[behaviour(ignore)]
+    pub chain_file: PathBuf,
+    #[behaviour(ignore)]
+    pub mined_sender: mpsc::Sender<MineOutcome>,
+    // id and cancellation flag of the mining job in flight, if any
+    #[behaviour(ignore)]
+    mining: Option<(u64, Arc<AtomicBool>)>,
+    // mining jobs started so far, also the id of the next one
+    #[behaviour(ignore)]
+    mining_jobs: u64,
+    // the `MineRequest` waiting for the job in flight
+    #[behaviour(ignore)]
+    mine_reply: Option<oneshot::Sender<Option<Block>>>,
+    #[behaviour(ignore)]
+    pub watch_peers: bool,
+    // when `handle_create_block` last started a mine, calls closer than the interval are refused
+    #[behaviour(ignore)]
+    last_block_time: Option<Instant>,
+    #[behaviour(ignore)]
+    pub min_block_interval: Duration,
+    #[behaviour(ignore)]
+    known_peers: HashSet<PeerId>,
+    // when each gossip peer last forwarded a message, or joined; also the set of them
+    #[behaviour(ignore)]
+    last_active: HashMap<PeerId, Instant>,
+    #[behaviour(ignore)]
+    pub max_peers: NonZeroUsize,
+    // open connections, a peer may have more than one
+    #[behaviour(ignore)]
+    connections: usize,
+    // addresses dialed with `add peer`, and the peers that answered at them
+    #[behaviour(ignore)]
+    manual_addrs: HashSet<Multiaddr>,
+    #[behaviour(ignore)]
+    manual_peers: HashMap<PeerId, Multiaddr>,
+    // consecutive failed pings per peer, reset by a successful one
+    #[behaviour(ignore)]
+    ping_failures: HashMap<PeerId, u32>,
+    #[behaviour(ignore)]
+    max_ping_failures: NonZeroU32,
+    // chain responses that checked out minus those that did not, by peer id
+    #[behaviour(ignore)]
+    peer_scores: HashMap<String, i32>,
+    #[behaviour(ignore)]
+    sync: Option<SyncRequest>,
+    #[behaviour(ignore)]
+    pub sync_timeout: Duration,
+    #[behaviour(ignore)]
+    pub stale_after: Duration,
+    #[behaviour(ignore)]
+    pub max_sync_attempts: NonZeroU32,
+    #[behaviour(ignore)]
+    pub max_response_blocks: NonZeroUsize,
+    // a truncated chain response, completed backwards a range at a time
+    #[behaviour(ignore)]
+    partial_chain: Option<PartialChain>,
+    #[behaviour(ignore)]
+    pub mine_on_start: u64,
+    // blocks still to mine for `mine_on_start`, counted from init
+    #[behaviour(ignore)]
+    auto_mine_left: u64,
+    // mined blocks wait here for the next tick so a quick run of them is sent as one batch
+    #[behaviour(ignore)]
+    unsent_blocks: Vec<Block>,
+    #[behaviour(ignore)]
+    pub chain_topic: IdentTopic,
+    #[behaviour(ignore)]
+    block_topic: IdentTopic,
+    // range messages share field names with chain messages, so they get their own topic
+    #[behaviour(ignore)]
+    range_topic: IdentTopic,
+    #[behaviour(ignore)]
+    registry: ChainRegistry,
+    #[behaviour(ignore)]
+    orphans: OrphanPool,
+}
+
+// mdns reports a peer once per address, and may repeat an address
+fn group_addrs(
+    addrs: impl Iterator<Item = (PeerId, Multiaddr)>,
+) -> BTreeMap<PeerId, BTreeSet<Multiaddr>> {
+    let mut list: BTreeMap<PeerId, BTreeSet<Multiaddr>> = BTreeMap::new();
+    for (peer, addr) in addrs {
+        list.entry(peer).or_default().insert(addr);
+    }
+    list
+}
+
+// the tail of a chain that fits in one chain response
+fn newest_blocks(blocks: &[Block], max: NonZeroUsize) -> Vec<Block> {
+    let fit = blocks_that_fit(blocks.iter().rev(), max);
+    blocks[blocks.len() - fit..].to_vec()
+}
+
+// how many of `blocks`, taken in order, go in one message, at most `max`
+fn blocks_that_fit<'a>(blocks: impl Iterator<Item = &'a Block>, max: NonZeroUsize) -> usize {
+    let mut size = 0usize;
+    blocks
+        .take(max.get())
+        .take_while(|block| {
+            // the separating comma, and a block that can't serialize never fits
+            let len = serde_json::to_vec(block).map_or(usize::MAX, |json| json.len());
+            size = size.saturating_add(len).saturating_add(1);
+            size <= MAX_TRANSMIT_SIZE - ENVELOPE_BYTES
+        })
+        .count()
+}
+
+// peer ids in messages are compared parsed, so formatting can't hide a match;
+// malformed ones are logged and never match
+fn is_local_peer(local: &PeerId, id: &str) -> bool {
+    match id.trim().parse::<PeerId>() {
+        Ok(peer) => peer == *local,
+        Err(e) => {
+            warn!("ignoring message for malformed peer id {:?}: {}", id, e);
+            false
+        }
+    }
+}
+
+// the ed25519 secret is the sha256 of the big-endian seed
+pub fn keypair_from_seed(seed: u64) -> identity::Keypair {
+    let mut secret = Sha256::digest(&seed.to_be_bytes()).to_vec();
+    let secret =
+        identity::ed25519::SecretKey::from_bytes(&mut secret).expect("32 bytes make a key");
+    identity::Keypair::Ed25519(secret.into())
+}
+
+// appends the newest `count` blocks of a hosted chain to its file
+fn append_hosted(chain: &HostedChain, count: usize) {
+    for block in &chain.app.blocks[chain.app.blocks.len() - count..] {
+        if let Err(e) = block.append_to_file(&chain.chain_file) {
+            error!(
+                "could not persist {} block: {}",
+                chain.chain_file.display(),
+                e
+            );
+        }
+    }
+}
+
+fn save_hosted(chain: &HostedChain) {
+    if let Err(e) = chain.app.save_to_file(&chain.chain_file) {
+        error!("could not persist {}: {}", chain.chain_file.display(), e);
+    }
+}
+
+// the largest units first, e.g. "1h 3m 12s"
+fn format_age(secs: u64) -> String {
+    let units = [
+        (secs / 86400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+    ];
+    let mut parts: Vec<String> = units
+        .iter()
+        .skip_while(|(n, _)| *n == 0)
+        .map(|(n, unit)| format!("{}{}", n, unit))
+        .collect();
+    parts.push(format!("{}s", secs % 60));
+    parts.join(" ")
+}
+
+// topics are prefixed with the network name so separate networks don't mix
+fn topic(network: Option<&str>, name: &str) -> IdentTopic {
+    match network {
+        Some(network) => IdentTopic::new(format!("{}-{}", network, name)),
+        None => IdentTopic::new(name),
+    }
 }
 
 impl AppBehaviour {
-    pub async fn new(app: App, reponse_sender: mpsc::UnboundedSender<ChainResponse>) -> Self {
+    pub async fn new(
+        keys: identity::Keypair,
+        app: App,
+        reponse_sender: mpsc::Sender<(Option<String>, ChainResponse)>,
+        chain_file: PathBuf,
+        mined_sender: mpsc::Sender<MineOutcome>,
+        network: Option<&str>,
+        mdns: bool,
+    ) -> Self {
+        let max_ping_failures = NonZeroU32::new(MAX_PING_FAILURES).expect("is not zero");
         let mut behaviour = AppBehaviour {
             app,
-            floodsub: Floodsub::new(*PEER_ID),
-            mdns: Mdns::new(Default::default())
-                .await
-                .expect("cannot create mdns"),
+            chain_file,
+            mined_sender,
+            mining: None,
+            mining_jobs: 0,
+            mine_reply: None,
+            watch_peers: true,
+            last_block_time: None,
+            min_block_interval: Duration::from_secs(MIN_BLOCK_INTERVAL_SECS),
+            known_peers: HashSet::new(),
+            last_active: HashMap::new(),
+            max_peers: NonZeroUsize::new(MAX_PEERS).expect("is not zero"),
+            connections: 0,
+            manual_addrs: HashSet::new(),
+            manual_peers: HashMap::new(),
+            ping_failures: HashMap::new(),
+            max_ping_failures,
+            peer_scores: HashMap::new(),
+            sync: None,
+            sync_timeout: Duration::from_secs(SYNC_TIMEOUT_SECS),
+            stale_after: Duration::from_secs(STALE_AFTER_SECS),
+            max_sync_attempts: NonZeroU32::new(MAX_SYNC_ATTEMPTS).expect("is not zero"),
+            max_response_blocks: NonZeroUsize::new(MAX_RESPONSE_BLOCKS).expect("is not zero"),
+            partial_chain: None,
+            mine_on_start: 0,
+            auto_mine_left: 0,
+            unsent_blocks: Vec::new(),
+            ping: new_ping(max_ping_failures),
+            chain_topic: topic(network, "chains"),
+            block_topic: topic(network, "blocks"),
+            range_topic: topic(network, "ranges"),
+            registry: ChainRegistry::default(),
+            orphans: OrphanPool::default(),
+            peer_id: PeerId::from(keys.public()),
+            gossipsub: new_gossipsub(keys),
+            mdns: if mdns {
+                Some(
+                    Mdns::new(Default::default())
+                        .await
+                        .expect("cannot create mdns"),
+                )
+            } else {
+                None
+            }
+            .into(),
             reponse_sender,
         };
-        behaviour.floodsub.subscribe(CHAIN_TOPIC.clone());
-        behaviour.floodsub.subscribe(BLOCK_TOPIC.clone());
+        for topic in [
+            &behaviour.chain_topic,
+            &behaviour.block_topic,
+            &behaviour.range_topic,
+        ] {
+            behaviour
+                .gossipsub
+                .subscribe(topic)
+                .unwrap_or_else(|e| panic!("can subscribe to {}: {:?}", topic, e));
+        }
         behaviour
     }
 
-    pub fn handle_create_block(&mut self, cmd: &str) {
-        if let Some(data) = cmd.strip_prefix("create b") {
-            let latest_block = self.app.blocks.last().expect("there is at least one block");
-            let block = Block::new(
-                latest_block.id + 1,
-                latest_block.hash.clone(),
-                data.to_owned(),
-            );
-            let json = serde_json::to_string(&block).expect("can jsonify request");
-            self.app.blocks.push(block);
-            println!("broadcasting new block");
-            self.floodsub.publish(BLOCK_TOPIC.clone(), json.as_bytes());
-        }
+    // only takes effect before the behaviour is handed to a swarm
+    pub fn set_max_ping_failures(&mut self, max_failures: NonZeroU32) {
+        self.max_ping_failures = max_failures;
+        self.ping = new_ping(max_failures);
     }
 
-    pub fn print_chain(&self) {
-        let json = serde_json::to_string_pretty(&self.app.blocks).expect("can jsonify blcoks");
-        print!("{}", json);
+    pub fn publish(&mut self, topic: IdentTopic, msg: Message) {
+        let hash = topic.hash();
+        if let Err(e) = self.try_publish(topic, msg) {
+            warn!("could not publish to {}: {}", hash, e);
+        }
     }
 
-    pub fn handle_init(&mut self) {
-        let peers = self.get_list_peers();
-        self.app.genesis();
+    fn try_publish(&mut self, topic: IdentTopic, msg: Message) -> Result<(), Error> {
+        let json = serde_json::to_vec(&msg)?;
+        self.gossipsub.publish(topic, json)?;
+        Ok(())
+    }
 
-        println!("connected nodes: {}", peers.len());
+    // chain responses are the large messages, gzipped when built with compression;
+    // `network` is the hosted one to publish on, None for the node's own
+    pub fn publish_chain(&mut self, network: Option<&str>, resp: ChainResponse) {
+        let topic = match network {
+            Some(network) => match self.registry.get(network) {
+                Some(chain) => chain.chain_topic.clone(),
+                None => return warn!("dropping chain response for unhosted {}", network),
+            },
+            None => self.chain_topic.clone(),
+        };
+        if let Err(e) = self.try_publish_chain(topic.clone(), resp) {
+            warn!("could not publish to {}: {}", topic.hash(), e);
+        }
+    }
 
-        if !peers.is_empty() {
-            let req = LocalChainRequest {
-                from_peer_id: peers.iter().last().expect("at lease one peer").to_string(),
-            };
+    fn try_publish_chain(&mut self, topic: IdentTopic, resp: ChainResponse) -> Result<(), Error> {
+        let data = serde_json::to_vec(&Message::ChainResponse(resp))?;
+        #[cfg(feature = "compression")]
+        let data = crate::compression::compress(&data)?;
+        self.gossipsub.publish(topic, data)?;
+        Ok(())
+    }
 
-            let json = serde_json::to_string(&req).expect("can jsonify request");
+    // hands a chain response to the main loop to publish; a full channel means the loop
+    // is behind, the requester retries elsewhere
+    fn queue_chain_response(&self, network: Option<&str>, blocks: Vec<Block>, receiver: PeerId) {
+        let resp = ChainResponse {
+            blocks,
+            receiver: receiver.to_string(),
+        };
+        match self
+            .reponse_sender
+            .try_send((network.map(str::to_string), resp))
+        {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!(
+                "too many pending chain responses, dropping one for {}",
+                receiver
+            ),
+            Err(e) => error!("error sending response {}", e),
+        }
+    }
 
-            self.floodsub.publish(CHAIN_TOPIC.clone(), json.as_bytes());
+    pub fn handle_transaction(&mut self, from: String, to: String, amount: u64) {
+        if amount > MAX_AMOUNT {
+            println!("amounts above {} are not supported", MAX_AMOUNT);
+            return;
+        }
+        let nonce = self.app.next_nonce(&from);
+        let tx = Transaction {
+            from,
+            to,
+            amount,
+            nonce,
+        };
+        if !self.app.can_afford(&tx) {
+            println!("{} cannot afford {}", tx.from, tx.amount);
+            return;
         }
+        self.app.pending.push(tx);
+        println!("transaction queued, {} pending", self.app.pending.len());
     }
 
-    fn get_list_peers(&self) -> Vec<String> {
-        println!("Discover peers");
-        let nodes = self.mdns.discovered_nodes();
-        let mut unique_peers = HashSet::new();
-        for peer in nodes {
-            unique_peers.insert(peer);
+    // starts mining on a blocking thread, the outcome comes back through `mined_sender`;
+    // returns the id of the block being mined, None if the request was refused
+    pub fn handle_create_block(&mut self, data: &str) -> Option<u64> {
+        let task = match self.app.mine_task(data.to_string()) {
+            Ok(task) => task,
+            Err(MineError::Uninitialised) => {
+                warn!("the chain is not initialised yet, try again shortly");
+                return None;
+            }
+            Err(e) => {
+                println!("{}", e);
+                return None;
+            }
+        };
+        if let Some(elapsed) = self.last_block_time.map(|t| t.elapsed()) {
+            if elapsed < self.min_block_interval {
+                println!(
+                    "too soon, wait {:.1}s before creating another block",
+                    (self.min_block_interval - elapsed).as_secs_f64()
+                );
+                return None;
+            }
         }
-        unique_peers.iter().map(|p| p.to_string()).collect()
+        self.last_block_time = Some(Instant::now());
+        Some(self.start_mining(None, task))
     }
 
-    pub fn handle_print_peers(&self) {
-        let peers = self.get_list_peers();
-        peers.iter().for_each(|p| println!("{}", p));
+    // mines `task` on a blocking thread in place of any job in flight, the node's own
+    // chain and the hosted ones share a single job; returns the id of the block
+    fn start_mining(&mut self, network: Option<&str>, task: MineTask) -> u64 {
+        if self.cancel_mining() {
+            info!("aborted the mine in progress, restarting");
+        }
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let cancel = Arc::new(AtomicBool::new(false));
+        let job = self.mining_jobs;
+        self.mining_jobs += 1;
+        self.mining = Some((job, cancel.clone()));
+        let sender = self.mined_sender.clone();
+        let id = task.id;
+        info!(
+            "mining block {} at difficulty {} on {} threads",
+            id, task.difficulty, threads
+        );
+        let network = network.map(str::to_string);
+        task::spawn_blocking(move || {
+            let result = task.mine(threads, &cancel);
+            // whoever cancelled the job already moved on from it
+            if matches!(result, Err(MineError::Cancelled)) {
+                return;
+            }
+            // waits for room, this thread has nothing else to do
+            let outcome = MineOutcome {
+                job,
+                network,
+                result,
+            };
+            if sender.blocking_send(outcome).is_err() {
+                warn!("finished mining block {} but the node is gone", id);
+            }
+        });
+        id
     }
-}
 
-impl NetworkBehaviourEventProcess<MdnsEvent> for AppBehaviour {
-    fn inject_event(&mut self, event: MdnsEvent) {
-        match event {
-            MdnsEvent::Discovered(discovered_list) => {
-                for (peer, _addr) in discovered_list {
-                    self.floodsub.add_node_to_partial_view(peer);
-                }
+    // unlike the create command this never aborts a mine already in flight
+    pub fn handle_remote_mine(&mut self, request: MineRequest) {
+        if self.mining.is_some() {
+            request.reply.send(None).ok();
+            return;
+        }
+        match self.handle_create_block(&request.data) {
+            Some(_) => self.mine_reply = Some(request.reply),
+            None => {
+                request.reply.send(None).ok();
             }
-            MdnsEvent::Expired(expired_list) => {
-                for (peer, _addr) in expired_list {
-                    if !self.mdns.has_node(&peer) {
-                        self.floodsub.remove_node_from_partial_view(&peer);
-                    }
+        }
+    }
+
+    // adds the orphans waiting on `hash`, each one added may in turn free its own
+    fn connect_orphans(&mut self, hash: &str) {
+        let added = self.orphans.connect(&mut self.app, hash);
+        for block in &self.app.blocks[self.app.blocks.len() - added..] {
+            self.persist_block(block);
+        }
+    }
+
+    // a failed job frees the miner the same as a finished one, and answers its request
+    pub fn handle_mined_block(&mut self, outcome: MineOutcome) {
+        if self
+            .mining
+            .as_ref()
+            .is_none_or(|(job, _)| *job != outcome.job)
+        {
+            debug!(
+                "dropping the outcome of cancelled mining job {}",
+                outcome.job
+            );
+            return;
+        }
+        self.mining = None;
+        if let Some(network) = outcome.network {
+            return self.handle_mined_hosted(&network, outcome.result);
+        }
+        let mined = match outcome.result {
+            Ok(block) => match self.app.try_add_bock(block.clone(), None) {
+                Ok(()) => Some(block),
+                Err(e) => {
+                    warn!("dropping stale mined block {}: {}", block.id, e);
+                    None
                 }
+            },
+            Err(e) => {
+                warn!("mining failed: {}", e);
+                None
             }
+        };
+        if let Some(reply) = self.mine_reply.take() {
+            reply.send(mined.clone()).ok();
         }
+        let Some(block) = mined else {
+            return;
+        };
+        #[cfg(feature = "metrics")]
+        crate::metrics::BLOCKS_MINED.inc();
+        self.persist_block(&block);
+        self.connect_orphans(&block.hash);
+        self.unsent_blocks.push(block);
     }
-}
 
-impl NetworkBehaviourEventProcess<FloodsubEvent> for AppBehaviour {
-    fn inject_event(&mut self, event: FloodsubEvent) {
-        if let FloodsubEvent::Message(msg) = event {
-            if let Ok(resp) = serde_json::from_slice::<ChainResponse>(&msg.data) {
-                if resp.receiver == PEER_ID.to_string() {
-                    println!("response from {}:", msg.source.to_string());
-                    resp.blocks.iter().for_each(|r| println!("{:?}", r));
-                    self.app.blocks = self.app.choose_chain(self.app.blocks.clone(), resp.blocks);
+    // as few batches as fit in a message each, a block at the data limit goes alone
+    fn broadcast_mined_blocks(&mut self) {
+        let mut blocks = std::mem::take(&mut self.unsent_blocks);
+        while !blocks.is_empty() {
+            let fit = blocks_that_fit(blocks.iter(), self.max_response_blocks).max(1);
+            let mut batch: Vec<Block> = blocks.drain(..fit).collect();
+            let message = match batch.len() {
+                1 => {
+                    info!("broadcasting new block");
+                    Message::Block(batch.remove(0))
                 }
-            } else if let Ok(resp) = serde_json::from_slice::<LocalChainRequest>(&msg.data) {
-                println!("sending local chain to {}", msg.source.to_string());
-                let peer_id = resp.from_peer_id;
-                if peer_id == PEER_ID.to_string() {
-                    if let Err(e) = self.reponse_sender.send(ChainResponse {
-                        blocks: self.app.blocks.clone(),
-                        receiver: msg.source.to_string(),
-                    }) {
-                        print!("Error sending reposnse {}", e);
-                    }
+                n => {
+                    info!("broadcasting {} new blocks", n);
+                    Message::BlockBatch(BlockBatch { blocks: batch })
                 }
-            } else if let Ok(block) = serde_json::from_slice::<Block>(&msg.data) {
-                println!("received new block from {}", msg.source.to_string());
-                self.app.try_add_bock(block);
+            };
+            self.publish(self.block_topic.clone(), message);
+        }
+    }
+
+    fn cancel_mining(&mut self) -> bool {
+        self.mine_reply = None;
+        match self.mining.take() {
+            Some((_, cancel)) => {
+                cancel.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn connection_opened(&mut self, peer_id: PeerId) {
+        self.connections += 1;
+        info!(
+            "connected to {} ({} connections)",
+            peer_id, self.connections
+        );
+    }
+
+    // `cause` is None for connections closed on purpose
+    pub fn connection_closed<E: fmt::Display>(&mut self, peer_id: PeerId, cause: Option<E>) {
+        self.connections = self.connections.saturating_sub(1);
+        info!(
+            "disconnected from {} ({} connections)",
+            peer_id, self.connections
+        );
+        if let Some(cause) = cause {
+            debug!("connection to {} closed: {}", peer_id, cause);
+        }
+    }
+
+    pub fn handle_dialed_peer(&mut self, peer_id: PeerId, address: &Multiaddr) {
+        if self.manual_addrs.contains(address) {
+            self.manual_peers.insert(peer_id, address.clone());
+            if self.known_peers.insert(peer_id) && self.watch_peers {
+                println!(
+                    "peer joined: {} ({} peers)",
+                    peer_id,
+                    self.known_peers.len()
+                );
+            }
+        }
+        self.add_gossip_peer(peer_id);
+    }
+
+    // the cap holds whichever way peers come in; evicted ones keep their connection but
+    // stop being gossiped with until found again
+    fn add_gossip_peer(&mut self, peer: PeerId) {
+        self.gossipsub.add_explicit_peer(&peer);
+        self.last_active.insert(peer, Instant::now());
+        while self.last_active.len() > self.max_peers.get() {
+            let Some(oldest) = self
+                .last_active
+                .iter()
+                .min_by_key(|(_, at)| **at)
+                .map(|(peer, _)| *peer)
+            else {
+                break;
+            };
+            info!(
+                "more than {} peers, dropping the least recently active {}",
+                self.max_peers, oldest
+            );
+            self.forget_peer(&oldest);
+        }
+    }
+
+    // remembers an address dialed with `add peer`, whoever answers there stays in gossip
+    // until disconnected
+    pub fn add_manual_addr(&mut self, addr: Multiaddr) {
+        self.manual_addrs.insert(addr);
+    }
+
+    // takes `peer` out of gossip; mdns or the peer itself may bring it back later
+    pub fn forget_peer(&mut self, peer: &PeerId) {
+        self.ping_failures.remove(peer);
+        self.last_active.remove(peer);
+        if let Some(addr) = self.manual_peers.remove(peer) {
+            self.manual_addrs.remove(&addr);
+        }
+        self.gossipsub.remove_explicit_peer(peer);
+        if self.known_peers.remove(peer) && self.watch_peers {
+            println!("peer left: {} ({} peers)", peer, self.known_peers.len());
+        }
+    }
+
+    pub fn handle_balance(&self, account: &str) {
+        let balance = self.app.balances().get(account).copied().unwrap_or(0);
+        println!("{}: {}", account, balance);
+    }
+
+    pub fn handle_status(&self) {
+        print!("{}", self.status());
+    }
+
+    // height, tip, difficulty and peers, a line each
+    fn status(&self) -> String {
+        let tip = if self.app.light {
+            self.app.header_tip().map(|h| (h.id, &h.hash, h.timestamp))
+        } else {
+            self.app.tip().map(|b| (b.id, &b.hash, b.timestamp))
+        };
+        let mut status = match tip {
+            Some((id, hash, timestamp)) => format!(
+                "height: {}\ntip: {}\ntip timestamp: {}\n",
+                id, hash, timestamp
+            ),
+            None => String::from("height: no blocks yet\n"),
+        };
+        if self.app.light {
+            status.push_str("mode: light\n");
+        }
+        status.push_str(&format!("difficulty: {}\n", self.app.next_difficulty()));
+        status.push_str(&format!("peers: {}\n", self.gossipsub.all_peers().count()));
+        status.push_str(&format!("connections: {}\n", self.connections));
+        match self.app.last_reorg_depth {
+            Some(depth) => status.push_str(&format!("last reorg: {} blocks\n", depth)),
+            None => status.push_str("last reorg: none\n"),
+        }
+        status
+    }
+
+    pub fn handle_since(&self) {
+        let Some(age) = self.tip_age() else {
+            println!("no blocks yet");
+            return;
+        };
+        println!("last block {} ago", format_age(age));
+        if age > self.stale_after.as_secs() {
+            println!(
+                "warning: the tip is older than {}",
+                format_age(self.stale_after.as_secs())
+            );
+        }
+    }
+
+    // seconds since the tip was mined, by the local clock
+    fn tip_age(&self) -> Option<u64> {
+        let timestamp = if self.app.light {
+            self.app.header_tip().map(|h| h.timestamp)
+        } else {
+            self.app.tip().map(|b| b.timestamp)
+        }?;
+        // a tip ahead of the local clock counts as just mined
+        Some(self.app.clock.now().saturating_sub(timestamp).max(0) as u64)
+    }
+
+    // runs off the event loop, the result is printed when it is done
+    pub fn handle_hashrate(&self) {
+        println!("measuring hashrate for {}s", HASHRATE_SECS);
+        let hasher = self.app.hasher.clone();
+        task::spawn_blocking(move || {
+            let rate = blockchain::measure_hashrate(&*hasher, Duration::from_secs(HASHRATE_SECS));
+            println!("{:.0} hashes/s on one thread", rate);
+        });
+    }
+
+    pub fn handle_validate(&self) {
+        if self.app.light {
+            match self.app.is_header_chain_valid(&self.app.headers) {
+                Ok(()) => println!("headers are valid ({} headers)", self.app.headers.len()),
+                Err(i) => println!("headers are invalid at {}", self.app.headers[i].id),
+            }
+            return;
+        }
+        match self.app.is_chain_valid(&self.app.blocks) {
+            Ok(()) => println!("chain is valid ({} blocks)", self.app.len()),
+            Err(i) => println!("chain is invalid at block {}", self.app.blocks[i].id),
+        }
+    }
+
+    pub fn print_chain(&self) {
+        match serde_json::to_string_pretty(&self.app.blocks) {
+            Ok(json) => print!("{}", json),
+            Err(e) => error!("could not print chain: {}", e),
+        }
+    }
+
+    pub fn print_block(&self, id: u64) {
+        print!("{}", self.block_report(id));
+    }
+
+    // the block as pretty json and where it came from, or why it is not there
+    fn block_report(&self, id: u64) -> String {
+        let Some(block) = self.app.get(id) else {
+            return match (self.app.iter().next(), self.app.tip()) {
+                (Some(first), Some(tip)) => format!(
+                    "block {} not found, the chain holds {}..={}\n",
+                    id, first.id, tip.id
+                ),
+                _ => format!("block {} not found, the chain is empty\n", id),
+            };
+        };
+        let mut report = match serde_json::to_string_pretty(block) {
+            Ok(json) => json + "\n",
+            Err(e) => {
+                error!("could not print block: {}", e);
+                String::new()
+            }
+        };
+        if let Some(source) = self.app.sources.get(&block.hash) {
+            report += &format!("received from: {}\n", source);
+        }
+        report
+    }
+
+    pub fn handle_prune(&mut self, keep: usize) {
+        if self.app.light {
+            println!("light nodes keep no blocks to prune");
+            return;
+        }
+        match self.app.prune(keep) {
+            0 => println!("nothing to prune, the chain has {} blocks", self.app.len()),
+            dropped => {
+                println!("pruned {} blocks, kept {}", dropped, self.app.len());
+                self.persist_chain();
+            }
+        }
+    }
+
+    pub fn handle_export(&self, path: &Path, force: bool) {
+        match self.app.export_to_file(path, force) {
+            Ok(()) => println!("exported {} blocks to {}", self.app.len(), path.display()),
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists => {
+                println!("{} exists, add --force to overwrite", path.display())
+            }
+            Err(e) => println!("could not export to {}: {}", path.display(), e),
+        }
+    }
+
+    // validates a chain file like `import` does, without adopting it
+    pub fn handle_check(&self, path: &Path) {
+        print!("{}", self.check_report(path));
+    }
+
+    // validates a chain file against the local rules, leaving the local chain alone
+    fn check_report(&self, path: &Path) -> String {
+        let blocks = match App::import_from_file(path) {
+            Ok(blocks) => blocks,
+            Err(e) => return format!("could not read {}: {}\n", path.display(), e),
+        };
+        match self.app.is_chain_valid(&blocks) {
+            Ok(()) => format!("{} is valid ({} blocks)\n", path.display(), blocks.len()),
+            Err(i) => format!("{} is invalid at block {}\n", path.display(), blocks[i].id),
+        }
+    }
+
+    pub fn handle_import(&mut self, path: &Path) {
+        let blocks = match App::import_from_file(path) {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                println!("could not import {}: {}", path.display(), e);
+                return;
+            }
+        };
+        if let Err(i) = self.app.is_chain_valid(&blocks) {
+            println!(
+                "rejected {}: invalid at block {}",
+                path.display(),
+                blocks[i].id
+            );
+            return;
+        }
+        let tip = self.app.tip().map(|b| b.hash.clone());
+        match self.app.choose_chain(self.app.blocks.clone(), blocks) {
+            Some(blocks) if blocks.last().map(|b| &b.hash) != tip.as_ref() => {
+                let len = blocks.len();
+                self.adopt_chain(blocks, &path.display().to_string(), None);
+                println!("adopted {} blocks from {}", len, path.display());
+            }
+            _ => println!("kept the local chain, {} is not heavier", path.display()),
+        }
+    }
+
+    // replaces the local chain, reporting a reorg if local blocks are dropped; the new
+    // blocks are attributed to `source` when they came from a peer
+    fn adopt_chain(&mut self, blocks: Vec<Block>, origin: &str, source: Option<PeerId>) {
+        let fork = blockchain::fork_point(&self.app.blocks, &blocks);
+        let dropped = self.app.len() - fork;
+        if dropped > 0 {
+            println!(
+                "reorg: dropped {} blocks, adopted {} blocks from {}",
+                dropped,
+                blocks.len() - fork,
+                origin
+            );
+            self.app.last_reorg_depth = Some(dropped);
+        }
+        // a chain from genesis replaces whatever was pruned
+        if blocks.first().is_some_and(|b| b.id == 0) {
+            self.app.snapshot = None;
+        }
+        if let Some(source) = source {
+            for block in &blocks[fork..] {
+                self.app
+                    .sources
+                    .insert(block.hash.clone(), source.to_string());
+            }
+        }
+        self.app.blocks = blocks;
+        self.app.retain_sources();
+        self.app.prune_pending();
+        self.persist_chain();
+    }
+
+    // valid responses raise the sender's score, anything else lowers it
+    fn handle_chain_response(&mut self, source: PeerId, mut blocks: Vec<Block>) {
+        if self.sync.as_ref().is_some_and(|sync| sync.peer == source) {
+            self.sync = None;
+        }
+        if blocks.first().is_some_and(|b| b.id > 0) {
+            match self.app.complete_chain(&blocks) {
+                Some(full) => blocks = full,
+                None => return self.request_older(source, blocks),
+            }
+        }
+        if let Err(e) = self.app.check_remote_chain(&blocks) {
+            warn!("rejecting chain from {}: {}", source, e);
+            self.score_peer(&source, -1);
+            return;
+        }
+        blocks.iter_mut().for_each(Block::migrate);
+        if self.app.light {
+            let headers: Vec<BlockHeader> = blocks.iter().map(BlockHeader::from).collect();
+            if self.app.is_header_chain_valid(&headers).is_err() {
+                warn!("rejecting invalid headers from {}", source);
+                self.score_peer(&source, -1);
+                return;
+            }
+            self.score_peer(&source, 1);
+            if self.app.choose_headers(headers) {
+                info!("adopted {} headers from {}", self.app.headers.len(), source);
             }
+            return;
+        }
+        if self.app.is_chain_valid(&blocks).is_err() {
+            warn!("rejecting invalid chain from {}", source);
+            self.score_peer(&source, -1);
+            return;
+        }
+        self.score_peer(&source, 1);
+        match self.app.choose_chain(self.app.blocks.clone(), blocks) {
+            Some(blocks) => self.adopt_chain(blocks, &source.to_string(), Some(source)),
+            None => warn!("local and remote chains are invalid, keeping local"),
         }
     }
+
+    fn score_peer(&mut self, peer: &PeerId, delta: i32) {
+        let score = self.peer_scores.entry(peer.to_string()).or_insert(0);
+        *score += delta;
+        debug!("score of {} is now {}", peer, score);
+    }
+
+    // highest score wins, ties go to the last peer as before scoring existed
+    fn best_peer<'a>(&self, peers: impl Iterator<Item = &'a PeerId>) -> Option<PeerId> {
+        peers
+            .max_by_key(|peer| {
+                self.peer_scores
+                    .get(&peer.to_string())
+                    .copied()
+                    .unwrap_or(0)
+            })
+            .copied()
+    }
+
+    pub fn handle_init(&mut self) {
+        let peers = self.get_list_peers();
+        if self.app.is_empty() && self.app.headers.is_empty() {
+            self.app.genesis();
+            self.persist_chain();
+        }
+
+        info!("connected nodes: {}", peers.len());
+        if self.mine_on_start > 0 && self.app.light {
+            warn!("light nodes cannot mine, ignoring --mine-on-start");
+        } else if self.mine_on_start > 0 && !self.app.consensus.can_seal() {
+            warn!("this node is not an authority, ignoring --mine-on-start");
+        } else {
+            self.auto_mine_left = self.mine_on_start;
+        }
+
+        let peer = self.best_peer(peers.keys());
+        if let Some(peer) = peer {
+            self.request_chain(peer);
+        }
+        self.init_hosted(peer);
+    }
+
+    // drops everything but genesis and asks a peer for its chain
+    pub fn handle_resync(&mut self) {
+        let peers = self.get_list_peers();
+        let Some(peer) = self.best_peer(peers.keys()) else {
+            println!("no peers connected, cannot resync");
+            return;
+        };
+        if self.cancel_mining() {
+            info!("aborted the mine in progress");
+        }
+        self.app.blocks.clear();
+        self.app.headers.clear();
+        self.app.snapshot = None;
+        self.app.genesis();
+        self.persist_chain();
+        println!("chain reset to genesis, resyncing from {}", peer);
+        self.request_chain(peer);
+    }
+
+    // pushes the local chain to every peer without waiting to be asked
+    pub fn handle_broadcast(&mut self) {
+        if self.app.light {
+            println!("light nodes have no blocks to broadcast");
+            return;
+        } else if self.app.snapshot.is_some() {
+            println!("the chain is pruned, peers would reject it");
+            return;
+        }
+        let resp = ChainResponse {
+            blocks: newest_blocks(&self.app.blocks, self.max_response_blocks),
+            receiver: BROADCAST_RECEIVER.to_string(),
+        };
+        let len = resp.blocks.len();
+        self.publish_chain(None, resp);
+        println!("broadcast {} blocks", len);
+    }
+
+    fn request_chain(&mut self, peer: PeerId) {
+        self.sync = Some(SyncRequest {
+            peer,
+            deadline: Instant::now() + self.sync_timeout,
+            attempts: 1,
+            tried: HashSet::from([peer]),
+        });
+        self.send_chain_request(peer);
+    }
+
+    pub fn handle_tick(&mut self) {
+        self.broadcast_mined_blocks();
+        self.check_sync_timeout();
+        self.auto_mine();
+    }
+
+    // mines the next `mine_on_start` block once sync is done, starting blocks a target
+    // interval apart so retargeting sees a steady pace
+    fn auto_mine(&mut self) {
+        if self.auto_mine_left == 0 || self.mining.is_some() || self.sync.is_some() {
+            return;
+        }
+        let spacing = Duration::from_secs(self.app.target_block_interval as u64);
+        if self.last_block_time.is_some_and(|t| t.elapsed() < spacing) {
+            return;
+        }
+        let data = format!(
+            "auto block {}",
+            self.mine_on_start - self.auto_mine_left + 1
+        );
+        if self.handle_create_block(&data).is_some() {
+            self.auto_mine_left -= 1;
+        }
+    }
+
+    // retries an unanswered chain request with another peer, preferring untried ones
+    fn check_sync_timeout(&mut self) {
+        let Some(mut sync) = self.sync.take() else {
+            return;
+        };
+        if Instant::now() < sync.deadline {
+            self.sync = Some(sync);
+            return;
+        }
+        warn!("no chain response from {}", sync.peer);
+        self.score_peer(&sync.peer, -1);
+        if sync.attempts >= self.max_sync_attempts.get() {
+            warn!("giving up on chain sync after {} attempts", sync.attempts);
+            return;
+        }
+        let peers = self.get_list_peers();
+        let untried = self.best_peer(peers.keys().filter(|p| !sync.tried.contains(p)));
+        let Some(peer) = untried.or_else(|| self.best_peer(peers.keys())) else {
+            warn!("no peers left to sync from, giving up");
+            return;
+        };
+        sync.attempts += 1;
+        sync.peer = peer;
+        sync.deadline = Instant::now() + self.sync_timeout;
+        sync.tried.insert(peer);
+        info!(
+            "retrying chain sync with {} (attempt {})",
+            peer, sync.attempts
+        );
+        self.sync = Some(sync);
+        self.send_chain_request(peer);
+    }
+
+    fn send_chain_request(&mut self, peer: PeerId) {
+        let req = LocalChainRequest {
+            from_peer_id: peer.to_string(),
+        };
+        self.publish(self.chain_topic.clone(), Message::ChainRequest(req));
+    }
+
+    fn accept_block(&mut self, source: PeerId, block: Block) -> bool {
+        #[cfg(feature = "metrics")]
+        crate::metrics::BLOCKS_RECEIVED.inc();
+        match self.app.try_add_bock(block, Some(&source.to_string())) {
+            Ok(()) => {
+                let block = self.app.tip().expect("block was just added");
+                let hash = block.hash.clone();
+                self.persist_block(block);
+                self.connect_orphans(&hash);
+                true
+            }
+            Err(e) => {
+                #[cfg(feature = "metrics")]
+                crate::metrics::BLOCKS_REJECTED.inc();
+                warn!("dropping invalid block: {}", e);
+                false
+            }
+        }
+    }
+
+    // asks for the page before a truncated chain, until it reaches the local chain or genesis
+    fn request_older(&mut self, peer: PeerId, partial: Vec<Block>) {
+        let first_id = partial[0].id;
+        let start_id = first_id.saturating_sub(self.max_response_blocks.get() as u64);
+        let height = partial[partial.len() - 1].id;
+        self.request_range(peer, start_id, first_id - 1);
+        self.partial_chain = Some(PartialChain {
+            peer,
+            blocks: partial,
+            range: (start_id, first_id - 1),
+            height,
+        });
+    }
+
+    // joins the page to the blocks after it; a page cut short to fit in one message is
+    // asked for again, as many blocks as came ending next to the held ones
+    fn handle_older_page(&mut self, mut partial: PartialChain, page: Vec<Block>) {
+        let (start_id, end_id) = partial.range;
+        if page.len() as u64 > end_id - start_id + 1
+            || page
+                .iter()
+                .zip(start_id..)
+                .any(|(block, id)| block.id != id)
+        {
+            warn!(
+                "dropping a page that is not the one asked of {}",
+                partial.peer
+            );
+            return;
+        }
+        let first_id = partial.blocks[0].id;
+        let last_id = page[page.len() - 1].id;
+        if last_id + 1 < first_id {
+            let start_id = first_id.saturating_sub(page.len() as u64);
+            self.request_range(partial.peer, start_id, first_id - 1);
+            partial.range = (start_id, first_id - 1);
+            self.partial_chain = Some(partial);
+            return;
+        }
+        let mut blocks = page;
+        blocks.extend(partial.blocks);
+        if blocks.len() as u64 > partial.height + 1 {
+            warn!(
+                "dropping a chain from {} longer than its height",
+                partial.peer
+            );
+            return;
+        }
+        self.handle_chain_response(partial.peer, blocks);
+    }
+
+    fn request_range(&mut self, peer: PeerId, start_id: u64, end_id: u64) {
+        info!("missing blocks {}..={}, asking {}", start_id, end_id, peer);
+        let req = RangeRequest {
+            from_peer_id: peer.to_string(),
+            start_id,
+            end_id,
+        };
+        self.publish(self.range_topic.clone(), Message::RangeRequest(req));
+    }
+
+    fn handle_range_message(&mut self, source: PeerId, msg: Message) {
+        match msg {
+            Message::RangeResponse(resp) if is_local_peer(&self.peer_id, &resp.receiver) => {
+                info!("received {} blocks from {}", resp.blocks.len(), source);
+                // anything but the page asked for is added on the tip like any other range
+                let older = self.partial_chain.take_if(|partial| {
+                    partial.peer == source
+                        && resp.blocks.first().is_some_and(|b| b.id == partial.range.0)
+                });
+                if let Some(partial) = older {
+                    return self.handle_older_page(partial, resp.blocks);
+                }
+                for mut block in resp.blocks {
+                    if !block.is_supported() {
+                        warn!(
+                            "skipping block {} of unsupported version {}",
+                            block.id, block.version
+                        );
+                        break;
+                    }
+                    block.migrate();
+                    // an orphan this range delivered the parent of may already be added
+                    if self.app.get(block.id).is_some_and(|b| b.hash == block.hash) {
+                        continue;
+                    }
+                    if !self.accept_block(source, block) {
+                        break;
+                    }
+                }
+            }
+            Message::RangeRequest(req) if is_local_peer(&self.peer_id, &req.from_peer_id) => {
+                info!(
+                    "sending blocks {}..={} to {}",
+                    req.start_id, req.end_id, source
+                );
+                let blocks = (req.start_id..=req.end_id).map_while(|id| self.app.get(id));
+                let fit = blocks_that_fit(blocks.clone(), self.max_response_blocks);
+                let resp = RangeResponse {
+                    blocks: blocks.take(fit).cloned().collect(),
+                    receiver: source.to_string(),
+                };
+                self.publish(self.range_topic.clone(), Message::RangeResponse(resp));
+            }
+            Message::RangeResponse(_) | Message::RangeRequest(_) => {}
+            _ => warn!(
+                "dropping unexpected message on the range topic from {}",
+                source
+            ),
+        }
+    }
+
+    pub fn handle_shutdown(&mut self) {
+        self.cancel_mining();
+        self.broadcast_mined_blocks();
+        info!(
+            "shutting down, saving chain to {}",
+            self.chain_file.display()
+        );
+        for topic in [&self.chain_topic, &self.block_topic, &self.range_topic] {
+            if let Err(e) = self.gossipsub.unsubscribe(topic) {
+                warn!("could not unsubscribe from {}: {:?}", topic, e);
+            }
+        }
+        self.persist_chain();
+        for (_, chain) in self.registry.iter() {
+            save_hosted(chain);
+        }
+    }
+
+    // keeps a chain for `network` next to the node's own, see `registry`
+    pub fn host_chain(&mut self, network: &str, app: App, chain_file: PathBuf) {
+        let chain = HostedChain {
+            app,
+            chain_file,
+            chain_topic: topic(Some(network), "chains"),
+            block_topic: topic(Some(network), "blocks"),
+            orphans: OrphanPool::default(),
+        };
+        for topic in [&chain.chain_topic, &chain.block_topic] {
+            self.gossipsub
+                .subscribe(topic)
+                .unwrap_or_else(|e| panic!("can subscribe to {}: {:?}", topic, e));
+        }
+        self.registry.insert(network.to_string(), chain);
+    }
+
+    // starts every hosted chain from genesis if needed and asks `peer` for its version
+    fn init_hosted(&mut self, peer: Option<PeerId>) {
+        let mut requests = Vec::new();
+        for network in self
+            .registry
+            .iter()
+            .map(|(n, _)| n.clone())
+            .collect::<Vec<_>>()
+        {
+            let chain = self.registry.get_mut(&network).expect("network is hosted");
+            if chain.app.is_empty() {
+                chain.app.genesis();
+                save_hosted(chain);
+            }
+            if let Some(peer) = peer {
+                requests.push(chain.chain_topic.clone());
+                info!("asking {} for its {} chain", peer, network);
+            }
+        }
+        if let Some(peer) = peer {
+            for topic in requests {
+                let req = LocalChainRequest {
+                    from_peer_id: peer.to_string(),
+                };
+                self.publish(topic, Message::ChainRequest(req));
+            }
+        }
+    }
+
+    // a message on one of the hosted networks' topics
+    fn handle_hosted_message(&mut self, network: &str, source: PeerId, msg: Message) {
+        let Some(chain) = self.registry.get_mut(network) else {
+            return;
+        };
+        match msg {
+            Message::ChainResponse(resp) => {
+                if resp.receiver != BROADCAST_RECEIVER
+                    && !is_local_peer(&self.peer_id, &resp.receiver)
+                {
+                    return;
+                }
+                let mut blocks = resp.blocks;
+                // without range requests a capped response only completes a chain that
+                // already holds the blocks before it
+                if blocks.first().is_some_and(|b| b.id > 0) {
+                    match chain.app.complete_chain(&blocks) {
+                        Some(full) => blocks = full,
+                        None => {
+                            return warn!(
+                                "dropping partial {} chain from {}, it does not link up",
+                                network, source
+                            )
+                        }
+                    }
+                }
+                if let Err(e) = chain.app.check_remote_chain(&blocks) {
+                    warn!("rejecting {} chain from {}: {}", network, source, e);
+                    return;
+                }
+                blocks.iter_mut().for_each(Block::migrate);
+                let local_tip = chain.app.tip().map(|b| b.hash.clone());
+                if let Some(blocks) = chain.app.choose_chain(chain.app.blocks.clone(), blocks) {
+                    if blocks.last().map(|b| &b.hash) != local_tip.as_ref() {
+                        info!(
+                            "adopted {} blocks on {} from {}",
+                            blocks.len(),
+                            network,
+                            source
+                        );
+                        chain.app.blocks = blocks;
+                        chain.app.retain_sources();
+                        save_hosted(chain);
+                    }
+                }
+            }
+            Message::ChainRequest(req) => {
+                if is_local_peer(&self.peer_id, &req.from_peer_id) {
+                    info!("sending {} chain to {}", network, source);
+                    let blocks = newest_blocks(&chain.app.blocks, self.max_response_blocks);
+                    self.queue_chain_response(Some(network), blocks, source);
+                }
+            }
+            Message::Block(mut block) => {
+                if !block.is_supported() {
+                    warn!(
+                        "skipping {} block {} of unsupported version",
+                        network, block.id
+                    );
+                    return;
+                }
+                block.migrate();
+                match chain.app.tip().map(|b| b.id) {
+                    Some(tip_id) if block.id == tip_id + 1 => {
+                        match chain.app.try_add_bock(block, Some(&source.to_string())) {
+                            Ok(()) => {
+                                let block = chain.app.tip().expect("block was just added");
+                                info!("added {} block {} from {}", network, block.id, source);
+                                let hash = block.hash.clone();
+                                append_hosted(chain, 1);
+                                let added = chain.orphans.connect(&mut chain.app, &hash);
+                                append_hosted(chain, added);
+                            }
+                            Err(e) => warn!("dropping invalid {} block: {}", network, e),
+                        }
+                    }
+                    Some(tip_id) if block.id > tip_id + 1 => {
+                        info!(
+                            "missing {} blocks before {}, asking {}",
+                            network, block.id, source
+                        );
+                        let req = LocalChainRequest {
+                            from_peer_id: source.to_string(),
+                        };
+                        let topic = chain.chain_topic.clone();
+                        chain.orphans.insert(source, block);
+                        self.publish(topic, Message::ChainRequest(req));
+                    }
+                    _ => debug!(
+                        "ignoring stale {} block {} from {}",
+                        network, block.id, source
+                    ),
+                }
+            }
+            _ => warn!("dropping unexpected {} message from {}", network, source),
+        }
+    }
+
+    // the subset of commands that makes sense on a hosted chain, given as `@network ...`
+    pub fn handle_hosted_command(&mut self, network: &str, command: Command) {
+        let Some(chain) = self.registry.get_mut(network) else {
+            println!(
+                "{} is not hosted, start the node with --host-network {}",
+                network, network
+            );
+            return;
+        };
+        match command {
+            Command::ListChain => match serde_json::to_string_pretty(&chain.app.blocks) {
+                Ok(json) => print!("{}", json),
+                Err(e) => error!("could not print chain: {}", e),
+            },
+            Command::ShowBlock(id) => match chain.app.get(id).map(serde_json::to_string_pretty) {
+                Some(Ok(json)) => println!("{}", json),
+                Some(Err(e)) => error!("could not print block: {}", e),
+                None => println!("block {} not found on {}", id, network),
+            },
+            Command::Status => {
+                match chain.app.tip() {
+                    Some(tip) => println!("height: {}\ntip: {}", tip.id, tip.hash),
+                    None => println!("height: no blocks yet"),
+                }
+                println!("difficulty: {}", chain.app.next_difficulty());
+            }
+            Command::Validate => match chain.app.is_chain_valid(&chain.app.blocks) {
+                Ok(()) => println!("chain is valid ({} blocks)", chain.app.len()),
+                Err(i) => println!("chain is invalid at block {}", chain.app.blocks[i].id),
+            },
+            Command::CreateBlock(data) => self.mine_hosted(network, data),
+            Command::Mine => self.mine_hosted(network, String::new()),
+            _ => println!(
+                "only ls c, block, create b, mine, status and validate run on hosted networks"
+            ),
+        }
+    }
+
+    // hosted chains mine in the background like the node's own, the block comes back
+    // through `handle_mined_hosted`
+    fn mine_hosted(&mut self, network: &str, data: String) {
+        let Some(chain) = self.registry.get_mut(network) else {
+            return;
+        };
+        let task = match chain.app.mine_task(data) {
+            Ok(task) => task,
+            Err(MineError::Uninitialised) => {
+                return println!("{} is not initialised yet, try again shortly", network)
+            }
+            Err(e) => return println!("{}", e),
+        };
+        let id = self.start_mining(Some(network), task);
+        println!("mining block {} on {}", id, network);
+    }
+
+    fn handle_mined_hosted(&mut self, network: &str, result: Result<Block, MineError>) {
+        let Some(chain) = self.registry.get_mut(network) else {
+            return;
+        };
+        let block = match result {
+            Ok(block) => block,
+            Err(e) => return println!("could not mine on {}: {}", network, e),
+        };
+        if let Err(e) = chain.app.try_add_bock(block.clone(), None) {
+            return warn!("dropping stale mined {} block {}: {}", network, block.id, e);
+        }
+        append_hosted(chain, 1);
+        let added = chain.orphans.connect(&mut chain.app, &block.hash);
+        append_hosted(chain, added);
+        println!("mined block {} on {}", block.id, network);
+        let topic = chain.block_topic.clone();
+        self.publish(topic, Message::Block(block));
+    }
+
+    // answers with at most `max_response_blocks` of the newest blocks, older ones are
+    // only sent through range requests
+    fn handle_chain_request(&mut self, source: PeerId, req: LocalChainRequest) {
+        info!("sending local chain to {}", source);
+        // light and pruned nodes have no full chain to offer, the requester retries elsewhere
+        if is_local_peer(&self.peer_id, &req.from_peer_id)
+            && !self.app.light
+            && self.app.snapshot.is_none()
+        {
+            let blocks = newest_blocks(&self.app.blocks, self.max_response_blocks);
+            self.queue_chain_response(None, blocks, source);
+        }
+    }
+
+    fn handle_gossip_block(&mut self, source: PeerId, mut block: Block) {
+        if !block.is_supported() {
+            warn!(
+                "skipping block {} of unsupported version {} from {}",
+                block.id, block.version, source
+            );
+            return;
+        }
+        block.migrate();
+        if self.app.light {
+            self.handle_light_block(source, &block);
+            return;
+        }
+        if self.app.iter().any(|b| b.hash == block.hash) {
+            debug!("ignoring duplicate block {}", block.hash);
+            return;
+        }
+        match self.app.tip().map(|b| b.id) {
+            Some(tip_id) if block.id <= tip_id => {
+                debug!("ignoring stale block {} from {}", block.id, source)
+            }
+            Some(tip_id) if block.id > tip_id + 1 => {
+                info!("received new block from {}", source);
+                self.request_range(source, tip_id + 1, block.id);
+                self.orphans.insert(source, block);
+            }
+            Some(_) => {
+                info!("received new block from {}", source);
+                self.accept_block(source, block);
+            }
+            None => warn!("chain is not initialised, dropping block {}", block.id),
+        }
+    }
+
+    // a batch that starts past the tip is fetched whole with one range request, instead
+    // of one per block
+    fn handle_block_batch(&mut self, source: PeerId, blocks: Vec<Block>) {
+        info!("received {} blocks from {}", blocks.len(), source);
+        let tip_id = self.app.tip().map(|b| b.id);
+        if let (false, Some(tip_id), Some(first), Some(last)) =
+            (self.app.light, tip_id, blocks.first(), blocks.last())
+        {
+            if first.id > tip_id + 1 {
+                self.request_range(source, tip_id + 1, last.id);
+                return;
+            }
+        }
+        for block in blocks {
+            self.handle_gossip_block(source, block);
+        }
+    }
+
+    fn handle_light_block(&mut self, source: PeerId, block: &Block) {
+        let header = BlockHeader::from(block);
+        match self.app.height() {
+            Some(tip_id) if header.id <= tip_id => {
+                debug!("ignoring stale header {} from {}", header.id, source)
+            }
+            Some(tip_id) if header.id > tip_id + 1 => {
+                info!("missing headers before {}, asking {}", header.id, source);
+                self.request_chain(source);
+            }
+            Some(_) => match self.app.try_add_header(header) {
+                Ok(()) => info!("added header {} from {}", block.id, source),
+                Err(e) => warn!("rejected header {} from {}: {}", block.id, source, e),
+            },
+            None => warn!("headers are not initialised, dropping {}", header.id),
+        }
+    }
+
+    // mdns reports a peer once per address, so it may repeat
+    fn peers_discovered(&mut self, peers: impl Iterator<Item = PeerId>) {
+        for peer in peers {
+            if self.known_peers.insert(peer) && self.watch_peers {
+                println!("peer joined: {} ({} peers)", peer, self.known_peers.len());
+            }
+            self.add_gossip_peer(peer);
+        }
+    }
+
+    fn peers_expired(&mut self, peers: impl Iterator<Item = PeerId>) {
+        for peer in peers {
+            let rediscovered = self.mdns.as_ref().is_some_and(|m| m.has_node(&peer));
+            if !rediscovered && !self.manual_peers.contains_key(&peer) {
+                self.gossipsub.remove_explicit_peer(&peer);
+                self.last_active.remove(&peer);
+                if self.known_peers.remove(&peer) && self.watch_peers {
+                    println!("peer left: {} ({} peers)", peer, self.known_peers.len());
+                }
+            }
+        }
+    }
+
+    // light nodes leave the chain file alone, it holds full blocks
+    fn persist_block(&self, block: &Block) {
+        if self.app.light {
+            return;
+        }
+        if let Err(e) = block.append_to_file(&self.chain_file) {
+            error!("could not persist block: {}", e);
+        }
+    }
+
+    fn persist_chain(&self) {
+        if self.app.light {
+            return;
+        }
+        if let Err(e) = self.app.save_to_file(&self.chain_file) {
+            error!("could not persist chain: {}", e);
+        }
+        let snapshot_file = self.chain_file.with_file_name(blockchain::SNAPSHOT_FILE);
+        let saved = match &self.app.snapshot {
+            Some(snapshot) => snapshot.save_to_file(&snapshot_file),
+            None => match fs::remove_file(&snapshot_file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+        };
+        if let Err(e) = saved {
+            error!("could not persist snapshot: {}", e);
+        }
+    }
+
+    fn discovered_nodes(&self) -> impl Iterator<Item = &PeerId> {
+        self.mdns
+            .as_ref()
+            .into_iter()
+            .flat_map(|mdns| mdns.discovered_nodes())
+    }
+
+    // discovered and manually added peers with their deduplicated addresses
+    fn get_list_peers(&mut self) -> BTreeMap<PeerId, BTreeSet<Multiaddr>> {
+        debug!("Discover peers");
+        let peers: BTreeSet<PeerId> = self.discovered_nodes().copied().collect();
+        let discovered: Vec<(PeerId, Multiaddr)> = peers
+            .into_iter()
+            .flat_map(|peer| {
+                let addrs = self.mdns.addresses_of_peer(&peer);
+                addrs.into_iter().map(move |addr| (peer, addr))
+            })
+            .collect();
+        let manual = self
+            .manual_peers
+            .iter()
+            .map(|(peer, addr)| (*peer, addr.clone()));
+        let mut list = group_addrs(discovered.into_iter().chain(manual));
+        // without mdns, bootstrap peers would otherwise never be synced from
+        if !self.mdns.is_enabled() {
+            for (peer, _) in self.gossipsub.all_peers() {
+                list.entry(*peer).or_default();
+            }
+        }
+        list
+    }
+
+    #[cfg(feature = "http")]
+    pub fn peer_ids(&self) -> Vec<String> {
+        let nodes = self.discovered_nodes().chain(self.manual_peers.keys());
+        let mut unique_peers = HashSet::new();
+        for peer in nodes {
+            unique_peers.insert(peer);
+        }
+        unique_peers.iter().map(|p| p.to_string()).collect()
+    }
+
+    pub fn handle_print_peers(&mut self) {
+        let peers = self.get_list_peers();
+        peers.keys().for_each(|p| println!("{}", p));
+    }
+
+    pub fn handle_print_peer_details(&mut self) {
+        for (peer, addrs) in self.get_list_peers() {
+            println!("{}", peer);
+            addrs.iter().for_each(|addr| println!("  {}", addr));
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<MdnsEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: MdnsEvent) {
+        match event {
+            MdnsEvent::Discovered(list) => self.peers_discovered(list.map(|(peer, _)| peer)),
+            MdnsEvent::Expired(list) => self.peers_expired(list.map(|(peer, _)| peer)),
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<PingEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: PingEvent) {
+        let peer = event.peer;
+        match event.result {
+            Ok(PingSuccess::Ping { rtt }) => {
+                debug!("ping to {} took {:?}", peer, rtt);
+                self.ping_failures.remove(&peer);
+            }
+            Ok(PingSuccess::Pong) => {}
+            Err(e) => {
+                let failures = self.ping_failures.entry(peer).or_insert(0);
+                *failures += 1;
+                debug!("ping to {} failed ({} in a row): {}", peer, failures, e);
+                if *failures >= self.max_ping_failures.get() {
+                    warn!("{} missed {} pings, dropping it", peer, failures);
+                    self.forget_peer(&peer);
+                }
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<GossipsubEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        if let GossipsubEvent::Message {
+            propagation_source,
+            message: msg,
+            ..
+        } = event
+        {
+            let source = msg.source.unwrap_or(propagation_source);
+            if let Some(at) = self.last_active.get_mut(&propagation_source) {
+                *at = Instant::now();
+            }
+            #[cfg(feature = "compression")]
+            let inflated = match crate::compression::decompress(&msg.data) {
+                Ok(inflated) => inflated,
+                Err(e) => return warn!("dropping undecodable message from {}: {}", source, e),
+            };
+            #[cfg(feature = "compression")]
+            let data: &[u8] = &inflated;
+            #[cfg(not(feature = "compression"))]
+            let data = msg.data.as_slice();
+            let message = match serde_json::from_slice::<Message>(data) {
+                Ok(message) => message,
+                Err(_) => return warn!("dropping malformed message from {}", source),
+            };
+            if let Some(network) = self.registry.network_for(&msg.topic) {
+                return self.handle_hosted_message(&network, source, message);
+            } else if msg.topic == self.range_topic.hash() {
+                return self.handle_range_message(source, message);
+            }
+            match message {
+                Message::ChainResponse(resp) => {
+                    if resp.receiver == BROADCAST_RECEIVER
+                        || is_local_peer(&self.peer_id, &resp.receiver)
+                    {
+                        info!("response from {}:", source);
+                        resp.blocks.iter().for_each(|r| debug!("{:?}", r));
+                        self.handle_chain_response(source, resp.blocks);
+                    }
+                }
+                Message::ChainRequest(req) => self.handle_chain_request(source, req),
+                Message::Block(block) => self.handle_gossip_block(source, block),
+                Message::BlockBatch(batch) => self.handle_block_batch(source, batch.blocks),
+                _ => warn!("dropping unexpected message from {}", source),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod harness;
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{futures::StreamExt, swarm::SwarmBuilder};
+    use tempfile::TempDir;
+
+    use super::{
+        harness::{drive_for, drive_until, TestNode},
+        *,
+    };
+    use crate::consensus::ProofOfAuthority;
+
+    // a node that never connects, on a cheap chain kept in a temporary directory
+    struct Node {
+        behaviour: AppBehaviour,
+        responses: mpsc::Receiver<(Option<String>, ChainResponse)>,
+        mined: mpsc::Receiver<MineOutcome>,
+        dir: TempDir,
+    }
+
+    async fn test_node() -> Node {
+        let dir = tempfile::tempdir().expect("can create a temp dir");
+        let mut app = App::new();
+        app.difficulty = 1;
+        app.miner = "miner".to_string();
+        app.genesis();
+        let (response_sender, responses) = mpsc::channel(CHANNEL_CAPACITY);
+        let (mined_sender, mined) = mpsc::channel(CHANNEL_CAPACITY);
+        let chain_file = dir.path().join("chain.jsonl");
+        let behaviour = AppBehaviour::new(
+            identity::Keypair::generate_ed25519(),
+            app,
+            response_sender,
+            chain_file,
+            mined_sender,
+            None,
+            false,
+        )
+        .await;
+        Node {
+            behaviour,
+            responses,
+            mined,
+            dir,
+        }
+    }
+
+    #[tokio::test]
+    async fn block_mined_on_one_node_is_appended_by_the_other() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        a.connect(&mut b).await;
+        assert!(a.behaviour_mut().handle_create_block("from a").is_some());
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[0].behaviour().app.len() == 2 && nodes[1].tip_hash() == nodes[0].tip_hash()
+        })
+        .await;
+        let tip = b.behaviour().app.tip().expect("has blocks");
+        assert_eq!(tip.data, "from a");
+        assert_eq!(tip.miner, a.peer_id().to_string());
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_peer_id() {
+        let peer = |seed| keypair_from_seed(seed).public().into_peer_id();
+        assert_eq!(peer(7), peer(7));
+        assert_ne!(peer(7), peer(8));
+    }
+
+    #[tokio::test]
+    async fn hosted_chains_mine_in_the_background() {
+        let mut node = test_node().await;
+        let mut side = App::new();
+        side.difficulty = 1;
+        side.genesis();
+        let side_file = node.dir.path().join("side.jsonl");
+        node.behaviour.host_chain("side", side, side_file.clone());
+        node.behaviour
+            .handle_hosted_command("side", Command::CreateBlock("hosted".to_string()));
+        assert!(node.behaviour.mining.is_some());
+        let outcome = node.mined.recv().await.expect("the job reports");
+        node.behaviour.handle_mined_block(outcome);
+        assert!(node.behaviour.mining.is_none());
+        let chain = node.behaviour.registry.get_mut("side").expect("is hosted");
+        assert_eq!(chain.app.tip().map(|b| b.data.as_str()), Some("hosted"));
+        assert!(side_file.exists());
+        assert_eq!(node.behaviour.app.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn hosted_chain_responses_are_capped_and_queued() {
+        let mut node = test_node().await;
+        let mut side = App::new();
+        side.difficulty = 1;
+        side.genesis();
+        let mut behind = App::new();
+        behind.difficulty = 1;
+        behind.genesis();
+        side.add_mined_block("one".to_string()).expect("mines");
+        side.add_mined_block("two".to_string()).expect("mines");
+        let side_file = node.dir.path().join("side.jsonl");
+        node.behaviour.host_chain("side", side, side_file);
+        node.behaviour.max_response_blocks = NonZeroUsize::new(2).expect("is not zero");
+        let requester = PeerId::random();
+        let req = LocalChainRequest {
+            from_peer_id: node.behaviour.peer_id.to_string(),
+        };
+        node.behaviour
+            .handle_hosted_message("side", requester, Message::ChainRequest(req));
+        let (network, resp) = node.responses.try_recv().expect("is queued");
+        assert_eq!(network.as_deref(), Some("side"));
+        assert_eq!(resp.receiver, requester.to_string());
+        let ids: Vec<u64> = resp.blocks.iter().map(|b| b.id).collect();
+        assert_eq!(ids, [1, 2]);
+        // a node holding genesis completes the capped response
+        let mut other = test_node().await;
+        let other_file = other.dir.path().join("side.jsonl");
+        other.behaviour.host_chain("side", behind, other_file);
+        let resp = ChainResponse {
+            blocks: resp.blocks,
+            receiver: other.behaviour.peer_id.to_string(),
+        };
+        other.behaviour.handle_hosted_message(
+            "side",
+            PeerId::random(),
+            Message::ChainResponse(resp),
+        );
+        let chain = other.behaviour.registry.get("side").expect("is hosted");
+        assert_eq!(chain.app.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn non_authority_refuses_to_mine() {
+        let mut node = test_node().await;
+        let authority = identity::Keypair::generate_ed25519()
+            .public()
+            .into_peer_id();
+        node.behaviour.app.consensus = Arc::new(ProofOfAuthority::new(
+            identity::Keypair::generate_ed25519(),
+            [authority],
+        ));
+        assert_eq!(node.behaviour.handle_create_block("refused"), None);
+        assert!(node.behaviour.mining.is_none());
+        let (reply, response) = oneshot::channel();
+        node.behaviour.handle_remote_mine(MineRequest {
+            data: "refused".to_string(),
+            reply,
+        });
+        assert_eq!(response.await.expect("is answered").map(|b| b.id), None);
+        node.behaviour.mine_on_start = 3;
+        node.behaviour.handle_init();
+        assert_eq!(node.behaviour.auto_mine_left, 0);
+    }
+
+    #[tokio::test]
+    async fn remote_mine_is_answered_with_the_block() {
+        let mut node = test_node().await;
+        let (reply, response) = oneshot::channel();
+        node.behaviour.handle_remote_mine(MineRequest {
+            data: "remote".to_string(),
+            reply,
+        });
+        let outcome = node.mined.recv().await.expect("the job reports");
+        node.behaviour.handle_mined_block(outcome);
+        let block = response.await.expect("is answered").expect("was mined");
+        assert_eq!(block.data, "remote");
+        assert_eq!(node.behaviour.app.tip().map(|b| &b.hash), Some(&block.hash));
+        assert!(node.behaviour.mining.is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_mine_frees_the_miner_and_answers() {
+        let mut node = test_node().await;
+        let (reply, response) = oneshot::channel();
+        node.behaviour.handle_remote_mine(MineRequest {
+            data: "remote".to_string(),
+            reply,
+        });
+        let (job, _) = node.behaviour.mining.clone().expect("is mining");
+        node.mined.recv().await.expect("the job reports");
+        node.behaviour.handle_mined_block(MineOutcome {
+            job,
+            network: None,
+            result: Err(MineError::Exhausted),
+        });
+        assert!(node.behaviour.mining.is_none());
+        assert_eq!(response.await.expect("is answered").map(|b| b.id), None);
+        assert_eq!(node.behaviour.app.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn outcome_of_a_cancelled_job_is_dropped() {
+        let mut node = test_node().await;
+        assert!(node.behaviour.handle_create_block("first").is_some());
+        let first = node.mined.recv().await.expect("the job reports");
+        node.behaviour.last_block_time = None;
+        assert!(node.behaviour.handle_create_block("second").is_some());
+        node.behaviour.handle_mined_block(first);
+        assert!(node.behaviour.mining.is_some());
+        assert_eq!(node.behaviour.app.len(), 1);
+        let second = node.mined.recv().await.expect("the job reports");
+        node.behaviour.handle_mined_block(second);
+        assert!(node.behaviour.mining.is_none());
+        assert_eq!(
+            node.behaviour.app.tip().map(|b| b.data.as_str()),
+            Some("second")
+        );
+    }
+
+    // answers pings so nodes keep the connection, and hands on every gossip message
+    #[derive(NetworkBehaviour)]
+    struct Subscriber {
+        gossipsub: Gossipsub,
+        ping: Ping,
+        #[behaviour(ignore)]
+        received: mpsc::UnboundedSender<Vec<u8>>,
+    }
+
+    impl NetworkBehaviourEventProcess<GossipsubEvent> for Subscriber {
+        fn inject_event(&mut self, event: GossipsubEvent) {
+            if let GossipsubEvent::Message { message, .. } = event {
+                let _ = self.received.send(message.data);
+            }
+        }
+    }
+
+    impl NetworkBehaviourEventProcess<PingEvent> for Subscriber {
+        fn inject_event(&mut self, _: PingEvent) {}
+    }
+
+    fn gossip(source: PeerId, seqno: u64, message: &Message) -> GossipsubMessage {
+        GossipsubMessage {
+            source: Some(source),
+            data: serde_json::to_vec(message).expect("can jsonify"),
+            sequence_number: Some(seqno),
+            topic: topic(None, "blocks").hash(),
+        }
+    }
+
+    #[test]
+    fn block_messages_are_identified_by_content() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut app = App::new();
+        app.genesis();
+        let block = Message::Block(app.tip().expect("has genesis").clone());
+        assert_eq!(
+            message_id(&gossip(a, 1, &block)),
+            message_id(&gossip(b, 2, &block))
+        );
+
+        let request = Message::ChainRequest(LocalChainRequest {
+            from_peer_id: a.to_string(),
+        });
+        assert_ne!(
+            message_id(&gossip(a, 1, &request)),
+            message_id(&gossip(a, 2, &request))
+        );
+    }
+
+    #[tokio::test]
+    async fn published_block_is_received_once() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        a.connect(&mut b).await;
+
+        // a bare gossipsub subscriber hearing the block from both a and b
+        let keys = identity::Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keys.public());
+        let (received_sender, mut received) = mpsc::unbounded_channel();
+        let mut gossipsub = new_gossipsub(keys.clone());
+        let blocks = topic(None, "blocks");
+        gossipsub.subscribe(&blocks).expect("can subscribe");
+        let behaviour = Subscriber {
+            gossipsub,
+            ping: new_ping(NonZeroU32::new(3).expect("is not zero")),
+            received: received_sender,
+        };
+        let transport = crate::build_transport(&keys, false);
+        let mut subscriber = SwarmBuilder::new(transport, behaviour, peer_id)
+            .executor(Box::new(|fut| {
+                tokio::spawn(fut);
+            }))
+            .build();
+        for node in [&a, &b] {
+            subscriber
+                .dial_addr(node.listen_addrs[0].clone())
+                .expect("can dial");
+        }
+        tokio::spawn(async move {
+            loop {
+                subscriber.select_next_some().await;
+            }
+        });
+        let subscribed = |node: &TestNode| {
+            node.behaviour()
+                .gossipsub
+                .all_peers()
+                .any(|(peer, topics)| *peer == peer_id && topics.contains(&&blocks.hash()))
+        };
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            subscribed(nodes[0]) && subscribed(nodes[1])
+        })
+        .await;
+
+        assert!(a.behaviour_mut().handle_create_block("once").is_some());
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[0].behaviour().app.len() == 2 && nodes[1].tip_hash() == nodes[0].tip_hash()
+        })
+        .await;
+        // b relaying the block afresh is dropped as a duplicate too
+        let block = b.behaviour().app.tip().expect("has blocks").clone();
+        let block_topic = b.behaviour().block_topic.clone();
+        b.behaviour_mut()
+            .publish(block_topic, Message::Block(block));
+        drive_for(&mut [&mut a, &mut b], Duration::from_secs(2)).await;
+
+        let mut blocks = 0;
+        while let Ok(data) = received.try_recv() {
+            if let Ok(Message::Block(block)) = serde_json::from_slice(&data) {
+                assert_eq!(block.data, "once");
+                blocks += 1;
+            }
+        }
+        assert_eq!(blocks, 1);
+    }
+
+    #[tokio::test]
+    async fn invalid_chain_response_keeps_the_local_chain() {
+        let mut node = test_node().await;
+        node.behaviour
+            .app
+            .add_mined_block("local".to_string())
+            .expect("mines");
+        let before: Vec<String> = node.behaviour.app.iter().map(|b| b.hash.clone()).collect();
+        let mut remote = node.behaviour.app.blocks.clone();
+        remote[1].data = "tampered".to_string();
+        node.behaviour
+            .handle_chain_response(PeerId::random(), remote);
+        let after: Vec<String> = node.behaviour.app.iter().map(|b| b.hash.clone()).collect();
+        assert_eq!(after, before);
+    }
+
+    #[tokio::test]
+    async fn bootstrap_peer_exchanges_a_block_without_mdns() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        assert!(!a.behaviour().mdns.is_enabled() && !b.behaviour().mdns.is_enabled());
+        let addr = b.listen_addrs[0].clone();
+        let mut bootstrap = crate::bootstrap::Bootstrap::new([addr.clone()]);
+        crate::dial_bootstrap(&mut a.swarm, &mut bootstrap, addr);
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[0].gossips_with(nodes[1]) && nodes[1].gossips_with(nodes[0])
+        })
+        .await;
+
+        assert!(b.behaviour_mut().handle_create_block("from b").is_some());
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[1].behaviour().app.len() == 2 && nodes[0].tip_hash() == nodes[1].tip_hash()
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_leaves_a_valid_chain_file() {
+        let mut node = test_node().await;
+        for data in ["one", "two"] {
+            node.behaviour
+                .app
+                .add_mined_block(data.to_string())
+                .expect("mines");
+        }
+        node.behaviour.handle_shutdown();
+        assert_eq!(node.behaviour.gossipsub.topics().count(), 0);
+
+        let saved = App::load_from_file(&node.behaviour.chain_file).expect("can load");
+        assert_eq!(saved.len(), 3);
+        assert_eq!(node.behaviour.app.is_chain_valid(&saved.blocks), Ok(()));
+        assert_eq!(
+            saved.tip().map(|b| &b.hash),
+            node.behaviour.app.tip().map(|b| &b.hash)
+        );
+    }
+
+    #[tokio::test]
+    async fn status_reports_height_and_tip() {
+        let mut node = test_node().await;
+        for data in ["one", "two", "three"] {
+            node.behaviour
+                .app
+                .add_mined_block(data.to_string())
+                .expect("mines");
+        }
+        let tip = node.behaviour.app.tip().expect("has blocks").clone();
+        let status = node.behaviour.status();
+        let lines: Vec<&str> = status.lines().collect();
+        assert_eq!(lines[0], "height: 3");
+        assert_eq!(lines[1], format!("tip: {}", tip.hash));
+        assert_eq!(lines[2], format!("tip timestamp: {}", tip.timestamp));
+        assert!(lines.contains(&"peers: 0"));
+    }
+
+    #[tokio::test]
+    async fn blocks_are_accepted_while_mining() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        a.connect(&mut b).await;
+        // far too hard to finish, so it is still running when b's block arrives
+        let mut task = a
+            .behaviour()
+            .app
+            .mine_task("slow".to_string())
+            .expect("has genesis");
+        task.difficulty = blockchain::MAX_DIFFICULTY;
+        a.behaviour_mut().start_mining(None, task);
+
+        assert!(b.behaviour_mut().handle_create_block("from b").is_some());
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[1].behaviour().app.len() == 2 && nodes[0].tip_hash() == nodes[1].tip_hash()
+        })
+        .await;
+        a.behaviour_mut().cancel_mining();
+    }
+
+    #[tokio::test]
+    async fn creating_a_block_before_genesis_fails_gracefully() {
+        let mut node = test_node().await;
+        node.behaviour.app.blocks.clear();
+        assert!(node.behaviour.handle_create_block("early").is_none());
+        assert!(node.behaviour.mining.is_none());
+        assert_eq!(node.behaviour.app.len(), 0);
+
+        // mining without a node starts from genesis instead
+        let mut app = App::new();
+        app.difficulty = 1;
+        let block = app.add_mined_block("early".to_string()).expect("mines");
+        assert_eq!(block.id, 1);
+        assert_eq!(app.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn discovered_and_expired_peers_update_the_count() {
+        let mut node = test_node().await;
+        let (a, b) = (PeerId::random(), PeerId::random());
+        node.behaviour.peers_discovered([a, b, a].into_iter());
+        assert_eq!(node.behaviour.known_peers.len(), 2);
+        node.behaviour.peers_expired([a].into_iter());
+        assert_eq!(node.behaviour.known_peers.len(), 1);
+
+        // notifications off still keeps count
+        node.behaviour.watch_peers = false;
+        node.behaviour.peers_discovered([a].into_iter());
+        assert_eq!(node.behaviour.known_peers.len(), 2);
+        node.behaviour.peers_expired([a, b].into_iter());
+        assert!(node.behaviour.known_peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn node_three_blocks_behind_fetches_the_gap() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        a.connect(&mut b).await;
+        // mined without gossip, so b only learns of them from the next block
+        for data in ["one", "two", "three"] {
+            a.behaviour_mut()
+                .app
+                .add_mined_block(data.to_string())
+                .expect("mines");
+        }
+        assert!(a.behaviour_mut().handle_create_block("four").is_some());
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[0].behaviour().app.len() == 5 && nodes[1].tip_hash() == nodes[0].tip_hash()
+        })
+        .await;
+        let data: Vec<&str> = b.behaviour().app.iter().map(|b| b.data.as_str()).collect();
+        assert_eq!(data, ["genesis!", "one", "two", "three", "four"]);
+    }
+
+    #[tokio::test]
+    async fn a_block_delivered_twice_is_added_once() {
+        let mut node = test_node().await;
+        let mut peer = App::new();
+        peer.difficulty = 1;
+        let block = peer.add_mined_block("twice".to_string()).expect("mines");
+        let source = PeerId::random();
+        node.behaviour.handle_gossip_block(source, block.clone());
+        node.behaviour.handle_gossip_block(source, block);
+        assert_eq!(node.behaviour.app.len(), 2);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn received_blocks_are_counted() {
+        let mut node = test_node().await;
+        let mut peer = App::new();
+        peer.difficulty = 1;
+        let block = peer.add_mined_block("counted".to_string()).expect("mines");
+        let before = crate::metrics::BLOCKS_RECEIVED.get();
+        node.behaviour.handle_gossip_block(PeerId::random(), block);
+        assert!(crate::metrics::BLOCKS_RECEIVED.get() > before);
+    }
+
+    #[tokio::test]
+    async fn both_muxers_connect() {
+        for use_yamux in [false, true] {
+            let listen = "/ip4/127.0.0.1/tcp/0";
+            let mut a = TestNode::with_options(None, use_yamux, listen).await;
+            let mut b = TestNode::with_options(None, use_yamux, listen).await;
+            a.connect(&mut b).await;
+            assert!(a.swarm.is_connected(&b.peer_id()));
+        }
+    }
+
+    #[test]
+    fn addresses_are_grouped_by_peer() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let addr = |s: &str| s.parse::<Multiaddr>().expect("is a multiaddr");
+        let discovered = [
+            (a, addr("/ip4/10.0.0.1/tcp/1")),
+            (b, addr("/ip4/10.0.0.2/tcp/2")),
+            (a, addr("/ip4/10.0.0.1/tcp/3")),
+            (a, addr("/ip4/10.0.0.1/tcp/1")),
+        ];
+        let list = group_addrs(discovered.into_iter());
+        assert_eq!(list.len(), 2);
+        assert_eq!(
+            list[&a],
+            BTreeSet::from([addr("/ip4/10.0.0.1/tcp/1"), addr("/ip4/10.0.0.1/tcp/3")])
+        );
+        assert_eq!(list[&b], BTreeSet::from([addr("/ip4/10.0.0.2/tcp/2")]));
+    }
+
+    #[tokio::test]
+    async fn reorg_records_how_many_blocks_were_dropped() {
+        let mut node = test_node().await;
+        for data in ["a1", "a2"] {
+            node.behaviour
+                .app
+                .add_mined_block(data.to_string())
+                .expect("mines");
+        }
+        let mut remote = App::new();
+        remote.difficulty = 1;
+        for data in ["b1", "b2", "b3"] {
+            remote.add_mined_block(data.to_string()).expect("mines");
+        }
+        // everything past genesis differs, so three blocks replace two
+        assert_eq!(
+            blockchain::fork_point(&node.behaviour.app.blocks, &remote.blocks),
+            1
+        );
+        node.behaviour
+            .handle_chain_response(PeerId::random(), remote.blocks.clone());
+
+        assert_eq!(node.behaviour.app.last_reorg_depth, Some(2));
+        let data: Vec<&str> = node.behaviour.app.iter().map(|b| b.data.as_str()).collect();
+        assert_eq!(data, ["genesis!", "b1", "b2", "b3"]);
+        assert!(node.behaviour.status().contains("last reorg: 2 blocks"));
+    }
+
+    #[tokio::test]
+    async fn import_adopts_heavier_chains_and_rejects_invalid_ones() {
+        let mut node = test_node().await;
+        let mut heavier = App::new();
+        heavier.difficulty = 1;
+        for data in ["one", "two"] {
+            heavier.add_mined_block(data.to_string()).expect("mines");
+        }
+        let path = node.dir.path().join("import.json");
+
+        let mut invalid = heavier.blocks.clone();
+        invalid[1].data = "tampered".to_string();
+        let invalid = App {
+            blocks: invalid,
+            ..App::new()
+        };
+        invalid.export_to_file(&path, true).expect("can export");
+        node.behaviour.handle_import(&path);
+        assert_eq!(node.behaviour.app.len(), 1);
+
+        heavier.export_to_file(&path, true).expect("can export");
+        node.behaviour.handle_import(&path);
+        assert_eq!(node.behaviour.app.len(), 3);
+        assert_eq!(
+            node.behaviour.app.tip().map(|b| &b.hash),
+            heavier.tip().map(|b| &b.hash)
+        );
+    }
+
+    #[tokio::test]
+    async fn nodes_on_other_networks_ignore_each_other() {
+        let listen = "/ip4/127.0.0.1/tcp/0";
+        let mut red = TestNode::with_options(Some("red"), false, listen).await;
+        let mut other_red = TestNode::with_options(Some("red"), false, listen).await;
+        let mut blue = TestNode::with_options(Some("blue"), false, listen).await;
+        red.connect(&mut other_red).await;
+        crate::add_peer(&mut blue.swarm, &red.listen_addrs[0].to_string());
+        let red_id = red.peer_id();
+        drive_until(&mut [&mut red, &mut other_red, &mut blue], |nodes| {
+            nodes[2].swarm.is_connected(&red_id)
+        })
+        .await;
+
+        assert!(red
+            .behaviour_mut()
+            .handle_create_block("red only")
+            .is_some());
+        drive_until(&mut [&mut red, &mut other_red, &mut blue], |nodes| {
+            nodes[0].behaviour().app.len() == 2 && nodes[1].tip_hash() == nodes[0].tip_hash()
+        })
+        .await;
+        drive_for(
+            &mut [&mut red, &mut other_red, &mut blue],
+            Duration::from_secs(1),
+        )
+        .await;
+        assert_eq!(blue.behaviour().app.len(), 1);
+        assert!(blue.swarm.is_connected(&red_id));
+    }
+
+    #[tokio::test]
+    async fn block_at_the_data_limit_is_mined_and_gossiped() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        a.connect(&mut b).await;
+        // the bytes JSON escapes the longest
+        let over = "\u{1}".repeat(blockchain::MAX_BLOCK_DATA_BYTES + 1);
+        assert!(a.behaviour_mut().handle_create_block(&over).is_none());
+        assert!(a.behaviour().mining.is_none());
+
+        let at_limit = &over[1..];
+        assert!(a.behaviour_mut().handle_create_block(at_limit).is_some());
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[1].behaviour().app.len() == 2
+        })
+        .await;
+        let tip = b.behaviour().app.tip().expect("has blocks");
+        assert_eq!(tip.data, at_limit);
+        assert_eq!(b.tip_hash(), a.tip_hash());
+    }
+
+    #[tokio::test]
+    async fn resync_resets_to_genesis_and_asks_a_peer() {
+        let mut lonely = test_node().await;
+        lonely
+            .behaviour
+            .app
+            .add_mined_block("kept".to_string())
+            .expect("mines");
+        lonely.behaviour.handle_resync();
+        assert_eq!(lonely.behaviour.app.len(), 2);
+
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        a.connect(&mut b).await;
+        for (node, blocks) in [(&mut a, 1), (&mut b, 3)] {
+            for i in 0..blocks {
+                let app = &mut node.behaviour_mut().app;
+                app.add_mined_block(i.to_string()).expect("mines");
+            }
+        }
+        a.behaviour_mut().handle_resync();
+        assert_eq!(a.behaviour().app.len(), 1);
+        assert_eq!(
+            a.behaviour().sync.as_ref().map(|s| s.peer),
+            Some(b.peer_id())
+        );
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[0].tip_hash() == nodes[1].tip_hash()
+        })
+        .await;
+        assert_eq!(a.behaviour().app.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn rapid_creates_are_rate_limited() {
+        let mut node = test_node().await;
+        node.behaviour.min_block_interval = Duration::from_millis(200);
+        assert!(node.behaviour.handle_create_block("first").is_some());
+        assert!(node.behaviour.handle_create_block("too soon").is_none());
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(node.behaviour.handle_create_block("later").is_some());
+        node.behaviour.cancel_mining();
+    }
+
+    #[tokio::test]
+    async fn peer_is_pruned_after_the_ping_failure_threshold() {
+        let mut node = test_node().await;
+        node.behaviour
+            .set_max_ping_failures(NonZeroU32::new(3).expect("is not zero"));
+        let peer = PeerId::random();
+        node.behaviour.peers_discovered(std::iter::once(peer));
+        let fail = |behaviour: &mut AppBehaviour| {
+            NetworkBehaviourEventProcess::inject_event(
+                behaviour,
+                PingEvent {
+                    peer,
+                    result: Err(libp2p::ping::PingFailure::Timeout),
+                },
+            )
+        };
+        fail(&mut node.behaviour);
+        fail(&mut node.behaviour);
+        assert!(node.behaviour.known_peers.contains(&peer));
+
+        // a successful ping starts the count over
+        NetworkBehaviourEventProcess::inject_event(
+            &mut node.behaviour,
+            PingEvent {
+                peer,
+                result: Ok(PingSuccess::Ping {
+                    rtt: Duration::from_millis(1),
+                }),
+            },
+        );
+        fail(&mut node.behaviour);
+        fail(&mut node.behaviour);
+        assert!(node.behaviour.known_peers.contains(&peer));
+        fail(&mut node.behaviour);
+        assert!(!node.behaviour.known_peers.contains(&peer));
+    }
+
+    #[tokio::test]
+    async fn block_command_shows_one_block() {
+        let mut node = test_node().await;
+        for data in ["one", "two"] {
+            node.behaviour
+                .app
+                .add_mined_block(data.to_string())
+                .expect("mines");
+        }
+        let report = node.behaviour.block_report(1);
+        let block: Block = serde_json::from_str(&report).expect("is a block");
+        assert_eq!(block.id, 1);
+        assert_eq!(
+            block.hash,
+            node.behaviour.app.get(1).expect("is mined").hash
+        );
+        assert_eq!(block.data, "one");
+        assert_eq!(
+            node.behaviour.block_report(3),
+            "block 3 not found, the chain holds 0..=2\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_and_wrong_genesis_responses_are_rejected() {
+        let mut node = test_node().await;
+        let local = node.behaviour.app.blocks.clone();
+        let peer = PeerId::random();
+        assert_eq!(
+            node.behaviour.app.check_remote_chain(&[]),
+            Err(blockchain::ChainError::Empty)
+        );
+        node.behaviour.handle_chain_response(peer, Vec::new());
+
+        // longer than the local chain, but from another genesis
+        let mut other = App::new();
+        other.difficulty = 1;
+        other.genesis();
+        other.blocks[0].hash = "0".repeat(64);
+        other.add_mined_block("other".to_string()).expect("mines");
+        assert_eq!(
+            node.behaviour.app.check_remote_chain(&other.blocks),
+            Err(blockchain::ChainError::WrongGenesis)
+        );
+        node.behaviour.handle_chain_response(peer, other.blocks);
+
+        assert_eq!(node.behaviour.app.blocks.len(), local.len());
+        assert_eq!(
+            node.behaviour.app.tip().map(|b| &b.hash),
+            local.last().map(|b| &b.hash)
+        );
+        assert_eq!(node.behaviour.peer_scores[&peer.to_string()], -2);
+    }
+
+    #[tokio::test]
+    async fn chain_requests_go_to_the_better_scoring_peer() {
+        let mut node = test_node().await;
+        for port in [4001, 4002] {
+            let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", port)
+                .parse()
+                .expect("is valid");
+            node.behaviour.manual_peers.insert(PeerId::random(), addr);
+        }
+        node.behaviour.handle_init();
+        let first = node.behaviour.sync.as_ref().expect("asks a peer").peer;
+        node.behaviour.handle_chain_response(first, Vec::new());
+
+        node.behaviour.handle_resync();
+        let second = node.behaviour.sync.as_ref().expect("asks a peer").peer;
+        assert_ne!(second, first);
+        assert!(node.behaviour.manual_peers.contains_key(&second));
+    }
+
+    #[tokio::test]
+    async fn unanswered_sync_retries_another_peer_then_gives_up() {
+        let mut node = test_node().await;
+        for port in [4001, 4002] {
+            let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", port)
+                .parse()
+                .expect("is valid");
+            node.behaviour.manual_peers.insert(PeerId::random(), addr);
+        }
+        node.behaviour.sync_timeout = Duration::ZERO;
+        node.behaviour.max_sync_attempts = NonZeroU32::new(2).expect("is not zero");
+        node.behaviour.handle_init();
+        let first = node.behaviour.sync.as_ref().expect("asks a peer").peer;
+
+        node.behaviour.handle_tick();
+        let retry = node.behaviour.sync.as_ref().expect("retries");
+        assert_ne!(retry.peer, first);
+        assert_eq!(retry.attempts, 2);
+
+        node.behaviour.handle_tick();
+        assert!(node.behaviour.sync.is_none());
+    }
+
+    #[tokio::test]
+    async fn broadcast_chain_is_adopted_by_a_peer_behind() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        a.connect(&mut b).await;
+        // mined locally only, so b hears of them through the broadcast alone
+        for data in ["one", "two"] {
+            a.behaviour_mut()
+                .app
+                .add_mined_block(data.to_string())
+                .expect("mines");
+        }
+        assert_eq!(b.behaviour().app.len(), 1);
+        a.behaviour_mut().handle_broadcast();
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[1].tip_hash() == nodes[0].tip_hash()
+        })
+        .await;
+        assert_eq!(b.behaviour().app.len(), 3);
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn nodes_listen_and_dial_over_websocket() {
+        let ws = "/ip4/127.0.0.1/tcp/0/ws";
+        let mut a = TestNode::with_options(None, false, ws).await;
+        let mut b = TestNode::with_options(None, false, ws).await;
+        assert!(b.listen_addrs[0].to_string().ends_with("/ws"));
+        a.connect(&mut b).await;
+        assert!(a.swarm.is_connected(&b.peer_id()));
+    }
+
+    #[tokio::test]
+    async fn check_reports_without_touching_the_chain() {
+        let mut node = test_node().await;
+        for data in ["one", "two", "three"] {
+            node.behaviour
+                .app
+                .add_mined_block(data.to_string())
+                .expect("mines");
+        }
+        let path = node.dir.path().join("export.jsonl");
+        node.behaviour
+            .app
+            .export_to_file(&path, false)
+            .expect("exports");
+        // shorter than the file, so adopting it would show
+        node.behaviour.app.blocks.truncate(1);
+
+        assert_eq!(
+            node.behaviour.check_report(&path),
+            format!("{} is valid (4 blocks)\n", path.display())
+        );
+        let tampered = std::fs::read_to_string(&path)
+            .expect("can read")
+            .replace("\"two\"", "\"forged\"");
+        std::fs::write(&path, tampered).expect("can write");
+        assert_eq!(
+            node.behaviour.check_report(&path),
+            format!("{} is invalid at block 2\n", path.display())
+        );
+        assert_eq!(node.behaviour.app.blocks.len(), 1);
+    }
+
+    #[test]
+    fn receivers_are_compared_as_parsed_peer_ids() {
+        let local = PeerId::random();
+        assert!(is_local_peer(&local, &local.to_string()));
+        assert!(is_local_peer(&local, &format!(" {}\n", local)));
+        assert!(!is_local_peer(&local, &PeerId::random().to_string()));
+        assert!(!is_local_peer(&local, "not a peer id"));
+        assert!(!is_local_peer(&local, ""));
+    }
+
+    #[tokio::test]
+    async fn chain_responses_send_at_most_the_newest_blocks() {
+        let mut node = test_node().await;
+        for data in ["one", "two", "three", "four"] {
+            node.behaviour
+                .app
+                .add_mined_block(data.to_string())
+                .expect("mines");
+        }
+        node.behaviour.max_response_blocks = NonZeroUsize::new(3).expect("is not zero");
+        let requester = PeerId::random();
+        let req = LocalChainRequest {
+            from_peer_id: node.behaviour.peer_id.to_string(),
+        };
+        node.behaviour.handle_chain_request(requester, req);
+        let (network, resp) = node.responses.try_recv().expect("is queued");
+        assert_eq!(network, None);
+        assert_eq!(resp.receiver, requester.to_string());
+        let ids: Vec<u64> = resp.blocks.iter().map(|b| b.id).collect();
+        assert_eq!(ids, [2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn disconnected_peer_misses_later_blocks() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        a.connect(&mut b).await;
+        let b_id = b.peer_id();
+        crate::disconnect(&mut a.swarm, &b_id.to_string());
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            !nodes[0].swarm.is_connected(&b_id)
+        })
+        .await;
+
+        assert!(a.behaviour_mut().handle_create_block("after").is_some());
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[0].behaviour().app.len() == 2
+        })
+        .await;
+        drive_for(&mut [&mut a, &mut b], Duration::from_secs(1)).await;
+        assert_eq!(b.behaviour().app.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dropped_bootstrap_peer_is_redialed_when_it_comes_back() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .expect("has a free port")
+            .port();
+        let listen = format!("/ip4/127.0.0.1/tcp/{}", port);
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::with_options(None, false, &listen).await;
+        let addr = b.listen_addrs[0].clone();
+        let mut bootstrap = crate::bootstrap::Bootstrap::new([addr.clone()]);
+        crate::dial_bootstrap(&mut a.swarm, &mut bootstrap, addr.clone());
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[0].swarm.is_connected(&nodes[1].peer_id())
+        })
+        .await;
+        // the main loop reports connection events to the bootstrap list like this
+        let b_id = b.peer_id();
+        bootstrap.connected(&addr, b_id);
+        drop(b);
+        drive_until(&mut [&mut a], |nodes| !nodes[0].swarm.is_connected(&b_id)).await;
+        bootstrap.disconnected(&b_id);
+        assert!(bootstrap.due().is_empty());
+
+        let mut b = TestNode::with_options(None, false, &listen).await;
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !a.swarm.is_connected(&b.peer_id()) {
+                for addr in bootstrap.due() {
+                    crate::dial_bootstrap(&mut a.swarm, &mut bootstrap, addr);
+                }
+                drive_for(&mut [&mut a, &mut b], Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("redials after the backoff");
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn large_chain_arrives_intact_through_compression() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        a.connect(&mut b).await;
+        let data = "compressible ".repeat(100);
+        let genesis = App::genesis_block().timestamp;
+        for i in 1..=40 {
+            let app = &mut a.behaviour_mut().app;
+            // a block per target interval keeps the difficulty at 1
+            let at = genesis + i * blockchain::TARGET_BLOCK_INTERVAL;
+            app.clock = Arc::new(crate::clock::FixedClock(at));
+            app.add_mined_block(format!("{} {}", data, i))
+                .expect("mines");
+        }
+        let json = serde_json::to_vec(&a.behaviour().app.blocks).expect("serializes");
+        let compressed = crate::compression::compress(&json).expect("compresses");
+        assert!(compressed.len() < json.len() / 4);
+
+        a.behaviour_mut().handle_broadcast();
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[1].tip_hash() == nodes[0].tip_hash()
+        })
+        .await;
+        let received = serde_json::to_vec(&b.behaviour().app.blocks).expect("serializes");
+        assert_eq!(received, json);
+    }
+
+    #[tokio::test]
+    async fn oversized_chain_response_is_logged_not_fatal() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        a.connect(&mut b).await;
+        let mut huge = App::genesis_block();
+        // hashes, so compression can't bring it under the size limit
+        huge.data = (0u32..65536)
+            .map(|i| hex::encode(Sha256::digest(&i.to_be_bytes())))
+            .collect();
+        let resp = || ChainResponse {
+            blocks: vec![huge.clone()],
+            receiver: BROADCAST_RECEIVER.to_string(),
+        };
+        let topic = a.behaviour().chain_topic.clone();
+        assert!(a.behaviour_mut().try_publish_chain(topic, resp()).is_err());
+        a.behaviour_mut().publish_chain(None, resp());
+
+        // the node carries on and later responses still go out
+        a.behaviour_mut()
+            .app
+            .add_mined_block("after".to_string())
+            .expect("mines");
+        a.behaviour_mut().handle_broadcast();
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[1].tip_hash() == nodes[0].tip_hash()
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn hosted_chains_keep_their_blocks_apart() {
+        let dir = tempfile::tempdir().expect("can create a temp dir");
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        for (name, node) in [("a", &mut a), ("b", &mut b)] {
+            for network in ["red", "blue"] {
+                let mut app = App::new();
+                app.difficulty = 1;
+                app.genesis();
+                let file = dir.path().join(format!("{}-{}.jsonl", name, network));
+                node.behaviour_mut().host_chain(network, app, file);
+            }
+        }
+        a.connect(&mut b).await;
+        let hosted_len = |node: &TestNode, network: &str| {
+            node.behaviour().registry.get(network).map(|c| c.app.len())
+        };
+
+        a.behaviour_mut()
+            .handle_hosted_command("red", Command::CreateBlock("red block".to_string()));
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            hosted_len(nodes[1], "red") == Some(2)
+        })
+        .await;
+        drive_for(&mut [&mut a, &mut b], Duration::from_millis(500)).await;
+        for node in [&a, &b] {
+            let red = &node.behaviour().registry.get("red").expect("is hosted").app;
+            assert_eq!(red.tip().map(|t| t.data.as_str()), Some("red block"));
+            assert_eq!(hosted_len(node, "blue"), Some(1));
+            assert_eq!(node.behaviour().app.len(), 1);
+        }
+    }
+
+    #[test]
+    fn messages_only_parse_as_the_kind_they_were_sent_as() {
+        let block = App::genesis_block();
+        let json = serde_json::to_value(Message::Block(block.clone())).expect("serializes");
+        assert_eq!(json["kind"], "block");
+        let parsed: Message = serde_json::from_value(json.clone()).expect("parses");
+        assert!(matches!(parsed, Message::Block(b) if b.hash == block.hash));
+
+        // the same payload under another kind, or untagged, is refused
+        let mut relabelled = json.clone();
+        relabelled["kind"] = "chain_request".into();
+        assert!(serde_json::from_value::<Message>(relabelled).is_err());
+        assert!(serde_json::from_value::<Message>(json["payload"].clone()).is_err());
+
+        let req = Message::ChainRequest(LocalChainRequest {
+            from_peer_id: PeerId::random().to_string(),
+        });
+        let json = serde_json::to_value(req).expect("serializes");
+        assert!(matches!(
+            serde_json::from_value(json),
+            Ok(Message::ChainRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn flooded_chain_requests_queue_at_most_the_capacity() {
+        let mut node = test_node().await;
+        let local = node.behaviour.peer_id.to_string();
+        let req = || LocalChainRequest {
+            from_peer_id: local.clone(),
+        };
+        for _ in 0..CHANNEL_CAPACITY * 4 {
+            node.behaviour.handle_chain_request(PeerId::random(), req());
+        }
+        let mut queued = 0;
+        while node.responses.try_recv().is_ok() {
+            queued += 1;
+        }
+        assert_eq!(queued, CHANNEL_CAPACITY);
+
+        // once the loop catches up, responses are queued again
+        node.behaviour.handle_chain_request(PeerId::random(), req());
+        assert!(node.responses.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn received_block_records_its_source_peer() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        a.connect(&mut b).await;
+        assert!(a.behaviour_mut().handle_create_block("sourced").is_some());
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[1].behaviour().app.len() == 2
+        })
+        .await;
+        let hash = b.tip_hash().expect("has blocks");
+        assert_eq!(
+            b.behaviour().app.sources.get(&hash),
+            Some(&a.peer_id().to_string())
+        );
+        // mined here, so there is no source to record
+        assert_eq!(a.behaviour().app.sources.get(&hash), None);
+        // kept beside the block, not in it
+        let json = serde_json::to_value(&b.behaviour().app.blocks[1]).expect("serializes");
+        assert!(json.get("source").is_none());
+    }
+
+    #[tokio::test]
+    async fn blocks_delivered_backwards_end_up_in_order() {
+        let mut node = test_node().await;
+        let mut other = App::new();
+        other.difficulty = 1;
+        other.genesis();
+        for data in ["one", "two", "three"] {
+            other.add_mined_block(data.to_string()).expect("mines");
+        }
+        let source = PeerId::random();
+        for block in other.blocks[1..].iter().rev() {
+            node.behaviour.handle_gossip_block(source, block.clone());
+        }
+        let hashes = |app: &App| app.iter().map(|b| b.hash.clone()).collect::<Vec<_>>();
+        assert_eq!(hashes(&node.behaviour.app), hashes(&other));
+        assert!(node
+            .behaviour
+            .orphans
+            .take_children(&other.blocks[3].hash)
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn since_measures_the_age_of_a_past_tip() {
+        let mut node = test_node().await;
+        let now = node.behaviour.app.clock.now();
+        node.behaviour.app.clock = Arc::new(crate::clock::FixedClock(now - 192));
+        node.behaviour
+            .app
+            .add_mined_block("old".to_string())
+            .expect("mines");
+        node.behaviour.app.clock = Arc::new(crate::clock::SystemClock);
+
+        let age = node.behaviour.tip_age().expect("has a tip");
+        assert!((192..=194).contains(&age), "age was {}", age);
+        assert!(age < node.behaviour.stale_after.as_secs());
+
+        assert_eq!(format_age(0), "0s");
+        assert_eq!(format_age(192), "3m 12s");
+        assert_eq!(format_age(3600), "1h 0m 0s");
+        assert_eq!(format_age(90061), "1d 1h 1m 1s");
+    }
+
+    #[tokio::test]
+    async fn batch_of_three_blocks_is_applied_in_order() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        a.connect(&mut b).await;
+        for data in ["one", "two", "three"] {
+            let behaviour = a.behaviour_mut();
+            let block = behaviour
+                .app
+                .add_mined_block(data.to_string())
+                .expect("mines");
+            behaviour.unsent_blocks.push(block);
+        }
+        a.behaviour_mut().broadcast_mined_blocks();
+        assert!(a.behaviour().unsent_blocks.is_empty());
+
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[1].behaviour().app.len() == 4
+        })
+        .await;
+        let data: Vec<_> = b.behaviour().app.iter().map(|b| b.data.as_str()).collect();
+        assert_eq!(data, ["genesis!", "one", "two", "three"]);
+        assert_eq!(b.tip_hash(), a.tip_hash());
+    }
+
+    #[tokio::test]
+    async fn connection_count_follows_the_swarm() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        assert_eq!(a.behaviour().connections, 0);
+        a.connect(&mut b).await;
+        assert_eq!(a.behaviour().connections, 1);
+        assert_eq!(b.behaviour().connections, 1);
+        assert!(a.behaviour().status().contains("connections: 1\n"));
+
+        crate::disconnect(&mut a.swarm, &b.peer_id().to_string());
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes.iter().all(|node| node.behaviour().connections == 0)
+        })
+        .await;
+        assert!(a.behaviour().status().contains("connections: 0\n"));
+    }
+
+    #[tokio::test]
+    async fn peers_past_the_cap_evict_the_least_recently_active() {
+        let mut node = test_node().await;
+        node.behaviour.max_peers = NonZeroUsize::new(2).expect("is not zero");
+        let [first, second, third] = [PeerId::random(), PeerId::random(), PeerId::random()];
+        // activity is timed with Instant, the pauses keep the order unambiguous
+        let pause = || std::thread::sleep(Duration::from_millis(5));
+        let hear_from = |node: &mut Node, peer: PeerId, seqno: u64| {
+            let block = Message::Block(App::genesis_block());
+            NetworkBehaviourEventProcess::<GossipsubEvent>::inject_event(
+                &mut node.behaviour,
+                GossipsubEvent::Message {
+                    propagation_source: peer,
+                    message_id: MessageId::from(seqno.to_string()),
+                    message: gossip(peer, seqno, &block),
+                },
+            );
+        };
+        node.behaviour.add_gossip_peer(first);
+        pause();
+        node.behaviour.add_gossip_peer(second);
+        pause();
+        // the first peer was added first but is heard from since, so the second is oldest
+        hear_from(&mut node, first, 1);
+        pause();
+
+        node.behaviour.add_gossip_peer(third);
+        assert_eq!(node.behaviour.last_active.len(), 2);
+        assert!(node.behaviour.last_active.contains_key(&first));
+        assert!(!node.behaviour.last_active.contains_key(&second));
+
+        pause();
+        hear_from(&mut node, third, 2);
+        pause();
+        node.behaviour.add_gossip_peer(second);
+        assert!(!node.behaviour.last_active.contains_key(&first));
+        assert!(node.behaviour.last_active.contains_key(&second));
+        assert!(node.behaviour.last_active.contains_key(&third));
+    }
+
+    #[tokio::test]
+    async fn full_chain_response_fits_in_one_message() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        a.connect(&mut b).await;
+        let genesis = App::genesis_block().timestamp;
+        for i in 1..=MAX_RESPONSE_BLOCKS as i64 {
+            let app = &mut a.behaviour_mut().app;
+            // a block per target interval keeps the difficulty at 1
+            let at = genesis + i * blockchain::TARGET_BLOCK_INTERVAL;
+            app.clock = Arc::new(crate::clock::FixedClock(at));
+            app.add_mined_block(format!("block {}", i)).expect("mines");
+        }
+        let max = a.behaviour().max_response_blocks;
+        let resp = ChainResponse {
+            blocks: newest_blocks(&a.behaviour().app.blocks, max),
+            receiver: BROADCAST_RECEIVER.to_string(),
+        };
+        assert_eq!(resp.blocks.len(), MAX_RESPONSE_BLOCKS);
+        let topic = a.behaviour().chain_topic.clone();
+        a.behaviour_mut()
+            .try_publish_chain(topic, resp)
+            .expect("fits under the transmit size");
+
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[1].tip_hash() == nodes[0].tip_hash()
+        })
+        .await;
+        assert_eq!(b.behaviour().app.len(), MAX_RESPONSE_BLOCKS + 1);
+    }
+
+    #[test]
+    fn responses_of_large_blocks_stop_at_the_transmit_size() {
+        let mut block = App::genesis_block();
+        block.data = "x".repeat(100 * 1024);
+        let blocks = vec![block; 20];
+        let max = NonZeroUsize::new(MAX_RESPONSE_BLOCKS).expect("is not zero");
+        let newest = newest_blocks(&blocks, max);
+        assert!(!newest.is_empty() && newest.len() < blocks.len());
+        let json = serde_json::to_vec(&Message::ChainResponse(ChainResponse {
+            blocks: newest,
+            receiver: PeerId::random().to_string(),
+        }))
+        .expect("serializes");
+        assert!(json.len() < MAX_TRANSMIT_SIZE);
+        assert_eq!(blocks_that_fit(blocks.iter(), max), 10);
+    }
+
+    #[tokio::test]
+    async fn older_pages_are_only_taken_when_asked_for() {
+        let mut node = test_node().await;
+        node.behaviour.max_response_blocks = NonZeroUsize::new(2).expect("is not zero");
+        let mut remote = App::new();
+        remote.difficulty = 1;
+        remote.genesis();
+        for i in 1..=6 {
+            remote
+                .add_mined_block(format!("block {}", i))
+                .expect("mines");
+        }
+        let page = |ids: std::ops::RangeInclusive<u64>| -> Vec<Block> {
+            ids.map(|id| remote.get(id).expect("has it").clone())
+                .collect()
+        };
+        let local = node.behaviour.peer_id.to_string();
+        let range = |blocks: Vec<Block>| {
+            Message::RangeResponse(RangeResponse {
+                blocks,
+                receiver: local.clone(),
+            })
+        };
+        let peer = PeerId::random();
+        let held = |node: &Node| {
+            let partial = node.behaviour.partial_chain.as_ref().expect("is paging");
+            (partial.blocks.len(), partial.range)
+        };
+
+        node.behaviour.handle_chain_response(peer, page(5..=6));
+        assert_eq!(held(&node), (2, (3, 4)));
+        // the right ids from another peer, or a page nobody asked for, are not joined
+        node.behaviour
+            .handle_range_message(PeerId::random(), range(page(3..=4)));
+        node.behaviour
+            .handle_range_message(peer, range(page(2..=4)));
+        assert_eq!(held(&node), (2, (3, 4)));
+
+        node.behaviour
+            .handle_range_message(peer, range(page(3..=4)));
+        assert_eq!(held(&node), (4, (1, 2)));
+        // a page cut short is asked for again, ending next to the held blocks
+        node.behaviour
+            .handle_range_message(peer, range(page(1..=1)));
+        assert_eq!(held(&node), (4, (2, 2)));
+        node.behaviour
+            .handle_range_message(peer, range(page(2..=2)));
+        assert_eq!(held(&node), (5, (0, 1)));
+
+        node.behaviour
+            .handle_range_message(peer, range(page(0..=1)));
+        assert!(node.behaviour.partial_chain.is_none());
+        assert_eq!(node.behaviour.app.len(), 7);
+        assert_eq!(
+            node.behaviour.app.tip().map(|b| &b.hash),
+            remote.tip().map(|b| &b.hash)
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_too_large_for_one_message_are_split() {
+        let mut a = TestNode::new().await;
+        let mut b = TestNode::new().await;
+        a.connect(&mut b).await;
+        let data = "x".repeat(150 * 1024);
+        for i in 1..=8 {
+            let behaviour = a.behaviour_mut();
+            let block = behaviour
+                .app
+                .add_mined_block(format!("{} {}", i, data))
+                .expect("mines");
+            behaviour.unsent_blocks.push(block);
+        }
+        let max = a.behaviour().max_response_blocks;
+        assert!(blocks_that_fit(a.behaviour().unsent_blocks.iter(), max) < 8);
+        a.behaviour_mut().broadcast_mined_blocks();
+
+        drive_until(&mut [&mut a, &mut b], |nodes| {
+            nodes[1].behaviour().app.len() == 9
+        })
+        .await;
+        assert_eq!(b.tip_hash(), a.tip_hash());
+    }
+
+    #[tokio::test]
+    async fn mine_on_start_grows_a_valid_chain() {
+        let mut node = TestNode::new().await;
+        let behaviour = node.behaviour_mut();
+        // blocks are spaced a target interval apart, a second keeps the test short
+        behaviour.app.target_block_interval = 1;
+        behaviour.mine_on_start = 3;
+        behaviour.handle_init();
+        drive_until(&mut [&mut node], |nodes| {
+            nodes[0].behaviour().app.height() == Some(3)
+        })
+        .await;
+
+        let app = &node.behaviour().app;
+        assert_eq!(app.is_chain_valid(&app.blocks), Ok(()));
+        let data: Vec<_> = app.iter().skip(1).map(|b| b.data.as_str()).collect();
+        assert_eq!(data, ["auto block 1", "auto block 2", "auto block 3"]);
+        assert_eq!(node.behaviour().auto_mine_left, 0);
+    }
+
+    #[tokio::test]
+    async fn orphans_connect_to_a_block_mined_here() {
+        let mut node = test_node().await;
+        assert!(node.behaviour.handle_create_block("mined here").is_some());
+        let outcome = node.mined.recv().await.expect("the job reports");
+        let mined = outcome.result.as_ref().expect("mines").clone();
+        // a peer builds on the block and its child arrives before the block is back
+        let mut peer = App::new();
+        peer.difficulty = 1;
+        peer.genesis();
+        peer.try_add_bock(mined, None).expect("links to genesis");
+        let child = peer.add_mined_block("child".to_string()).expect("mines");
+        node.behaviour
+            .handle_gossip_block(PeerId::random(), child.clone());
+        assert_eq!(node.behaviour.app.len(), 1);
+
+        node.behaviour.handle_mined_block(outcome);
+        assert_eq!(node.behaviour.app.tip().map(|b| &b.hash), Some(&child.hash));
+        // both are appended to the chain file
+        let saved = App::load_from_file(&node.dir.path().join("chain.jsonl")).expect("loads");
+        assert_eq!(saved.tip().map(|b| &b.hash), Some(&child.hash));
+    }
+
+    #[tokio::test]
+    async fn hosted_blocks_delivered_backwards_end_up_in_order() {
+        let mut node = test_node().await;
+        let mut hosted = App::new();
+        hosted.difficulty = 1;
+        hosted.genesis();
+        let file = node.dir.path().join("side.jsonl");
+        hosted.save_to_file(&file).expect("saves");
+        node.behaviour.host_chain("side", hosted, file.clone());
+        let mut other = App::new();
+        other.difficulty = 1;
+        other.genesis();
+        for data in ["one", "two", "three"] {
+            other.add_mined_block(data.to_string()).expect("mines");
+        }
+        let source = PeerId::random();
+        for block in other.blocks[1..].iter().rev() {
+            node.behaviour
+                .handle_hosted_message("side", source, Message::Block(block.clone()));
+        }
+
+        let hashes = |app: &App| app.iter().map(|b| b.hash.clone()).collect::<Vec<_>>();
+        let chain = node.behaviour.registry.get("side").expect("is hosted");
+        assert_eq!(hashes(&chain.app), hashes(&other));
+        let saved = App::load_from_file(&file).expect("loads");
+        assert_eq!(hashes(&saved), hashes(&other));
+    }
 }
@@ -0,0 +1,158 @@
+// nodes on loopback tcp in one process, each driven by the handlers the main loop calls
+use std::time::Duration;
+
+use libp2p::{
+    core::ConnectedPoint,
+    futures::{future::select_all, StreamExt},
+    identity,
+    swarm::{Swarm, SwarmEvent},
+    Multiaddr, PeerId,
+};
+use tempfile::TempDir;
+use tokio::{
+    select,
+    sync::mpsc,
+    time::{self, Interval},
+};
+
+use super::{AppBehaviour, ChainResponse, MineOutcome, CHANNEL_CAPACITY};
+use crate::{blockchain::App, build_swarm};
+
+// shorter than the node's own tick so mined blocks go out quickly
+const TICK: Duration = Duration::from_millis(100);
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct TestNode {
+    pub swarm: Swarm<AppBehaviour>,
+    pub listen_addrs: Vec<Multiaddr>,
+    _dir: TempDir,
+    responses: mpsc::Receiver<(Option<String>, ChainResponse)>,
+    mined: mpsc::Receiver<MineOutcome>,
+    ticker: Interval,
+}
+
+impl TestNode {
+    pub async fn new() -> Self {
+        TestNode::with_options(None, false, "/ip4/127.0.0.1/tcp/0").await
+    }
+
+    // a node on `network` listening on `listen`, cheap to mine on and started from genesis
+    pub async fn with_options(network: Option<&str>, use_yamux: bool, listen: &str) -> Self {
+        let dir = tempfile::tempdir().expect("can create a temp dir");
+        let keys = identity::Keypair::generate_ed25519();
+        let mut app = App::new();
+        app.difficulty = 1;
+        app.miner = PeerId::from(keys.public()).to_string();
+        app.genesis();
+        let (response_sender, responses) = mpsc::channel(CHANNEL_CAPACITY);
+        let (mined_sender, mined) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut behaviour = AppBehaviour::new(
+            keys.clone(),
+            app,
+            response_sender,
+            dir.path().join("chain.jsonl"),
+            mined_sender,
+            network,
+            false,
+        )
+        .await;
+        behaviour.min_block_interval = Duration::ZERO;
+        let mut swarm = build_swarm(behaviour, &keys, use_yamux);
+        let listen = listen.parse().expect("is a multiaddr");
+        Swarm::listen_on(&mut swarm, listen).expect("can listen");
+        let mut node = TestNode {
+            swarm,
+            listen_addrs: Vec::new(),
+            _dir: dir,
+            responses,
+            mined,
+            ticker: time::interval(TICK),
+        };
+        while node.listen_addrs.is_empty() {
+            node.step().await;
+        }
+        node
+    }
+
+    pub fn behaviour(&self) -> &AppBehaviour {
+        self.swarm.behaviour()
+    }
+
+    pub fn behaviour_mut(&mut self) -> &mut AppBehaviour {
+        self.swarm.behaviour_mut()
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        *self.swarm.local_peer_id()
+    }
+
+    pub fn tip_hash(&self) -> Option<String> {
+        self.behaviour().app.tip().map(|b| b.hash.clone())
+    }
+
+    // whether `other` is known to listen on this node's chain and block topics
+    pub fn gossips_with(&self, other: &TestNode) -> bool {
+        let behaviour = self.behaviour();
+        let topics = [behaviour.chain_topic.hash(), behaviour.block_topic.hash()];
+        behaviour.gossipsub.all_peers().any(|(peer, subscribed)| {
+            *peer == other.peer_id() && topics.iter().all(|t| subscribed.contains(&t))
+        })
+    }
+
+    // dials `other` as `add peer` does, and waits until the two gossip with each other
+    pub async fn connect(&mut self, other: &mut TestNode) {
+        let addr = other.listen_addrs[0].to_string();
+        crate::add_peer(&mut self.swarm, &addr);
+        drive_until(&mut [self, other], |nodes| {
+            nodes[0].gossips_with(nodes[1]) && nodes[1].gossips_with(nodes[0])
+        })
+        .await;
+    }
+
+    // handles the next event from any source, the way the main loop does
+    async fn step(&mut self) {
+        select! {
+            Some((network, resp)) = self.responses.recv() => {
+                self.behaviour_mut().publish_chain(network.as_deref(), resp)
+            }
+            Some(outcome) = self.mined.recv() => self.behaviour_mut().handle_mined_block(outcome),
+            _ = self.ticker.tick() => self.behaviour_mut().handle_tick(),
+            event = self.swarm.select_next_some() => match event {
+                SwarmEvent::ConnectionEstablished {
+                    peer_id, endpoint, ..
+                } => {
+                    self.behaviour_mut().connection_opened(peer_id);
+                    if let ConnectedPoint::Dialer { address } = endpoint {
+                        self.behaviour_mut().handle_dialed_peer(peer_id, &address);
+                    }
+                }
+                SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+                    self.behaviour_mut().connection_closed(peer_id, cause)
+                }
+                SwarmEvent::NewListenAddr { address, .. } => self.listen_addrs.push(address),
+                _ => {}
+            },
+        }
+    }
+}
+
+// runs every node's event loop until `done` holds, failing the test after TIMEOUT
+pub async fn drive_until(nodes: &mut [&mut TestNode], done: impl Fn(&[&mut TestNode]) -> bool) {
+    time::timeout(TIMEOUT, async {
+        while !done(nodes) {
+            select_all(nodes.iter_mut().map(|node| Box::pin(node.step()))).await;
+        }
+    })
+    .await
+    .expect("nodes get there in time");
+}
+
+// runs every node's event loop for `duration`, for when nothing observable should happen
+pub async fn drive_for(nodes: &mut [&mut TestNode], duration: Duration) {
+    let _ = time::timeout(duration, async {
+        loop {
+            select_all(nodes.iter_mut().map(|node| Box::pin(node.step()))).await;
+        }
+    })
+    .await;
+}
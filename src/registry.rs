@@ -0,0 +1,50 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use libp2p::gossipsub::{IdentTopic, TopicHash};
+
+use crate::{blockchain::App, orphans::OrphanPool};
+
+// a chain kept next to the node's own one, for another network; it syncs whole chains
+// and single blocks over that network's topics, without range requests
+pub struct HostedChain {
+    pub app: App,
+    pub chain_file: PathBuf,
+    pub chain_topic: IdentTopic,
+    pub block_topic: IdentTopic,
+    // blocks ahead of the tip, added once the chain catches up to them
+    pub orphans: OrphanPool,
+}
+
+// hosted chains by network name, see `--host-network`
+#[derive(Default)]
+pub struct ChainRegistry {
+    chains: HashMap<String, HostedChain>,
+}
+
+impl ChainRegistry {
+    pub fn insert(&mut self, network: String, chain: HostedChain) {
+        self.chains.insert(network, chain);
+    }
+
+    pub fn get(&self, network: &str) -> Option<&HostedChain> {
+        self.chains.get(network)
+    }
+
+    pub fn get_mut(&mut self, network: &str) -> Option<&mut HostedChain> {
+        self.chains.get_mut(network)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &HostedChain)> {
+        self.chains.iter()
+    }
+
+    // the hosted network a message on `topic` belongs to
+    pub fn network_for(&self, topic: &TopicHash) -> Option<String> {
+        self.chains
+            .iter()
+            .find(|(_, chain)| {
+                chain.chain_topic.hash() == *topic || chain.block_topic.hash() == *topic
+            })
+            .map(|(network, _)| network.clone())
+    }
+}
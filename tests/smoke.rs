@@ -0,0 +1,244 @@
+// runs the built node binary, as a user would
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    path::Path,
+    process::{Child, Command, Stdio},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// a node on loopback without mdns, keeping its state in `data_dir`, and the lines it
+// prints and logs
+fn start(data_dir: &Path, args: &[&str]) -> (Child, Receiver<String>) {
+    let mut node = Command::new(env!("CARGO_BIN_EXE_demo_blockchain"))
+        .args(["--listen-addr", "/ip4/127.0.0.1/tcp/0", "--no-mdns"])
+        .arg("--data-dir")
+        .arg(data_dir)
+        .args(args)
+        .env("RUST_LOG", "info")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("can start the node");
+    let (lines, logged) = mpsc::channel();
+    forward_lines(node.stdout.take().expect("stdout is piped"), lines.clone());
+    forward_lines(node.stderr.take().expect("stderr is piped"), lines);
+    (node, logged)
+}
+
+fn forward_lines(output: impl Read + Send + 'static, lines: Sender<String>) {
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines().map_while(Result::ok) {
+            if lines.send(line).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+fn wait_for(logged: &Receiver<String>, needle: &str) -> Option<String> {
+    loop {
+        match logged.recv_timeout(TIMEOUT) {
+            Ok(line) if line.contains(needle) => return Some(line),
+            Ok(_) => {}
+            Err(_) => return None,
+        }
+    }
+}
+
+// polls `path` until it holds `needle`, false after TIMEOUT
+fn wait_for_file(path: &Path, needle: &str) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < TIMEOUT {
+        if fs::read_to_string(path).is_ok_and(|content| content.contains(needle)) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+fn stop(mut node: Child) {
+    node.kill().expect("can stop the node");
+    node.wait().expect("node exits");
+}
+
+#[test]
+fn boots_with_info_logging() {
+    let dir = tempfile::tempdir().expect("can create a temp dir");
+    let (node, logged) = start(dir.path(), &[]);
+    let listening = wait_for(&logged, "listening on /ip4/");
+    stop(node);
+
+    let listening = listening.expect("the node logs its listen address");
+    assert!(listening.contains("INFO"));
+    assert!(listening.contains("/ip4/127.0.0.1/tcp/"));
+}
+
+#[test]
+fn chain_file_is_written_under_the_data_dir() {
+    let dir = tempfile::tempdir().expect("can create a temp dir");
+    let data_dir = dir.path().join("state");
+    let args = ["--difficulty", "1", "--min-block-interval", "0"];
+    let (mut node, logged) = start(&data_dir, &args);
+    // commands before init are refused
+    assert!(wait_for(&logged, "connected nodes").is_some());
+    let stdin = node.stdin.as_mut().expect("stdin is piped");
+    stdin
+        .write_all(b"create b in the data dir\n")
+        .expect("can type");
+
+    let chain_file = data_dir.join("chain.jsonl");
+    let written = wait_for_file(&chain_file, "in the data dir");
+    stop(node);
+    assert!(written, "{} has the mined block", chain_file.display());
+}
+
+#[test]
+fn addrs_prints_the_resolved_port() {
+    let dir = tempfile::tempdir().expect("can create a temp dir");
+    let (mut node, logged) = start(dir.path(), &[]);
+    assert!(wait_for(&logged, "connected nodes").is_some());
+    let stdin = node.stdin.as_mut().expect("stdin is piped");
+    stdin.write_all(b"addrs\n").expect("can type");
+    // the printed address starts the line, the logged one follows the log prefix
+    let printed = loop {
+        match logged.recv_timeout(TIMEOUT) {
+            Ok(line) if line.starts_with("/ip4/127.0.0.1/tcp/") => break Some(line),
+            Ok(_) => {}
+            Err(_) => break None,
+        }
+    };
+    stop(node);
+
+    let printed = printed.expect("addrs prints the listen address");
+    let port = printed
+        .split('/')
+        .nth(4)
+        .and_then(|port| port.parse::<u16>().ok())
+        .expect("has a tcp port");
+    assert_ne!(port, 0);
+    assert!(printed.contains("/p2p/"));
+}
+
+#[test]
+fn gen_genesis_prints_a_mined_genesis_and_exits() {
+    let dir = tempfile::tempdir().expect("can create a temp dir");
+    fs::write(
+        dir.path().join("genesis.json"),
+        r#"{"data": "smoke genesis"}"#,
+    )
+    .expect("can write");
+    let generate = || {
+        let output = Command::new(env!("CARGO_BIN_EXE_demo_blockchain"))
+            .arg("--data-dir")
+            .arg(dir.path())
+            .args(["--genesis", "genesis.json", "--gen-genesis"])
+            .output()
+            .expect("runs");
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).expect("is utf-8")
+    };
+    let printed = generate();
+    let genesis: serde_json::Value = serde_json::from_str(&printed).expect("prints json");
+    assert_eq!(genesis["id"], 0);
+    assert_eq!(genesis["data"], "smoke genesis");
+    // mined to the default difficulty of 16 leading zero bits
+    assert_eq!(genesis["difficulty"], 16);
+    assert!(genesis["hash"]
+        .as_str()
+        .is_some_and(|h| h.starts_with("0000")));
+    assert_eq!(generate(), printed);
+    assert!(!dir.path().join("chain.jsonl").exists());
+}
+
+#[test]
+fn nodes_without_mdns_exchange_blocks_over_an_explicit_dial() {
+    let dir = tempfile::tempdir().expect("can create a temp dir");
+    let args = ["--difficulty", "1", "--min-block-interval", "0"];
+    let (mut a, a_logged) = start(&dir.path().join("a"), &args);
+    let listening = wait_for(&a_logged, "listening on /ip4/").expect("a listens");
+    let addr = listening
+        .split_once("listening on ")
+        .map(|(_, addr)| addr.trim().to_string())
+        .expect("has an address");
+    assert!(wait_for(&a_logged, "connected nodes").is_some());
+
+    let b_args = [&args[..], &["--bootstrap", &addr]].concat();
+    let (b, b_logged) = start(&dir.path().join("b"), &b_args);
+    assert!(wait_for(&b_logged, "connected to").is_some());
+    assert!(wait_for(&b_logged, "connected nodes").is_some());
+    let stdin = a.stdin.as_mut().expect("stdin is piped");
+    stdin
+        .write_all(b"create b over an explicit dial\n")
+        .expect("can type");
+    // it may come as a gossiped block or in a's answer to b's chain request
+    let received = wait_for_file(&dir.path().join("b/chain.jsonl"), "over an explicit dial");
+    stop(a);
+    stop(b);
+    assert!(received, "b gets the block a mined");
+}
+
+#[test]
+fn corrupted_chain_file_is_refused_or_truncated() {
+    let dir = tempfile::tempdir().expect("can create a temp dir");
+    let args = ["--difficulty", "1", "--min-block-interval", "0"];
+    let (mut node, logged) = start(dir.path(), &args);
+    assert!(wait_for(&logged, "connected nodes").is_some());
+    let chain_file = dir.path().join("chain.jsonl");
+    for data in ["first block", "second block"] {
+        let stdin = node.stdin.as_mut().expect("stdin is piped");
+        writeln!(stdin, "create b {}", data).expect("can type");
+        assert!(wait_for_file(&chain_file, data));
+    }
+    stop(node);
+    let chain = fs::read_to_string(&chain_file).expect("can read");
+    fs::write(&chain_file, chain.replace("first block", "forged block")).expect("can write");
+
+    let refused = Command::new(env!("CARGO_BIN_EXE_demo_blockchain"))
+        .args(["--listen-addr", "/ip4/127.0.0.1/tcp/0", "--no-mdns"])
+        .arg("--data-dir")
+        .arg(dir.path())
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .expect("runs");
+    assert!(!refused.status.success());
+    let log = String::from_utf8_lossy(&refused.stderr);
+    assert!(log.contains("is invalid from block 1"), "{}", log);
+
+    let (node, logged) = start(dir.path(), &[&args[..], &["--truncate-invalid"]].concat());
+    assert!(wait_for(&logged, "dropping 2 blocks").is_some());
+    assert!(wait_for(&logged, "connected nodes").is_some());
+    stop(node);
+    let chain = fs::read_to_string(&chain_file).expect("can read");
+    assert!(!chain.contains("forged block") && !chain.contains("second block"));
+}
+
+#[test]
+fn script_commands_run_after_init() {
+    let dir = tempfile::tempdir().expect("can create a temp dir");
+    let script = dir.path().join("demo.txt");
+    fs::write(
+        &script,
+        "# two blocks, then where the chain got to\ncreate b first scripted\n\n\
+         create b second scripted\nstatus\n",
+    )
+    .expect("can write");
+    let script = script.to_str().expect("is utf-8");
+    let args = ["--difficulty", "1", "--min-block-interval", "0"];
+    let (node, logged) = start(dir.path(), &[&args[..], &["--script", script]].concat());
+    let height = wait_for(&logged, "height: ");
+    stop(node);
+
+    assert_eq!(height.as_deref(), Some("height: 2"));
+    let chain = fs::read_to_string(dir.path().join("chain.jsonl")).expect("can read");
+    let first = chain.find("first scripted").expect("has the first block");
+    let second = chain.find("second scripted").expect("has the second block");
+    assert!(first < second);
+}